@@ -238,9 +238,23 @@ impl ConsistentHashing {
         let lookup_table = self.tables.read();
         return lookup_table.nodes.len();
     }
+    // Members are keyed by their node ids, servers only known by address are assumed to
+    // use the id derived from it
+    fn to_server_id(&self, server_name: &String) -> u64 {
+        let lookup_table = self.tables.read();
+        lookup_table
+            .addrs
+            .iter()
+            .find(|(_, addr)| *addr == server_name)
+            .map(|(id, _)| *id)
+            .unwrap_or_else(|| hash_str(server_name))
+    }
     pub async fn set_weight(&self, server_name: &String, weight: u64) -> Result<(), ExecError> {
+        self.set_weight_by_id(self.to_server_id(server_name), weight)
+            .await
+    }
+    pub async fn set_weight_by_id(&self, server_id: u64, weight: u64) -> Result<(), ExecError> {
         let group_id = hash_str(&self.group_name);
-        let server_id = hash_str(server_name);
         self.weight_sm_client
             .set_weight(&group_id, &server_id, &weight)
             .await
@@ -257,7 +271,12 @@ impl ConsistentHashing {
     where
         F: Fn((usize, u32)) + 'static + Send + Sync,
     {
-        let server_id = hash_str(server);
+        self.watch_nodes_range_changed(self.to_server_id(server), f)
+    }
+    pub fn watch_nodes_range_changed<F>(&self, server_id: u64, f: F)
+    where
+        F: Fn((usize, u32)) + 'static + Send + Sync,
+    {
         let wrapper = move |_: &Member, _: &Action, nodes: &Vec<u64>, _: &Vec<u64>| {
             let node_len = nodes.len();
             let mut weight = 0;
@@ -386,7 +405,8 @@ mod test {
     use crate::membership::member::MemberService;
     use crate::membership::server::Membership;
     use crate::raft::client::RaftClient;
-    use crate::raft::{Options, RaftService, Storage};
+    use crate::raft::state_machine::master::RegisterResult;
+    use crate::raft::{Options, RaftService};
     use crate::rpc::Server;
     use crate::utils::time::async_wait_secs;
     use std::collections::HashMap;
@@ -400,16 +420,19 @@ mod test {
         info!("Creating raft service");
         let addr = String::from("127.0.0.1:2200");
         let raft_service = RaftService::new(Options {
-            storage: Storage::default(),
             address: addr.clone(),
             service_id: 0,
+            ..Options::default()
         });
 
         info!("Creating server");
         let server = Server::new(&addr);
         info!("Creating membership service");
-        let _membership = Membership::new(&server, &raft_service).await;
-        server.register_service(0, &raft_service).await;
+        assert_eq!(
+            Membership::new(&server, &raft_service).await,
+            RegisterResult::OK
+        );
+        server.register_service(0, &raft_service).await.unwrap();
         Server::listen_and_resume(&server).await;
         RaftService::start(&raft_service).await;
         raft_service.bootstrap().await;
@@ -468,7 +491,7 @@ mod test {
         member1_svr.join_group(&group_3).await.unwrap();
 
         info!("New weight service");
-        Weights::new(&raft_service).await;
+        assert_eq!(Weights::new(&raft_service).await, RegisterResult::OK);
 
         info!("New conshash for group 1");
         let ch1 = ConsistentHashing::new(&group_1, &wild_raft_client, &observer_client)
@@ -1,16 +1,20 @@
+use crate::raft::state_machine::master::RegisterResult;
 use crate::raft::state_machine::StateMachineCtl;
 use crate::raft::RaftService;
+use crate::utils::serde::Codec;
 use bifrost_plugins::hash_ident;
+use futures::future::{self, BoxFuture};
 use futures::FutureExt;
 use std::collections::HashMap;
 use std::sync::Arc;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DHT_WEIGHTS) as u64;
 
-raft_state_machine! {
-    def cmd set_weight(group: u64, id: u64, weight: u64);
-    def qry get_weights(group: u64) -> Option<HashMap<u64, u64>>;
-    def qry get_weight(group: u64, id: u64) -> Option<u64>;
+#[crate::macros::state_machine]
+pub trait StateMachineCmds {
+    fn set_weight(&mut self, group: u64, id: u64, weight: u64);
+    fn get_weights(&self, group: u64) -> Option<HashMap<u64, u64>>;
+    fn get_weight(&self, group: u64, id: u64) -> Option<u64>;
 }
 pub struct Weights {
     pub groups: HashMap<u64, HashMap<u64, u64>>,
@@ -49,16 +53,16 @@ impl StateMachineCtl for Weights {
     fn id(&self) -> u64 {
         self.id
     }
-    fn snapshot(&self) -> Option<Vec<u8>> {
-        Some(crate::utils::serde::serialize(&self.groups))
+    fn snapshot(&self, codec: &'static dyn Codec) -> Option<Vec<u8>> {
+        Some(codec.serialize(&self.groups))
     }
-    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
-        self.groups = crate::utils::serde::deserialize(data.as_slice()).unwrap();
+    fn recover(&mut self, codec: &'static dyn Codec, data: Vec<u8>) -> BoxFuture<()> {
+        self.groups = codec.deserialize(data.as_slice()).unwrap();
         future::ready(()).boxed()
     }
 }
 impl Weights {
-    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) -> RegisterResult {
         raft_service
             .register_state_machine(Box::new(Weights {
                 groups: HashMap::new(),
@@ -66,7 +70,7 @@ impl Weights {
             }))
             .await
     }
-    pub async fn new(raft_service: &Arc<RaftService>) {
+    pub async fn new(raft_service: &Arc<RaftService>) -> RegisterResult {
         Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
     }
 }
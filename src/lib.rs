@@ -21,12 +21,18 @@ pub mod rpc;
 #[macro_use]
 pub mod raft;
 pub mod conshash;
+pub mod macros;
 pub mod membership;
 pub mod vector_clock;
 
+pub use macros::state_machine;
+
 #[macro_use]
 extern crate log;
 
 #[macro_use]
 extern crate lazy_static; 
 pub extern crate bytes;
+
+// Lets code generated by `bifrost_plugins` refer to `::bifrost` from within this crate too
+extern crate self as bifrost;
@@ -0,0 +1,53 @@
+// Attribute forms of `service!` and `raft_state_machine!`, applied to a trait. They
+// generate the same dispatch, clients and commands, with the same function ids on the
+// wire, so either side of a call can use either form. `state_machine` is also exported
+// at the crate root as `#[bifrost::state_machine]`; `service` is not, because the
+// exported `service!` macro already takes that name there.
+//
+//     #[bifrost::macros::service]
+//     pub trait Service {
+//         /// Documentation is copied to the client methods
+//         fn hello(&self, name: &str) -> String;
+//         async fn add(&self, a: u32, b: u32) -> u32 { a + b }
+//         #[stream]
+//         fn count(&self, to: u64) -> u64;
+//         #[upload]
+//         fn sum(&self, base: u64, items: u64) -> u64;
+//         #[oneway]
+//         fn notify(&self, event: String);
+//     }
+//     dispatch_rpc_service_functions!(HelloServer);
+//
+// Unary and oneway methods are implemented as `fn hello<'a>(&'a self, name: &'a str) ->
+// BoxFuture<'a, String>` and can have an `async fn` default body. Stream methods return a
+// `BoxStream<'static, Item>`, upload methods take the stream of their last argument type
+// and return a `BoxFuture<'static, R>`.
+//
+// Arguments of unary methods may borrow, elided lifetimes become the lifetime of `&self`
+// and named ones must outlive it. Traits may have type parameters, which are bound by
+// `Serialize + DeserializeOwned + Send + Sync + 'static`; clients of a generic service
+// always go through the wire. Methods cannot have type parameters of their own, the
+// server dispatch needs concrete types.
+//
+//     #[bifrost::state_machine]
+//     pub trait StateMachineCmds<V> {
+//         fn set_weight(&mut self, id: u64, weight: V);
+//         fn get_weight(&self, name: &str) -> Option<V>;
+//         #[subscribe]
+//         fn on_weight(&self, id: u64) -> V;
+//     }
+//
+// Methods taking `&mut self` are commands, the ones taking `&self` are queries. Methods
+// marked with `#[subscribe]` are not part of the trait, they only get a `SMClient` method.
+pub use bifrost_plugins::{service, state_machine};
+
+// Dependencies of the generated code, so crates using the macros need not depend on them
+#[doc(hidden)]
+pub mod export {
+    pub use async_std;
+    pub use futures;
+    pub use lazy_static::lazy_static;
+    pub use log;
+    pub use serde;
+    pub use tokio;
+}
@@ -10,6 +10,8 @@ use tokio::time;
 use crate::membership::DEFAULT_SERVICE_ID;
 use crate::raft::client::RaftClient;
 use crate::raft::state_machine::master::ExecError;
+use crate::rpc::ClientPool;
+use crate::tcp::client::ClientOptions;
 
 static PING_INTERVAL: u64 = 500;
 
@@ -23,7 +25,16 @@ pub struct MemberService {
 
 impl MemberService {
     pub async fn new(server_address: &String, raft_client: &Arc<RaftClient>) -> Arc<MemberService> {
-        let server_id = hash_str(server_address);
+        Self::new_with_id(hash_str(server_address), server_address, raft_client).await
+    }
+    // The id identifies the member across restarts, rejoining from another address
+    // updates the address of the existing member. The address is the advertised one,
+    // as other members and clients see it.
+    pub async fn new_with_id(
+        server_id: u64,
+        server_address: &String,
+        raft_client: &Arc<RaftClient>,
+    ) -> Arc<MemberService> {
         let sm_client = Arc::new(SMClient::new(DEFAULT_SERVICE_ID, &raft_client));
         let service = Arc::new(MemberService {
             sm_client: sm_client.clone(),
@@ -35,15 +46,28 @@ impl MemberService {
             closed: AtomicBool::new(false),
             id: server_id,
         });
-        let _join_res = sm_client.join(&server_address).await;
+        let _join_res = sm_client.join_with_id(&server_id, &server_address).await;
         let service_clone = service.clone();
+        // Heartbeats are sent on behalf of this member, for faults injected on it to apply,
+        // to the servers of the raft cluster
+        let heartbeats = ClientPool::new_with_options(ClientOptions {
+            node_id: server_id,
+            sender_address: Some(server_address.clone()),
+            codec: raft_client.codec(),
+            ..ClientOptions::default()
+        });
         tokio::spawn(async move {
             while !service_clone.closed.load(Ordering::Relaxed) {
-                let rpc_client = service_clone.raft_client.current_leader_rpc_client().await;
-                if let Ok(rpc_client) = rpc_client {
-                    let _ping_res =
-                        ImmeServiceClient::ping(DEFAULT_SERVICE_ID, &rpc_client, service_clone.id)
-                            .await;
+                let leader = service_clone.raft_client.current_leader_rpc_client().await;
+                if let Ok(leader) = leader {
+                    if let Ok(rpc_client) = heartbeats.get(&leader.address).await {
+                        let _ping_res = ImmeServiceClient::ping(
+                            DEFAULT_SERVICE_ID,
+                            &rpc_client,
+                            service_clone.id,
+                        )
+                        .await;
+                    }
                 }
                 time::delay_for(time::Duration::from_millis(PING_INTERVAL)).await
             }
@@ -58,6 +82,9 @@ impl MemberService {
         self.close();
         self.sm_client.leave(&self.id).await
     }
+    pub async fn update_address(&self, address: &String) -> Result<bool, ExecError> {
+        self.sm_client.update_address(&self.id, address).await
+    }
     pub async fn join_group(&self, group: &String) -> Result<bool, ExecError> {
         self.member_client.join_group(group).await
     }
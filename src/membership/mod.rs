@@ -13,7 +13,10 @@ pub mod raft {
     use super::*;
     raft_state_machine! {
         def cmd hb_online_changed(online: Vec<u64>, offline: Vec<u64>);
+        // Members identified by the hash of their address, from logs before node ids
         def cmd join(address: String) -> Option<u64>;
+        def cmd join_with_id(id: u64, address: String) -> Option<u64>;
+        def cmd update_address(id: u64, address: String) -> bool;
         def cmd leave(id: u64) -> bool;
         def cmd join_group(group_name: String, id: u64) -> bool;
         def cmd leave_group(group: u64, id: u64) -> bool;
@@ -39,7 +42,7 @@ pub mod raft {
 // Expired update time will trigger timeout in the raft state machine
 mod heartbeat_rpc {
     service! {
-        rpc ping(id: u64);
+        oneway rpc ping(id: u64);
     }
 }
 
@@ -49,7 +52,8 @@ mod test {
     use crate::membership::member::MemberService;
     use crate::membership::server::Membership;
     use crate::raft::client::RaftClient;
-    use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
+    use crate::raft::state_machine::master::RegisterResult;
+    use crate::raft::{Options, RaftService, DEFAULT_SERVICE_ID};
     use crate::rpc::Server;
     use crate::utils::time::async_wait_secs;
     use futures::prelude::*;
@@ -61,16 +65,16 @@ mod test {
         let _ = env_logger::builder().format_timestamp(None).try_init();
         let addr = String::from("127.0.0.1:2100");
         let raft_service = RaftService::new(Options {
-            storage: Storage::default(),
             address: addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         info!("Creating server");
         let server = Server::new(&addr);
         info!("Register service");
         server
             .register_service(DEFAULT_SERVICE_ID, &raft_service)
-            .await;
+            .await
+            .unwrap();
         info!("Server listen and resume");
         Server::listen_and_resume(&server).await;
         info!("Start raft service");
@@ -78,7 +82,10 @@ mod test {
         info!("Bootstrap raft service");
         raft_service.bootstrap().await;
         info!("Creating membership service");
-        Membership::new(&server, &raft_service).await;
+        assert_eq!(
+            Membership::new(&server, &raft_service).await,
+            RegisterResult::OK
+        );
 
         let group_1 = String::from("test_group_1");
         let group_2 = String::from("test_group_2");
@@ -3,9 +3,11 @@ use super::raft::*;
 use super::*;
 use crate::membership::client::Member as ClientMember;
 use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::RegisterResult;
 use crate::raft::state_machine::StateMachineCtl;
 use crate::raft::{LogEntry, RaftMsg, RaftService, Service as raft_svr_trait};
 use crate::rpc::Server;
+use crate::utils::serde::Codec;
 use crate::utils::time;
 use async_std::sync::*;
 use bifrost_hasher::hash_str;
@@ -53,7 +55,7 @@ impl HeartbeatService {
     async fn update_raft(&self, online: &Vec<u64>, offline: &Vec<u64>) {
         let log = commands::hb_online_changed::new(online, offline);
         // Encode to state machine command
-        let (fn_id, _, data) = log.encode();
+        let (fn_id, _, data) = log.encode(self.raft_service.options.codec());
         self.raft_service
             .c_command(LogEntry {
                 id: 0,
@@ -103,7 +105,7 @@ impl Drop for Membership {
 }
 
 impl Membership {
-    pub async fn new(server: &Arc<Server>, raft_service: &Arc<RaftService>) {
+    pub async fn new(server: &Arc<Server>, raft_service: &Arc<RaftService>) -> RegisterResult {
         let service = Arc::new(HeartbeatService {
             status: RwLock::new(HashMap::new()),
             closed: AtomicBool::new(false),
@@ -172,10 +174,17 @@ impl Membership {
             version: 0,
         };
         membership_service.init_callback(raft_service).await;
-        raft_service
+        let res = raft_service
             .register_state_machine(Box::new(membership_service))
             .await;
-        server.register_service(DEFAULT_SERVICE_ID, &service).await;
+        if res != RegisterResult::OK {
+            return res;
+        }
+        server
+            .register_service(DEFAULT_SERVICE_ID, &service)
+            .await
+            .unwrap();
+        res
     }
     async fn compose_client_member(&self, id: u64) -> ClientMember {
         let member = self.members.get(&id).unwrap();
@@ -405,9 +414,16 @@ impl StateMachineCmds for Membership {
         .boxed()
     }
     fn join(&mut self, address: String) -> BoxFuture<Option<u64>> {
+        self.join_with_id(hash_str(&address), address)
+    }
+    fn join_with_id(&mut self, id: u64, address: String) -> BoxFuture<Option<u64>> {
         async move {
+            if self.members.contains_key(&id) {
+                // Rejoining, possibly from another address after a restart
+                self.update_address(id, address).await;
+                return None;
+            }
             self.version += 1;
-            let id = hash_str(&address);
             let mut joined = false;
             {
                 let mut stat_map = self.heartbeat.status.write().await;
@@ -442,6 +458,19 @@ impl StateMachineCmds for Membership {
         }
         .boxed()
     }
+    fn update_address(&mut self, id: u64, address: String) -> BoxFuture<bool> {
+        async move {
+            match self.members.get_mut(&id) {
+                Some(member) if member.address != address => member.address = address,
+                _ => return false,
+            }
+            self.version += 1;
+            // Watchers refresh the member from the notification, picking up the new address
+            self.notify_for_member_online(id).await;
+            true
+        }
+        .boxed()
+    }
     fn leave(&mut self, id: u64) -> BoxFuture<bool> {
         async move {
             if !self.members.contains_key(&id) {
@@ -615,11 +644,11 @@ impl StateMachineCtl for Membership {
     fn id(&self) -> u64 {
         DEFAULT_SERVICE_ID
     }
-    fn snapshot(&self) -> Option<Vec<u8>> {
+    fn snapshot(&self, _: &'static dyn Codec) -> Option<Vec<u8>> {
         //Some(serialize!(&self.map))
         None // TODO: Backup members
     }
-    fn recover(&mut self, _: Vec<u8>) -> BoxFuture<()> {
+    fn recover(&mut self, _: &'static dyn Codec, _: Vec<u8>) -> BoxFuture<()> {
         future::ready(()).boxed()
         //self.map = deserialize!(&data);
     }
@@ -4,6 +4,18 @@ extern crate bifrost_hasher;
 use proc_macro::TokenStream;
 use bifrost_hasher::hash_str;
 use proc_macro::TokenTree;
+use proc_macro2::Literal;
+use std::collections::HashMap;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    parse_macro_input, parse_quote, Error, FnArg, GenericParam, Generics, Ident, ItemTrait,
+    Lifetime, LifetimeDef, Pat, Result, Signature, Token, Type, TypeReference,
+};
+
+mod service;
+mod state_machine;
 
 #[proc_macro]
 pub fn hash_ident(item: TokenStream) -> TokenStream {
@@ -19,4 +31,228 @@ pub fn hash_ident(item: TokenStream) -> TokenStream {
     let text = &*text;
     let str = String::from(text);
     format!("{}", hash_str(&str)).parse().unwrap()
-}
\ No newline at end of file
+}
+
+// Fails the build when two of the function names hash to the same id, for `service!` and
+// `raft_state_machine!`
+#[doc(hidden)]
+#[proc_macro]
+pub fn check_fn_ids(item: TokenStream) -> TokenStream {
+    let names = parse_macro_input!(item with Punctuated::<Ident, Token![,]>::parse_terminated);
+    match check_ids(&names.into_iter().collect::<Vec<_>>()) {
+        Ok(()) => TokenStream::new(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// Generates from a trait what `service!` generates from its declarations, see
+// `bifrost::macros` for the syntax
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    expand(attr, parse_macro_input!(item as ItemTrait), service::expand)
+}
+
+// Generates from a trait what `raft_state_machine!` generates from its declarations, see
+// `bifrost::macros` for the syntax
+#[proc_macro_attribute]
+pub fn state_machine(attr: TokenStream, item: TokenStream) -> TokenStream {
+    expand(
+        attr,
+        parse_macro_input!(item as ItemTrait),
+        state_machine::expand,
+    )
+}
+
+fn expand(
+    attr: TokenStream,
+    item: ItemTrait,
+    f: fn(ItemTrait) -> Result<proc_macro2::TokenStream>,
+) -> TokenStream {
+    let attr = proc_macro2::TokenStream::from(attr);
+    let res = if attr.is_empty() {
+        f(item)
+    } else {
+        Err(Error::new(attr.span(), "takes no arguments"))
+    };
+    res.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+// Same value as `hash_ident!` of the name
+fn fn_id(name: &Ident) -> Literal {
+    Literal::u64_suffixed(hash_str(&name.to_string()))
+}
+
+// Functions are dispatched by id, two names hashing alike would call the same one
+fn check_ids(names: &[Ident]) -> Result<()> {
+    let mut ids = HashMap::new();
+    for name in names {
+        if let Some(other) = ids.insert(hash_str(&name.to_string()), name) {
+            let message = if other == name {
+                format!("`{}` is declared more than once", name)
+            } else {
+                format!(
+                    "function id of `{}` collides with the one of `{}`, rename either of them",
+                    name, other
+                )
+            };
+            return Err(Error::new(name.span(), message));
+        }
+    }
+    Ok(())
+}
+
+// Whether the method takes `&mut self`, it must take `self` by reference
+fn receiver(sig: &Signature) -> Result<bool> {
+    match sig.receiver() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() => {
+            Ok(receiver.mutability.is_some())
+        }
+        Some(other) => Err(Error::new(
+            other.span(),
+            "`self` must be taken by reference",
+        )),
+        None => Err(Error::new(
+            sig.paren_token.span,
+            "methods need a `self` receiver",
+        )),
+    }
+}
+
+// Type parameters of the trait, with the bounds the generated code needs to send them.
+// Servers and clients are generic over them, and the functions ids are the same for all
+// their types.
+fn trait_generics(item: &ItemTrait) -> Result<Generics> {
+    let mut generics = item.generics.clone();
+    let mut params = vec![];
+    for param in &generics.params {
+        match param {
+            GenericParam::Type(param) => params.push(param.ident.clone()),
+            other => {
+                return Err(Error::new(
+                    other.span(),
+                    "traits can only have type parameters",
+                ))
+            }
+        }
+    }
+    if !params.is_empty() {
+        let serde = quote::quote!(::bifrost::macros::export::serde);
+        let where_clause = generics.make_where_clause();
+        for param in params {
+            where_clause.predicates.push(parse_quote! {
+                #param: #serde::Serialize + #serde::de::DeserializeOwned + Send + Sync + 'static
+            });
+        }
+    }
+    Ok(generics)
+}
+
+// Lifetime of `self` in the generated trait methods
+fn self_lifetime() -> Lifetime {
+    parse_quote!('a)
+}
+
+// Lifetimes of the method, arguments can borrow for them. Remote methods cannot have type
+// parameters, the server needs to know the types it decodes.
+fn method_lifetimes(sig: &Signature) -> Result<Vec<LifetimeDef>> {
+    if let Some(ref where_clause) = sig.generics.where_clause {
+        return Err(Error::new(
+            where_clause.span(),
+            "remote methods cannot have bounds, put them on the trait",
+        ));
+    }
+    sig.generics
+        .params
+        .iter()
+        .map(|param| match param {
+            GenericParam::Lifetime(def) if def.lifetime == self_lifetime() => Err(Error::new(
+                def.span(),
+                "`'a` is the lifetime of `self` in the generated methods, rename it",
+            )),
+            GenericParam::Lifetime(def) => Ok(def.clone()),
+            other => Err(Error::new(
+                other.span(),
+                "remote methods cannot have type parameters, put them on the trait",
+            )),
+        })
+        .collect()
+}
+
+// Names the elided lifetimes of borrowed arguments, for them to live as long as `self`
+// in the generated trait methods
+struct NameElided(Lifetime);
+
+impl VisitMut for NameElided {
+    fn visit_type_reference_mut(&mut self, ty: &mut TypeReference) {
+        if ty.lifetime.is_none() {
+            ty.lifetime = Some(self.0.clone());
+        }
+        visit_mut::visit_type_reference_mut(self, ty);
+    }
+    fn visit_lifetime_mut(&mut self, lifetime: &mut Lifetime) {
+        if lifetime.ident == "_" {
+            *lifetime = self.0.clone();
+        }
+    }
+}
+
+fn name_elided(ty: &Type, lifetime: &Lifetime) -> Type {
+    let mut ty = ty.clone();
+    NameElided(lifetime.clone()).visit_type_mut(&mut ty);
+    ty
+}
+
+// Lets the lifetimes of a borrowed argument be inferred where the ones of its method are not
+// in scope
+struct EraseLifetimes;
+
+impl VisitMut for EraseLifetimes {
+    fn visit_lifetime_mut(&mut self, lifetime: &mut Lifetime) {
+        if lifetime.ident != "static" {
+            *lifetime = parse_quote!('_);
+        }
+    }
+}
+
+fn erase_lifetimes(ty: &Type) -> Type {
+    let mut ty = ty.clone();
+    EraseLifetimes.visit_type_mut(&mut ty);
+    ty
+}
+
+// Whether the type borrows, arguments of calls that outlive the request cannot
+fn borrows(ty: &Type) -> bool {
+    fn any_borrow(tokens: proc_macro2::TokenStream) -> bool {
+        tokens.into_iter().any(|token| match token {
+            proc_macro2::TokenTree::Punct(punct) => {
+                punct.as_char() == '&' || punct.as_char() == '\''
+            }
+            proc_macro2::TokenTree::Group(group) => any_borrow(group.stream()),
+            _ => false,
+        })
+    }
+    any_borrow(quote::ToTokens::to_token_stream(ty))
+}
+
+// Arguments after `self`, which are serialized in a tuple and need plain names
+fn plain_args(sig: &Signature) -> Result<Vec<(Ident, Type)>> {
+    if sig.constness.is_some() || sig.unsafety.is_some() || sig.abi.is_some() {
+        return Err(Error::new(
+            sig.span(),
+            "remote methods must be plain or `async` functions",
+        ));
+    }
+    sig.inputs
+        .iter()
+        .skip(1)
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => match *arg.pat {
+                Pat::Ident(ref pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                    Ok((pat.ident.clone(), (*arg.ty).clone()))
+                }
+                ref pat => Err(Error::new(pat.span(), "arguments need plain names")),
+            },
+            FnArg::Receiver(receiver) => Err(Error::new(receiver.span(), "unexpected `self`")),
+        })
+        .collect()
+}
@@ -0,0 +1,553 @@
+// `#[service]`, the attribute form of `service!`. Generated code refers to bifrost by
+// absolute paths and leaves the imports of the caller's module alone.
+use crate::{
+    borrows, check_ids, erase_lifetimes, fn_id, method_lifetimes, name_elided, plain_args,
+    receiver, self_lifetime, trait_generics,
+};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    Attribute, Block, Error, Generics, Ident, ItemTrait, LifetimeDef, Result, ReturnType,
+    TraitItem, Type,
+};
+
+enum Kind {
+    Unary,
+    // Marked with `#[stream]`, returns the item type
+    Stream,
+    // Marked with `#[upload]`, the last argument is the item type of the uploaded stream
+    Upload,
+    // Marked with `#[oneway]`, returns nothing
+    Oneway,
+}
+
+struct Method {
+    kind: Kind,
+    attrs: Vec<Attribute>,
+    name: Ident,
+    lifetimes: Vec<LifetimeDef>,
+    args: Vec<(Ident, Type)>,
+    items: Option<(Ident, Type)>,
+    out: Type,
+    body: Option<Block>,
+}
+
+fn kind(attrs: &mut Vec<Attribute>) -> Result<Kind> {
+    let mut kind = Kind::Unary;
+    let mut marker: Option<&'static str> = None;
+    let mut error = None;
+    attrs.retain(|attr| {
+        let (name, k) = if attr.path.is_ident("stream") {
+            ("stream", Kind::Stream)
+        } else if attr.path.is_ident("upload") {
+            ("upload", Kind::Upload)
+        } else if attr.path.is_ident("oneway") {
+            ("oneway", Kind::Oneway)
+        } else {
+            return true;
+        };
+        if let Some(other) = marker {
+            error.get_or_insert_with(|| {
+                Error::new(
+                    attr.span(),
+                    format!("`#[{}]` cannot be combined with `#[{}]`", name, other),
+                )
+            });
+        } else if !attr.tokens.is_empty() {
+            error.get_or_insert_with(|| {
+                Error::new(
+                    attr.tokens.span(),
+                    format!("`#[{}]` takes no arguments", name),
+                )
+            });
+        }
+        marker = Some(name);
+        kind = k;
+        false
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(kind),
+    }
+}
+
+fn parse_method(item: TraitItem) -> Result<Method> {
+    let mut method = match item {
+        TraitItem::Method(method) => method,
+        other => {
+            return Err(Error::new(
+                other.span(),
+                "services can only declare methods",
+            ))
+        }
+    };
+    let kind = kind(&mut method.attrs)?;
+    let sig = method.sig;
+    if receiver(&sig)? {
+        return Err(Error::new(
+            sig.inputs.span(),
+            "service methods take `&self`",
+        ));
+    }
+    let lifetimes = method_lifetimes(&sig)?;
+    let mut args = plain_args(&sig)?;
+    if let Kind::Oneway = kind {
+        if let Some((_, ty)) = args.iter().find(|(_, ty)| borrows(ty)) {
+            return Err(Error::new(
+                ty.span(),
+                "oneway methods run after the call returns, they cannot borrow arguments",
+            ));
+        }
+    }
+    let items = match kind {
+        Kind::Upload => Some(args.pop().ok_or_else(|| {
+            Error::new(
+                sig.paren_token.span,
+                "upload methods take the uploaded stream items as their last argument",
+            )
+        })?),
+        _ => None,
+    };
+    let out = match (&kind, sig.output) {
+        (Kind::Oneway, ReturnType::Type(_, ty)) => {
+            return Err(Error::new(ty.span(), "oneway methods cannot return values"))
+        }
+        (Kind::Stream, ReturnType::Default) => {
+            return Err(Error::new(
+                sig.ident.span(),
+                "stream methods return the type of their items",
+            ))
+        }
+        (_, ReturnType::Type(_, ty)) => *ty,
+        (_, ReturnType::Default) => syn::parse_quote!(()),
+    };
+    if let Some(ref body) = method.default {
+        match kind {
+            Kind::Stream | Kind::Upload => {
+                return Err(Error::new(
+                    body.span(),
+                    "only unary and oneway methods can have default bodies",
+                ))
+            }
+            _ if sig.asyncness.is_none() => {
+                return Err(Error::new(
+                    sig.fn_token.span,
+                    "default bodies need an `async fn`",
+                ))
+            }
+            _ => {}
+        }
+    }
+    Ok(Method {
+        kind,
+        attrs: method.attrs,
+        name: sig.ident,
+        lifetimes,
+        args,
+        items,
+        out,
+        body: method.default,
+    })
+}
+
+pub fn expand(item: ItemTrait) -> Result<TokenStream> {
+    let generics = trait_generics(&item)?;
+    let methods = item
+        .items
+        .into_iter()
+        .map(parse_method)
+        .collect::<Result<Vec<_>>>()?;
+    check_ids(&methods.iter().map(|m| m.name.clone()).collect::<Vec<_>>())?;
+    let vis = &item.vis;
+    let trait_name = &item.ident;
+    let trait_attrs = &item.attrs;
+    let supertraits = item.supertraits.iter();
+    let export = quote!(::bifrost::macros::export);
+    let rpc = quote!(::bifrost::rpc);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let params = generics.type_params().map(|param| &param.ident);
+    let generic = generics.type_params().next().is_some();
+    let lt = self_lifetime();
+
+    let trait_fns = methods.iter().map(|m| {
+        let (attrs, name, out, lifetimes) = (&m.attrs, &m.name, &m.out, &m.lifetimes);
+        let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+        match m.kind {
+            Kind::Unary | Kind::Oneway => {
+                let body = match m.body {
+                    Some(ref body) => quote!({ ::std::boxed::Box::pin(async move #body) }),
+                    None => quote!(;),
+                };
+                // The returned future can hold the borrowed arguments
+                let ty = ty.iter().map(|ty| name_elided(ty, &lt));
+                let outlives = lifetimes.iter().map(|def| &def.lifetime);
+                quote! {
+                    #(#attrs)*
+                    fn #name<#lt, #(#lifetimes),*>(&#lt self, #(#arg: #ty),*) -> #export::futures::future::BoxFuture<#lt, #out>
+                    where
+                        #(#outlives: #lt),*
+                    #body
+                }
+            }
+            Kind::Stream => quote! {
+                #(#attrs)*
+                fn #name<#(#lifetimes),*>(&self, #(#arg: #ty),*) -> #export::futures::stream::BoxStream<'static, #out>;
+            },
+            Kind::Upload => {
+                let (items, item) = m.items.as_ref().unwrap();
+                quote! {
+                    #(#attrs)*
+                    fn #name<#(#lifetimes),*>(&self, #(#arg: #ty,)* #items: #export::futures::stream::BoxStream<'static, #item>) -> #export::futures::future::BoxFuture<'static, #out>;
+                }
+            }
+        }
+    });
+
+    let dispatch_arms = methods.iter().map(|m| {
+        let name = &m.name;
+        let id = fn_id(name);
+        let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+        // Borrowed arguments borrow from the request body
+        let ty = ty.iter().map(erase_lifetimes);
+        let bad_args = format!("cannot decode arguments of {}", name);
+        let handle = match m.kind {
+            Kind::Unary => quote! {
+                let f_result = self.#name(#(#arg,)*).await;
+                Ok(::bifrost::bytes::BytesMut::from(::bifrost::utils::serde::serialize(&f_result).as_slice()))
+            },
+            Kind::Stream => quote! {
+                let stream_id = #rpc::streaming::open_download(self.#name(#(#arg,)*))?;
+                Ok(::bifrost::bytes::BytesMut::from(::bifrost::utils::serde::serialize(&stream_id).as_slice()))
+            },
+            Kind::Upload => {
+                let items = &m.items.as_ref().unwrap().0;
+                quote! {
+                    let stream_id = #rpc::streaming::open_upload(
+                        move |#items| self.#name(#(#arg,)* #items)
+                    )?;
+                    Ok(::bifrost::bytes::BytesMut::from(::bifrost::utils::serde::serialize(&stream_id).as_slice()))
+                }
+            }
+            Kind::Oneway => quote! {
+                self.#name(#(#arg,)*).await;
+                Ok(::bifrost::bytes::BytesMut::from(::bifrost::utils::serde::serialize(&()).as_slice()))
+            },
+        };
+        quote! {
+            #id => {
+                if let Some(data) = ::bifrost::utils::serde::deserialize(body.as_ref()) {
+                    let (#(#arg,)*): (#(#ty,)*) = data;
+                    #handle
+                } else {
+                    Err(#rpc::RemoteError::new(#rpc::RPCRequestError::BadRequest, #bad_args))
+                }
+            }
+        }
+    });
+
+    let (async_fns, imme_fns): (Vec<_>, Vec<_>) = methods
+        .iter()
+        .map(|m| client_fns(m, trait_name, &generics))
+        .unzip();
+
+    // Generic services are always called through the connection, the shortcut would need
+    // the type of the service object to call it
+    let local_services = if generic {
+        quote! {
+            #export::lazy_static! {
+                #vis static ref RPC_SVRS: #export::async_std::sync::RwLock<
+                    ::std::collections::BTreeMap<
+                        (::bifrost::tcp::NetworkId, u64, u64),
+                        (::std::sync::Arc<dyn ::std::any::Any + Send + Sync>, ::std::sync::Arc<::std::sync::atomic::AtomicBool>),
+                    >
+                > = #export::async_std::sync::RwLock::new(::std::collections::BTreeMap::new());
+            }
+        }
+    } else {
+        quote! {
+            #export::lazy_static! {
+                // With the flag of their server telling if it has interceptors
+                #vis static ref RPC_SVRS: #export::async_std::sync::RwLock<
+                    ::std::collections::BTreeMap<
+                        (::bifrost::tcp::NetworkId, u64, u64),
+                        (::std::sync::Arc<dyn #trait_name>, ::std::sync::Arc<::std::sync::atomic::AtomicBool>),
+                    >
+                > = #export::async_std::sync::RwLock::new(::std::collections::BTreeMap::new());
+            }
+
+            #[allow(dead_code)]
+            #vis async fn get_local(
+                network: ::bifrost::tcp::NetworkId,
+                server_id: u64,
+                service_id: u64,
+            ) -> Option<::std::sync::Arc<dyn #trait_name>> {
+                match RPC_SVRS.read().await.get(&(network, server_id, service_id)) {
+                    // Calling the service object would skip the interceptors of its server
+                    Some((service, intercepted)) if !intercepted.load(::std::sync::atomic::Ordering::Relaxed) => {
+                        Some(service.clone())
+                    }
+                    _ => None,
+                }
+            }
+        }
+    };
+
+    // Clients of generic services are generic too
+    let (params_field, params_value, imme_client) = if generic {
+        let params = quote!(::std::marker::PhantomData<fn() -> (#(#params,)*)>);
+        (
+            quote!(_params: #params,),
+            quote!(_params: ::std::marker::PhantomData,),
+            quote!(#vis struct ImmeServiceClient #impl_generics (#params) #where_clause;),
+        )
+    } else {
+        (quote!(), quote!(), quote!(#vis struct ImmeServiceClient;))
+    };
+
+    Ok(quote! {
+        #(#trait_attrs)*
+        #vis trait #trait_name #impl_generics: #rpc::RPCService #(+ #supertraits)* #where_clause {
+            #(#trait_fns)*
+            fn inner_dispatch<'a>(
+                &'a self,
+                data: ::bifrost::bytes::BytesMut,
+            ) -> #export::futures::future::BoxFuture<'a, Result<::bifrost::bytes::BytesMut, #rpc::RemoteError>> {
+                let (func_id, body) = #rpc::read_u64_head(data);
+                ::std::boxed::Box::pin(async move {
+                    match func_id {
+                        #(#dispatch_arms)*
+                        _ => Err(#rpc::RemoteError::new(
+                            #rpc::RPCRequestError::FunctionIdNotFound,
+                            format!("no function {}", func_id),
+                        )),
+                    }
+                })
+            }
+        }
+
+        #local_services
+
+        #[allow(dead_code)]
+        #vis struct AsyncServiceClient #impl_generics #where_clause {
+            pub service_id: u64,
+            pub client: ::std::sync::Arc<#rpc::RPCClient>,
+            // Deadline of every call from this client, counted from when the call starts
+            pub timeout: Option<::std::time::Duration>,
+            #params_field
+        }
+
+        #[allow(dead_code)]
+        impl #impl_generics AsyncServiceClient #ty_generics #where_clause {
+            #(#async_fns)*
+            pub fn new(service_id: u64, client: &::std::sync::Arc<#rpc::RPCClient>) -> ::std::sync::Arc<Self> {
+                ::std::sync::Arc::new(AsyncServiceClient {
+                    service_id,
+                    client: client.clone(),
+                    timeout: None,
+                    #params_value
+                })
+            }
+            pub fn with_timeout(&self, timeout: ::std::time::Duration) -> ::std::sync::Arc<Self> {
+                ::std::sync::Arc::new(AsyncServiceClient {
+                    service_id: self.service_id,
+                    client: self.client.clone(),
+                    timeout: Some(timeout),
+                    #params_value
+                })
+            }
+            pub fn server_id(&self) -> u64 {
+                self.client.server_id
+            }
+        }
+
+        #imme_client
+
+        #[allow(dead_code)]
+        impl #impl_generics ImmeServiceClient #ty_generics #where_clause {
+            #(#imme_fns)*
+        }
+    })
+}
+
+// Methods of `AsyncServiceClient` and `ImmeServiceClient`, the same as the ones of `service!`
+fn client_fns(m: &Method, trait_name: &Ident, generics: &Generics) -> (TokenStream, TokenStream) {
+    let export = quote!(::bifrost::macros::export);
+    let rpc = quote!(::bifrost::rpc);
+    let (attrs, name, out, lifetimes) = (&m.attrs, &m.name, &m.out, &m.lifetimes);
+    let id = fn_id(name);
+    let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+    let imme = {
+        let (_, ty_generics, _) = generics.split_for_impl();
+        let turbofish = ty_generics.as_turbofish();
+        quote!(ImmeServiceClient #turbofish)
+    };
+    // Calls the service object when its server is in this process, or sends the request
+    let call = |local_call: TokenStream, remote_call: TokenStream| {
+        if generics.type_params().next().is_some() {
+            return remote_call;
+        }
+        quote! {
+            let local: Option<::std::sync::Arc<dyn #trait_name>> = if client.can_call_local() {
+                get_local(client.network, client.server_id, service_id).await
+            } else {
+                None
+            };
+            if let Some(local) = local {
+                #local_call
+            } else {
+                #remote_call
+            }
+        }
+    };
+    let request = quote! {
+        let req_data = (#(#arg,)*);
+        let req_data_bytes = ::bifrost::bytes::BytesMut::from(client.codec().serialize(&req_data).as_slice());
+        let req_bytes = #rpc::prepend_u64(#id, req_data_bytes);
+    };
+    let with_timeout = quote! {
+        match self.timeout {
+            Some(timeout) => ::bifrost::tcp::deadline::with_timeout(timeout, call).await,
+            None => call.await,
+        }
+    };
+    match m.kind {
+        Kind::Unary => {
+            let body = call(
+                quote!(Ok(local.#name(#(#arg),*).await)),
+                quote! {
+                    #request
+                    let res_bytes = #rpc::RPCClient::send_async(::std::pin::Pin::new(&**client), service_id, req_bytes).await?;
+                    client.codec().deserialize(&res_bytes).ok_or(#rpc::RPCError::ClientCannotDecodeResponse)
+                },
+            );
+            (
+                quote! {
+                    #(#attrs)*
+                    pub async fn #name<#(#lifetimes),*>(&self, #(#arg: #ty),*) -> Result<#out, #rpc::RPCError> {
+                        let call = #imme::#name(self.service_id, &self.client, #(#arg),*);
+                        #with_timeout
+                    }
+                },
+                quote! {
+                    #(#attrs)*
+                    pub async fn #name<#(#lifetimes),*>(
+                        service_id: u64,
+                        client: &::std::sync::Arc<#rpc::RPCClient>,
+                        #(#arg: #ty),*
+                    ) -> Result<#out, #rpc::RPCError> {
+                        #body
+                    }
+                },
+            )
+        }
+        Kind::Stream => {
+            let body = call(
+                quote!(Ok(#rpc::streaming::RPCStream::local(local.#name(#(#arg),*)))),
+                quote! {
+                    #request
+                    let res_bytes = #rpc::RPCClient::send_async(::std::pin::Pin::new(&**client), service_id, req_bytes).await?;
+                    match client.codec().deserialize(&res_bytes) {
+                        Some(stream_id) => Ok(#rpc::streaming::RPCStream::remote(client.clone(), stream_id)),
+                        None => Err(#rpc::RPCError::ClientCannotDecodeResponse),
+                    }
+                },
+            );
+            (
+                quote! {
+                    #(#attrs)*
+                    pub async fn #name<#(#lifetimes),*>(&self, #(#arg: #ty),*) -> Result<#rpc::streaming::RPCStream<#out>, #rpc::RPCError> {
+                        let call = #imme::#name(self.service_id, &self.client, #(#arg),*);
+                        #with_timeout
+                    }
+                },
+                quote! {
+                    #(#attrs)*
+                    /// Items are pulled from the server as the returned stream is consumed
+                    pub async fn #name<#(#lifetimes),*>(
+                        service_id: u64,
+                        client: &::std::sync::Arc<#rpc::RPCClient>,
+                        #(#arg: #ty),*
+                    ) -> Result<#rpc::streaming::RPCStream<#out>, #rpc::RPCError> {
+                        #body
+                    }
+                },
+            )
+        }
+        Kind::Upload => {
+            let (items, item) = m.items.as_ref().unwrap();
+            let body = call(
+                quote!(Ok(local.#name(#(#arg,)* #export::futures::stream::StreamExt::boxed(#items)).await)),
+                quote! {
+                    #request
+                    let res_bytes = #rpc::RPCClient::send_async(::std::pin::Pin::new(&**client), service_id, req_bytes).await?;
+                    let stream_id: u64 = match client.codec().deserialize(&res_bytes) {
+                        Some(stream_id) => stream_id,
+                        None => return Err(#rpc::RPCError::ClientCannotDecodeResponse),
+                    };
+                    let res_bytes = #rpc::streaming::upload(client, stream_id, #items).await?;
+                    client.codec().deserialize(&res_bytes).ok_or(#rpc::RPCError::ClientCannotDecodeResponse)
+                },
+            );
+            (
+                quote! {
+                    #(#attrs)*
+                    pub async fn #name<#(#lifetimes,)* S>(&self, #(#arg: #ty,)* #items: S) -> Result<#out, #rpc::RPCError>
+                    where
+                        S: #export::futures::stream::Stream<Item = #item> + Send + 'static,
+                    {
+                        let call = #imme::#name(self.service_id, &self.client, #(#arg,)* #items);
+                        #with_timeout
+                    }
+                },
+                quote! {
+                    #(#attrs)*
+                    /// Items are pushed to the server as fast as its handler takes them
+                    pub async fn #name<#(#lifetimes,)* S>(
+                        service_id: u64,
+                        client: &::std::sync::Arc<#rpc::RPCClient>,
+                        #(#arg: #ty,)*
+                        #items: S,
+                    ) -> Result<#out, #rpc::RPCError>
+                    where
+                        S: #export::futures::stream::Stream<Item = #item> + Send + 'static,
+                    {
+                        #body
+                    }
+                },
+            )
+        }
+        Kind::Oneway => {
+            let body = call(
+                quote! {
+                    #export::tokio::spawn(async move { local.#name(#(#arg),*).await });
+                    Ok(())
+                },
+                quote! {
+                    #request
+                    #rpc::RPCClient::send_oneway(::std::pin::Pin::new(&**client), service_id, req_bytes).await
+                },
+            );
+            (
+                quote! {
+                    #(#attrs)*
+                    pub async fn #name(&self, #(#arg: #ty),*) -> Result<(), #rpc::RPCError> {
+                        #imme::#name(self.service_id, &self.client, #(#arg),*).await
+                    }
+                },
+                quote! {
+                    #(#attrs)*
+                    /// Returns once the request is sent, without waiting for the server to handle it.
+                    /// Errors of the handler never get back to the caller.
+                    pub async fn #name(
+                        service_id: u64,
+                        client: &::std::sync::Arc<#rpc::RPCClient>,
+                        #(#arg: #ty),*
+                    ) -> Result<(), #rpc::RPCError> {
+                        #body
+                    }
+                },
+            )
+        }
+    }
+}
@@ -0,0 +1,326 @@
+// `#[state_machine]`, the attribute form of `raft_state_machine!`. Methods taking
+// `&mut self` are commands, the ones taking `&self` are queries, and the ones marked with
+// `#[subscribe]` only get a client method subscribing to the events of their name.
+use crate::{
+    check_ids, erase_lifetimes, fn_id, method_lifetimes, name_elided, plain_args, receiver,
+    self_lifetime, trait_generics,
+};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    Attribute, Block, Error, Ident, ItemTrait, LifetimeDef, Result, ReturnType, TraitItem, Type,
+};
+
+#[derive(PartialEq)]
+enum Kind {
+    Cmd,
+    Qry,
+    Sub,
+}
+
+struct Method {
+    kind: Kind,
+    attrs: Vec<Attribute>,
+    name: Ident,
+    lifetimes: Vec<LifetimeDef>,
+    args: Vec<(Ident, Type)>,
+    out: Type,
+    body: Option<Block>,
+}
+
+fn parse_method(item: TraitItem) -> Result<Method> {
+    let mut method = match item {
+        TraitItem::Method(method) => method,
+        other => {
+            return Err(Error::new(
+                other.span(),
+                "state machines can only declare methods",
+            ))
+        }
+    };
+    let mut subscribe = None;
+    method.attrs.retain(|attr| {
+        if attr.path.is_ident("subscribe") {
+            subscribe = Some(attr.span());
+            false
+        } else {
+            true
+        }
+    });
+    let sig = method.sig;
+    let mutable = receiver(&sig)?;
+    let kind = match subscribe {
+        Some(_) if method.default.is_some() => {
+            return Err(Error::new(
+                method.default.span(),
+                "subscriptions have no body to run",
+            ))
+        }
+        Some(span) if mutable => {
+            return Err(Error::new(span, "subscriptions take `&self`"));
+        }
+        Some(_) => Kind::Sub,
+        None if mutable => Kind::Cmd,
+        None => Kind::Qry,
+    };
+    if method.default.is_some() && sig.asyncness.is_none() {
+        return Err(Error::new(
+            sig.fn_token.span,
+            "default bodies need an `async fn`",
+        ));
+    }
+    let lifetimes = method_lifetimes(&sig)?;
+    let args = plain_args(&sig)?;
+    let out = match sig.output {
+        ReturnType::Type(_, ty) => *ty,
+        ReturnType::Default => syn::parse_quote!(()),
+    };
+    Ok(Method {
+        kind,
+        attrs: method.attrs,
+        name: sig.ident,
+        lifetimes,
+        args,
+        out,
+        body: method.default,
+    })
+}
+
+pub fn expand(item: ItemTrait) -> Result<TokenStream> {
+    let generics = trait_generics(&item)?;
+    let methods = item
+        .items
+        .into_iter()
+        .map(parse_method)
+        .collect::<Result<Vec<_>>>()?;
+    check_ids(&methods.iter().map(|m| m.name.clone()).collect::<Vec<_>>())?;
+    let vis = &item.vis;
+    let trait_name = &item.ident;
+    let trait_attrs = &item.attrs;
+    let supertraits = item.supertraits.iter();
+    let export = quote!(::bifrost::macros::export);
+    let sm = quote!(::bifrost::raft::state_machine);
+    let codec = quote!(&'static dyn ::bifrost::utils::serde::Codec);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let params: Vec<_> = generics.type_params().map(|param| &param.ident).collect();
+    let lt = self_lifetime();
+
+    let op_type = |kind: &Kind| match kind {
+        Kind::Cmd => quote!(#sm::OpType::COMMAND),
+        Kind::Qry => quote!(#sm::OpType::QUERY),
+        Kind::Sub => quote!(#sm::OpType::SUBSCRIBE),
+    };
+
+    let commands = methods.iter().map(|m| {
+        let (name, out) = (&m.name, &m.out);
+        let id = fn_id(name);
+        let op = op_type(&m.kind);
+        let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+        let ty: Vec<_> = ty.iter().map(|ty| name_elided(ty, &lt)).collect();
+        // Commands borrow the arguments for the lifetimes of the method and the types of the
+        // trait
+        let lifetimes: Vec<_> = m.lifetimes.iter().map(|def| &def.lifetime).collect();
+        let generics = quote!(<#lt, #(#lifetimes,)* #(#params),*>);
+        // Arguments are only encoded once the codec of the raft service is known
+        quote! {
+            #[allow(non_camel_case_types)]
+            pub struct #name #generics #where_clause {
+                args: (#(&#lt #ty,)*),
+                _args: ::std::marker::PhantomData<(&#lt (), #(&#lifetimes (),)* fn() -> (#(#params,)*))>,
+            }
+            impl #generics ::bifrost::raft::RaftMsg<#out> for #name #generics #where_clause {
+                fn encode(self, codec: #codec) -> (u64, #sm::OpType, Vec<u8>) {
+                    (#id, #op, codec.serialize(&self.args))
+                }
+                fn decode_return(codec: #codec, data: &Vec<u8>) -> #out {
+                    codec.deserialize(data).unwrap()
+                }
+            }
+            impl #generics #name #generics #where_clause {
+                pub fn new(#(#arg: &#lt #ty),*) -> Self {
+                    #name {
+                        args: (#(#arg,)*),
+                        _args: ::std::marker::PhantomData,
+                    }
+                }
+            }
+        }
+    });
+
+    let trait_fns = methods.iter().filter(|m| m.kind != Kind::Sub).map(|m| {
+        let (attrs, name, out, lifetimes) = (&m.attrs, &m.name, &m.out, &m.lifetimes);
+        let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+        // The returned future can hold the borrowed arguments
+        let ty = ty.iter().map(|ty| name_elided(ty, &lt));
+        let outlives = lifetimes.iter().map(|def| &def.lifetime);
+        let receiver = match m.kind {
+            Kind::Cmd => quote!(&#lt mut self),
+            _ => quote!(&#lt self),
+        };
+        let body = match m.body {
+            Some(ref body) => quote!({ ::std::boxed::Box::pin(async move #body) }),
+            None => quote!(;),
+        };
+        quote! {
+            #(#attrs)*
+            fn #name<#lt, #(#lifetimes),*>(#receiver, #(#arg: #ty),*) -> #export::futures::future::BoxFuture<#lt, #out>
+            where
+                #(#outlives: #lt),*
+            #body
+        }
+    });
+
+    let op_type_arms = methods.iter().map(|m| {
+        let id = fn_id(&m.name);
+        let op = op_type(&m.kind);
+        quote!(#id => Some(#op),)
+    });
+
+    let dispatch_arms = |kind: Kind| {
+        methods
+            .iter()
+            .filter(|m| m.kind == kind)
+            .map(|m| {
+                let name = &m.name;
+                let id = fn_id(name);
+                let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+                // Borrowed arguments borrow from the log entry
+                let ty = ty.iter().map(erase_lifetimes);
+                quote! {
+                    #id => {
+                        let (#(#arg,)*): (#(#ty,)*) = codec.deserialize(data).unwrap();
+                        let f_result = self.#name(#(#arg),*).await;
+                        Some(codec.serialize(&f_result))
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let cmd_arms = dispatch_arms(Kind::Cmd);
+    let qry_arms = dispatch_arms(Kind::Qry);
+
+    let turbofish = quote!(<#(#params),*>);
+    let client_fns = methods.iter().map(|m| {
+        let (attrs, name, out, lifetimes) = (&m.attrs, &m.name, &m.out, &m.lifetimes);
+        let (arg, ty): (Vec<_>, Vec<_>) = m.args.iter().cloned().unzip();
+        match m.kind {
+            Kind::Sub => quote! {
+                #(#attrs)*
+                pub fn #name<#(#lifetimes,)* F>(
+                    &self,
+                    f: F,
+                    #(#arg: &#ty),*
+                ) -> #export::futures::future::BoxFuture<
+                    Result<
+                        Result<::bifrost::raft::client::SubscriptionReceipt, ::bifrost::raft::client::SubscriptionError>,
+                        #sm::master::ExecError,
+                    >,
+                >
+                where
+                    F: Fn(#out) -> #export::futures::future::BoxFuture<'static, ()> + 'static + Send + Sync,
+                {
+                    self.client.subscribe(self.sm_id, super::commands::#name::#turbofish::new(#(#arg),*), f)
+                }
+            },
+            _ => quote! {
+                #(#attrs)*
+                pub async fn #name<#(#lifetimes),*>(&self, #(#arg: &#ty),*) -> Result<#out, #sm::master::ExecError> {
+                    self.client
+                        .execute(self.sm_id, super::commands::#name::#turbofish::new(#(#arg),*))
+                        .await
+                }
+            },
+        }
+    });
+
+    // Clients of generic state machines are generic too
+    let (params_field, params_value) = if params.is_empty() {
+        (quote!(), quote!())
+    } else {
+        (
+            quote!(_params: ::std::marker::PhantomData<fn() -> (#(#params,)*)>,),
+            quote!(_params: ::std::marker::PhantomData,),
+        )
+    };
+
+    Ok(quote! {
+        #[allow(dead_code)]
+        pub mod commands {
+            #[allow(unused_imports)]
+            use super::*;
+            #(#commands)*
+        }
+
+        #(#trait_attrs)*
+        #vis trait #trait_name #impl_generics: #sm::StateMachineCtl #(+ #supertraits)* #where_clause {
+            #(#trait_fns)*
+            fn op_type_(&self, fn_id: u64) -> Option<#sm::OpType> {
+                match fn_id {
+                    #(#op_type_arms)*
+                    _ => {
+                        #export::log::debug!("Undefined function id: {}", fn_id);
+                        None
+                    }
+                }
+            }
+            #[allow(unused_variables, clippy::ptr_arg)]
+            fn dispatch_cmd_<'a>(
+                &'a mut self,
+                codec: #codec,
+                fn_id: u64,
+                data: &'a Vec<u8>,
+            ) -> #export::futures::future::BoxFuture<'a, Option<Vec<u8>>> {
+                ::std::boxed::Box::pin(async move {
+                    match fn_id {
+                        #(#cmd_arms)*
+                        _ => None,
+                    }
+                })
+            }
+            #[allow(unused_variables, clippy::ptr_arg)]
+            fn dispatch_qry_<'a>(
+                &'a self,
+                codec: #codec,
+                fn_id: u64,
+                data: &'a Vec<u8>,
+            ) -> #export::futures::future::BoxFuture<'a, Option<Vec<u8>>> {
+                ::std::boxed::Box::pin(async move {
+                    match fn_id {
+                        #(#qry_arms)*
+                        _ => None,
+                    }
+                })
+            }
+        }
+
+        #[allow(dead_code)]
+        pub mod client {
+            #[allow(unused_imports)]
+            use super::*;
+            use ::std::sync::Arc;
+            use ::bifrost::raft::client::RaftClient;
+
+            pub struct SMClient #impl_generics #where_clause {
+                client: Arc<RaftClient>,
+                sm_id: u64,
+                #params_field
+            }
+            impl #impl_generics SMClient #ty_generics #where_clause {
+                #(#client_fns)*
+                pub fn new(sm_id: u64, client: &Arc<RaftClient>) -> Self {
+                    Self {
+                        client: client.clone(),
+                        sm_id,
+                        #params_value
+                    }
+                }
+            }
+            impl #impl_generics #sm::StateMachineClient for SMClient #ty_generics #where_clause {
+                fn new_instance(sm_id: u64, client: &Arc<RaftClient>) -> Self {
+                    Self::new(sm_id, client)
+                }
+            }
+        }
+    })
+}
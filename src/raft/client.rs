@@ -7,11 +7,15 @@ use crate::raft::state_machine::configs::commands::{
 use crate::raft::state_machine::master::ExecError;
 use crate::raft::state_machine::StateMachineClient;
 use crate::rpc;
+use crate::rpc::retry::RetryPolicy;
+use crate::tcp::client::ClientOptions;
 use bifrost_hasher::{hash_bytes, hash_str};
 use futures::future::BoxFuture;
 use std::clone::Clone;
 use std::cmp::max;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::iter::FromIterator;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -38,6 +42,47 @@ pub enum SubscriptionError {
     CannotFindSubId,
 }
 
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::LeaderIdValid => write!(f, "leader id is not valid"),
+            ClientError::ServerUnreachable => write!(f, "raft servers unreachable"),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubscriptionError::RemoteError => write!(f, "subscription failed on the server"),
+            SubscriptionError::SubServiceNotSet => write!(f, "subscription service not set"),
+            SubscriptionError::CannotFindSubId => write!(f, "subscription not found"),
+        }
+    }
+}
+
+impl Error for SubscriptionError {}
+
+#[derive(Debug)]
+enum CommandFailure {
+    SwitchLeader,
+    NotCommitted,
+    UpdateInfo,
+    NotLeader,
+}
+
+// Queries and commands try other members on every attempt, backing off a little only
+fn retry_policy(max_attempts: usize) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: max_attempts as u32,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(500),
+        retryable: None,
+    }
+}
+
 struct QryMeta {
     pos: AtomicU64,
 }
@@ -54,10 +99,35 @@ pub struct RaftClient {
     last_log_id: AtomicU64,
     last_log_term: AtomicU64,
     service_id: u64,
+    // `rpc::DEFAULT_CLIENT_POOL` when not set
+    pool: Option<Arc<rpc::ClientPool>>,
 }
 
 impl RaftClient {
     pub async fn new(servers: &Vec<String>, service_id: u64) -> Result<Arc<Self>, ClientError> {
+        Self::connect(servers, service_id, None).await
+    }
+    // Connect to the servers with `options`, like the node id of the member using the client
+    pub async fn new_with_options(
+        servers: &Vec<String>,
+        service_id: u64,
+        options: ClientOptions,
+    ) -> Result<Arc<Self>, ClientError> {
+        let pool = Arc::new(rpc::ClientPool::new_with_options(options));
+        Self::connect(servers, service_id, Some(pool)).await
+    }
+    pub async fn new_with_pool(
+        servers: &Vec<String>,
+        service_id: u64,
+        pool: &Arc<rpc::ClientPool>,
+    ) -> Result<Arc<Self>, ClientError> {
+        Self::connect(servers, service_id, Some(pool.clone())).await
+    }
+    async fn connect(
+        servers: &Vec<String>,
+        service_id: u64,
+        pool: Option<Arc<rpc::ClientPool>>,
+    ) -> Result<Arc<Self>, ClientError> {
         let client = RaftClient {
             qry_meta: QryMeta {
                 pos: AtomicU64::new(rand::random::<u64>()),
@@ -70,6 +140,7 @@ impl RaftClient {
             last_log_id: AtomicU64::new(0),
             last_log_term: AtomicU64::new(0),
             service_id,
+            pool,
         };
         client.update_info(servers).await?;
         Ok(Arc::new(client))
@@ -84,6 +155,17 @@ impl RaftClient {
             None
         };
     }
+    fn pool(&self) -> &rpc::ClientPool {
+        match self.pool {
+            Some(ref pool) => pool,
+            None => &rpc::DEFAULT_CLIENT_POOL,
+        }
+    }
+    // Arguments and results of state machine calls are encoded with it, which must be the
+    // codec of the raft service
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.pool().codec()
+    }
 
     async fn cluster_info<'a>(&'a self, servers: &Vec<String>) -> Option<ClientClusterInfo> {
         debug!("Getting server info for {:?}", servers);
@@ -103,7 +185,7 @@ impl RaftClient {
                         debug!("Checking server info on {}", server_addr);
                         if !members.clients.contains_key(&id) {
                             debug!("Connecting to node {}", server_addr);
-                            match rpc::DEFAULT_CLIENT_POOL.get(&server_addr).await {
+                            match self.pool().get(&server_addr).await {
                                 Ok(client) => {
                                     debug!("Added server info on {} to members", server_addr);
                                     members.clients.insert(
@@ -193,14 +275,20 @@ impl RaftClient {
                 for id in members.clients.keys() {
                     connected_ids.insert(*id);
                 }
-                let ids_to_remove = connected_ids.difference(&remote_ids);
-                for id in ids_to_remove {
-                    members.clients.remove(id);
+                for id in connected_ids.iter() {
+                    // Members that left, or moved to another address
+                    let moved = match members.id_map.get(id) {
+                        Some(addr) => &members.clients.get(id).unwrap().client.address != addr,
+                        None => true,
+                    };
+                    if moved {
+                        members.clients.remove(id);
+                    }
                 }
-                for id in remote_ids.difference(&connected_ids) {
+                for id in remote_ids.iter() {
                     let addr = members.id_map.get(id).unwrap().clone();
                     if !members.clients.contains_key(id) {
-                        if let Ok(client) = rpc::DEFAULT_CLIENT_POOL.get(&addr).await {
+                        if let Ok(client) = self.pool().get(&addr).await {
                             members
                                 .clients
                                 .insert(*id, AsyncServiceClient::new(self.service_id, &client));
@@ -248,17 +336,17 @@ impl RaftClient {
 
     pub async fn execute<R, M>(&self, sm_id: u64, msg: M) -> Result<R, ExecError>
     where
-        R: 'static,
-        M: RaftMsg<R> + 'static,
+        M: RaftMsg<R>,
     {
-        let (fn_id, op, req_data) = msg.encode();
+        let codec = self.codec();
+        let (fn_id, op, req_data) = msg.encode(codec);
         let response = match op {
             OpType::QUERY => self.query(sm_id, fn_id, req_data).await,
             OpType::COMMAND | OpType::SUBSCRIBE => self.command(sm_id, fn_id, req_data).await,
         };
         match response {
             Ok(data) => match data {
-                Ok(data) => Ok(M::decode_return(&data)),
+                Ok(data) => Ok(M::decode_return(codec, &data)),
                 Err(e) => Err(e),
             },
             Err(e) => Err(e),
@@ -270,12 +358,11 @@ impl RaftClient {
     }
     fn get_sub_key<M, R>(&self, sm_id: u64, msg: M) -> SubKey
     where
-        M: RaftMsg<R> + 'static,
-        R: 'static,
+        M: RaftMsg<R>,
     {
         let raft_sid = self.service_id;
         let (fn_id, pattern_id) = {
-            let (fn_id, _, pattern_data) = msg.encode();
+            let (fn_id, _, pattern_data) = msg.encode(self.codec());
             (fn_id, hash_bytes(pattern_data.as_slice()))
         };
         return (raft_sid, sm_id, fn_id, pattern_id);
@@ -291,41 +378,48 @@ impl RaftClient {
         }
     }
 
-    pub async fn subscribe<M, R, F>(
+    // The key is made before the returned future, which does not borrow the message
+    pub fn subscribe<M, R, F>(
         &self,
         sm_id: u64,
         msg: M,
         f: F,
-    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    ) -> BoxFuture<'_, Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>>
     where
-        M: RaftMsg<R> + 'static,
+        M: RaftMsg<R>,
         R: 'static + Send,
         F: Fn(R) -> BoxFuture<'static, ()> + 'static + Send + Sync,
     {
-        let callback = match self.get_callback().await {
-            Ok(c) => c,
-            Err(e) => return Ok(Err(e)),
-        };
         let key = self.get_sub_key(sm_id, msg);
-        let wrapper_fn =
-            move |data: Vec<u8>| -> BoxFuture<'static, ()> { f(M::decode_return(&data)).boxed() };
-        let cluster_subs = self
-            .execute(
-                CONFIG_SM_ID,
-                conf_subscribe::new(&key, &callback.server_address, &callback.session_id),
-            )
-            .await;
-        match cluster_subs {
-            Ok(Ok(sub_id)) => {
-                let mut subs_map = callback.subs.write().await;
-                let subs_lst = subs_map.entry(key).or_insert_with(|| Vec::new());
-                let boxed_fn = Box::new(wrapper_fn);
-                subs_lst.push((boxed_fn, sub_id));
-                Ok(Ok((key, sub_id)))
+        let codec = self.codec();
+        let decode_return: fn(&'static dyn Codec, &Vec<u8>) -> R = M::decode_return;
+        let wrapper_fn = move |data: Vec<u8>| -> BoxFuture<'static, ()> {
+            f(decode_return(codec, &data)).boxed()
+        };
+        async move {
+            let callback = match self.get_callback().await {
+                Ok(c) => c,
+                Err(e) => return Ok(Err(e)),
+            };
+            let cluster_subs = self
+                .execute(
+                    CONFIG_SM_ID,
+                    conf_subscribe::new(&key, &callback.server_address, &callback.session_id),
+                )
+                .await;
+            match cluster_subs {
+                Ok(Ok(sub_id)) => {
+                    let mut subs_map = callback.subs.write().await;
+                    let subs_lst = subs_map.entry(key).or_insert_with(|| Vec::new());
+                    let boxed_fn = Box::new(wrapper_fn);
+                    subs_lst.push((boxed_fn, sub_id));
+                    Ok(Ok((key, sub_id)))
+                }
+                Ok(Err(_)) => Ok(Err(SubscriptionError::RemoteError)),
+                Err(e) => Err(e),
             }
-            Ok(Err(_)) => Ok(Err(SubscriptionError::RemoteError)),
-            Err(e) => Err(e),
         }
+        .boxed()
     }
 
     pub async fn unsubscribe(
@@ -367,75 +461,78 @@ impl RaftClient {
     }
 
     async fn query(&self, sm_id: u64, fn_id: u64, data: Vec<u8>) -> Result<ExecResult, ExecError> {
-        let mut depth = 0;
-        loop {
-            if depth == 0 {
-                trace!("Raft client query sm_id {}, fn_id {}", sm_id, fn_id);
-            } else {
-                warn!("Retry client query sm_id {}, fn_id {}", sm_id, fn_id);
+        trace!("Raft client query sm_id {}, fn_id {}", sm_id, fn_id);
+        // Every attempt goes to the next member, so each of them gets a chance
+        let num_members = self.members.read().await.clients.len();
+        retry_policy(num_members + 1)
+            .run_with(
+                |e| match e {
+                    ExecError::ServersUnreachable => false,
+                    _ => true,
+                },
+                || self.query_once(sm_id, fn_id, &data),
+            )
+            .await
+    }
+
+    async fn query_once(
+        &self,
+        sm_id: u64,
+        fn_id: u64,
+        data: &Vec<u8>,
+    ) -> Result<ExecResult, ExecError> {
+        let pos = self.qry_meta.pos.fetch_add(1, ORDERING);
+        let members = self.members.read().await;
+        let num_members = members.clients.len();
+        if num_members == 0 {
+            return Err(ExecError::ServersUnreachable);
+        }
+        let node_index = pos as usize % num_members;
+        let rpc_client = members.clients.values().nth(node_index).unwrap();
+        trace!(
+            "Query from node {} for sm_id {}, fn_id {}",
+            node_index,
+            sm_id,
+            fn_id
+        );
+        let res = rpc_client
+            .c_query(self.gen_log_entry(sm_id, fn_id, data))
+            .await;
+        trace!(
+            "Query from node {} for sm_id {}, fn_id {} completed",
+            node_index,
+            sm_id,
+            fn_id
+        );
+        match res {
+            // Errors of the last attempt are the ones returned
+            Ok(ClientQryResponse::LeftBehind) => {
+                debug!("Found left behind record on node {}", node_index);
+                Err(ExecError::TooManyRetry)
             }
-            let pos = self.qry_meta.pos.fetch_add(1, ORDERING);
-            let members = self.members.read().await;
-            let num_members = members.clients.len();
-            if num_members >= 1 {
-                let node_index = pos as usize % num_members;
-                let rpc_client = members.clients.values().nth(node_index).unwrap();
+            Ok(ClientQryResponse::Success {
+                data,
+                last_log_term,
+                last_log_id,
+            }) => {
+                swap_when_greater(&self.last_log_id, last_log_id);
+                swap_when_greater(&self.last_log_term, last_log_term);
                 trace!(
-                    "Query from node {} for sm_id {}, fn_id {}",
+                    "Query from node {} for sm_id {}, fn_id {}, successful at log id {}, term {}",
                     node_index,
                     sm_id,
-                    fn_id
+                    fn_id,
+                    last_log_id,
+                    last_log_term
                 );
-                let res = rpc_client
-                    .c_query(self.gen_log_entry(sm_id, fn_id, &data))
-                    .await;
-                trace!(
-                    "Query from node {} for sm_id {}, fn_id {} completed",
-                    node_index,
-                    sm_id,
-                    fn_id
+                Ok(data)
+            }
+            Err(e) => {
+                error!(
+                    "Got unknown error on query: {:?}, server {}",
+                    e, rpc_client.client.address
                 );
-                match res {
-                    Ok(res) => match res {
-                        ClientQryResponse::LeftBehind => {
-                            debug!("Found left behind record...{}", depth);
-                            if depth >= num_members {
-                                return Err(ExecError::TooManyRetry);
-                            } else {
-                                depth += 1;
-                                continue;
-                            }
-                        }
-                        ClientQryResponse::Success {
-                            data,
-                            last_log_term,
-                            last_log_id,
-                        } => {
-                            swap_when_greater(&self.last_log_id, last_log_id);
-                            swap_when_greater(&self.last_log_term, last_log_term);
-                            if depth > 0 {
-                                warn!("Retry successful...{}", depth);
-                            }
-                            trace!("Query from node {} for sm_id {}, fn_id {}, successful at log id {}, term {}", node_index, sm_id, fn_id, last_log_id, last_log_term);
-                            return Ok(data);
-                        }
-                    },
-                    Err(e) => {
-                        error!(
-                            "Got unknown error on query: {:?}, server {}",
-                            e, rpc_client.client.address
-                        );
-                        if depth >= num_members {
-                            return Err(ExecError::Unknown);
-                        } else {
-                            debug!("Retry query...{}", depth);
-                            depth += 1;
-                            continue;
-                        }
-                    }
-                }
-            } else {
-                return Err(ExecError::ServersUnreachable);
+                Err(ExecError::Unknown)
             }
         }
     }
@@ -446,82 +543,84 @@ impl RaftClient {
         fn_id: u64,
         data: Vec<u8>,
     ) -> Result<ExecResult, ExecError> {
-        enum FailureAction {
-            SwitchLeader,
-            NotCommitted,
-            UpdateInfo,
-            NotLeader,
-        }
-        let mut depth = 0;
-        loop {
-            let failure = {
-                if depth > 0 {
-                    let members = self.members.read().await;
-                    let num_members = members.clients.len();
-                    if depth >= max(num_members + 1, 5) {
-                        return Err(ExecError::TooManyRetry);
-                    };
-                }
-                match self.current_leader_client().await {
-                    Some((leader_id, client)) => {
-                        let cmd_res = client
-                            .c_command(self.gen_log_entry(sm_id, fn_id, &data))
-                            .await;
-                        match cmd_res {
-                            Ok(ClientCmdResponse::Success {
-                                data,
-                                last_log_term,
-                                last_log_id,
-                            }) => {
-                                swap_when_greater(&self.last_log_id, last_log_id);
-                                swap_when_greater(&self.last_log_term, last_log_term);
-                                return Ok(data);
-                            }
-                            Ok(ClientCmdResponse::NotLeader(new_leader_id)) => {
-                                if new_leader_id == 0 || leader_id == leader_id {
-                                    debug!(
-                                        "CLIENT: NOT LEADER, SUGGESTION NOT USEFUL, PROBE. GOT: {}",
-                                        new_leader_id
-                                    );
-                                    FailureAction::SwitchLeader
-                                } else {
-                                    debug!(
-                                        "CLIENT: NOT LEADER, REMOTE SUGGEST SWITCH TO {}",
-                                        new_leader_id
-                                    );
-                                    self.leader_id.store(new_leader_id, ORDERING);
-                                    FailureAction::NotLeader
-                                }
-                            }
-                            Ok(ClientCmdResponse::NotCommitted) => FailureAction::NotCommitted,
-                            Err(e) => {
-                                debug!("CLIENT: ERROR - {} - {:?}", leader_id, e);
-                                FailureAction::SwitchLeader // need switch server for leader
-                            }
+        let num_members = self.members.read().await.clients.len();
+        let attempts = AtomicU64::new(0);
+        // Every failure moves on to another leader, whatever the last one was the
+        // command ran out of attempts
+        retry_policy(max(num_members + 1, 5))
+            .run_with(
+                |_| true,
+                || {
+                    let attempt = attempts.fetch_add(1, ORDERING);
+                    self.command_once(sm_id, fn_id, &data, attempt)
+                },
+            )
+            .await
+            .map_err(|_| ExecError::TooManyRetry)
+    }
+
+    async fn command_once(
+        &self,
+        sm_id: u64,
+        fn_id: u64,
+        data: &Vec<u8>,
+        attempt: u64,
+    ) -> Result<ExecResult, CommandFailure> {
+        let failure = match self.current_leader_client().await {
+            Some((leader_id, client)) => {
+                let cmd_res = client
+                    .c_command(self.gen_log_entry(sm_id, fn_id, data))
+                    .await;
+                match cmd_res {
+                    Ok(ClientCmdResponse::Success {
+                        data,
+                        last_log_term,
+                        last_log_id,
+                    }) => {
+                        swap_when_greater(&self.last_log_id, last_log_id);
+                        swap_when_greater(&self.last_log_term, last_log_term);
+                        return Ok(data);
+                    }
+                    Ok(ClientCmdResponse::NotLeader(new_leader_id)) => {
+                        if new_leader_id == 0 || leader_id == leader_id {
+                            debug!(
+                                "CLIENT: NOT LEADER, SUGGESTION NOT USEFUL, PROBE. GOT: {}",
+                                new_leader_id
+                            );
+                            CommandFailure::SwitchLeader
+                        } else {
+                            debug!(
+                                "CLIENT: NOT LEADER, REMOTE SUGGEST SWITCH TO {}",
+                                new_leader_id
+                            );
+                            self.leader_id.store(new_leader_id, ORDERING);
+                            CommandFailure::NotLeader
                         }
                     }
-                    None => FailureAction::UpdateInfo, // need update members
-                }
-            }; //
-            match failure {
-                FailureAction::SwitchLeader => {
-                    debug!("Switch leader by probing");
-                    let members = self.members.read().await;
-                    let num_members = members.clients.len();
-                    let leader_id = self.leader_id.load(ORDERING);
-                    let new_leader_id = members
-                        .clients
-                        .keys()
-                        .nth(depth as usize % num_members)
-                        .unwrap();
-                    self.leader_id
-                        .compare_and_swap(leader_id, *new_leader_id, ORDERING);
-                    debug!("CLIENT: Switch leader {}", new_leader_id);
+                    Ok(ClientCmdResponse::NotCommitted) => CommandFailure::NotCommitted,
+                    Err(e) => {
+                        debug!("CLIENT: ERROR - {} - {:?}", leader_id, e);
+                        CommandFailure::SwitchLeader // need switch server for leader
+                    }
                 }
-                _ => {}
             }
-            depth += 1;
+            None => CommandFailure::UpdateInfo, // need update members
+        };
+        if let CommandFailure::SwitchLeader = failure {
+            debug!("Switch leader by probing");
+            let members = self.members.read().await;
+            let num_members = members.clients.len();
+            let leader_id = self.leader_id.load(ORDERING);
+            let new_leader_id = members
+                .clients
+                .keys()
+                .nth(attempt as usize % num_members)
+                .unwrap();
+            self.leader_id
+                .compare_and_swap(leader_id, *new_leader_id, ORDERING);
+            debug!("CLIENT: Switch leader {}", new_leader_id);
         }
+        Err(failure)
     }
 
     fn gen_log_entry(&self, sm_id: u64, fn_id: u64, data: &Vec<u8>) -> LogEntry {
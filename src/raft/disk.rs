@@ -1,12 +1,13 @@
 // Now only offers log persistent
 
 use crate::raft::{LogEntry, LogsMap, Options, RaftMeta, Storage};
+use crate::utils::serde::{codec_by_id, Codec, LEGACY_CODEC};
 use async_std::sync::*;
 use serde::{Deserialize, Serialize};
 
 use std::fs::OpenOptions;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::ops::Bound::*;
 use std::path::Path;
 use tokio::fs::*;
@@ -14,6 +15,11 @@ use tokio::io::*;
 
 // const MAX_LOG_CAPACITY: usize = 10;
 
+// Log files start with the magic and the id of the codec of their entries. Files written
+// before the header existed start right with the first entry.
+const LOG_MAGIC: [u8; 4] = *b"BFLG";
+const LOG_HEADER_LEN: usize = 5;
+
 #[derive(Clone)]
 pub struct DiskOptions {
     pub path: String,
@@ -26,6 +32,8 @@ pub struct StorageEntity {
     pub logs: Option<File>,
     pub snapshot: Option<File>,
     pub last_term: u64,
+    // Of the entries in the log file, which stays on it when the configured one changes
+    pub codec: &'static dyn Codec,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,6 +44,39 @@ struct DiskLogEntry {
     log: LogEntry,
 }
 
+// Codec of the entries in a log file, writing the header with the configured one into
+// new files
+fn log_codec(
+    file: &mut std::fs::File,
+    configured: &'static dyn Codec,
+) -> io::Result<&'static dyn Codec> {
+    let mut header = [0u8; LOG_HEADER_LEN];
+    if file.metadata()?.len() == 0 {
+        header[..4].copy_from_slice(&LOG_MAGIC);
+        header[4] = configured.id();
+        file.write_all(&header)?;
+        return Ok(configured);
+    }
+    if file.read_exact(&mut header).is_ok() && header[..4] == LOG_MAGIC {
+        let codec = codec_by_id(header[4]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("raft log is encoded with unknown codec {}", header[4]),
+            )
+        })?;
+        if codec != configured {
+            warn!(
+                "Raft log is encoded with {}, keep using it instead of {}",
+                codec.name(),
+                configured.name()
+            );
+        }
+        return Ok(codec);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(LEGACY_CODEC)
+}
+
 impl StorageEntity {
     pub fn new_with_options(
         opts: &Options,
@@ -56,9 +97,11 @@ impl StorageEntity {
                     .create(true)
                     .read(true)
                     .truncate(false);
+                let mut codec = opts.codec();
                 Some(Self {
                     logs: if options.append_logs {
                         let mut log_file = open_opts.open(log_path.as_path())?;
+                        codec = log_codec(&mut log_file, codec)?;
                         let mut len_buf = [0u8; 8];
                         let mut counter = 0;
                         loop {
@@ -70,10 +113,9 @@ impl StorageEntity {
                             if log_file.read_exact(&mut data_buf).is_err() {
                                 break;
                             }
-                            let entry = crate::utils::serde::deserialize::<DiskLogEntry>(
-                                data_buf.as_slice(),
-                            )
-                            .unwrap();
+                            let entry = codec
+                                .deserialize::<DiskLogEntry>(data_buf.as_slice())
+                                .unwrap();
                             *term = entry.term;
                             *commit_index = entry.commit_index;
                             *last_applied = entry.last_applied;
@@ -91,6 +133,7 @@ impl StorageEntity {
                         None
                     },
                     last_term: 0,
+                    codec,
                 })
             }
             _ => None,
@@ -113,7 +156,7 @@ impl StorageEntity {
                     last_applied: meta.last_applied,
                     log: log.clone(),
                 };
-                let entry_data = crate::utils::serde::serialize(&entry);
+                let entry_data = self.codec.serialize(&entry);
                 f.write(&(entry_data.len() as u64).to_le_bytes()).await?;
                 f.write(entry_data.as_slice()).await?;
                 self.last_term = *term;
@@ -1,10 +1,18 @@
-use self::state_machine::configs::commands::{del_member_, member_address, new_member_};
+use self::state_machine::configs::commands::{
+    add_member_, member_list, remove_member_, update_member_address,
+};
 use self::state_machine::configs::{RaftMember, CONFIG_SM_ID};
-use self::state_machine::master::{ExecError, ExecResult, MasterStateMachine, SubStateMachine};
+use self::state_machine::master::{
+    ExecError, ExecResult, MasterStateMachine, RegisterResult, SubStateMachine,
+};
 use self::state_machine::OpType;
 use crate::raft::client::RaftClient;
 use crate::raft::disk::*;
 use crate::raft::state_machine::StateMachineCtl;
+use crate::rpc::ClientPool;
+use crate::tcp::client::ClientOptions;
+use crate::tcp::server::ServerOptions;
+use crate::utils::serde::{default_codec, Codec};
 use crate::utils::time::get_time;
 use async_std::sync::*;
 use bifrost_hasher::hash_str;
@@ -31,9 +39,10 @@ pub mod disk;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_DEFAULT_SERVICE) as u64;
 
+// Arguments and results are encoded with the codec of the raft service
 pub trait RaftMsg<R>: Send + Sync {
-    fn encode(self) -> (u64, OpType, Vec<u8>);
-    fn decode_return(data: &Vec<u8>) -> R;
+    fn encode(self, codec: &'static dyn Codec) -> (u64, OpType, Vec<u8>);
+    fn decode_return(codec: &'static dyn Codec, data: &Vec<u8>) -> R;
 }
 
 const CHECKER_MS: i64 = 50;
@@ -170,14 +179,59 @@ impl Storage {
 #[derive(Clone)]
 pub struct Options {
     pub storage: Storage,
+    // Address other members and clients dial to reach this node
     pub address: String,
+    // Address `new_server` binds to when it differs from the advertised one
+    pub bind_address: Option<String>,
     pub service_id: u64,
+    // Identity of the node in the cluster, must be non-zero and stay the same across
+    // restarts for the node to keep its membership when its address changes.
+    // Derived from the address when not set.
+    pub node_id: Option<u64>,
+    // Encoding of new disk logs, state machine calls and snapshots, connections to other
+    // members and the server `new_server` starts. Members of a cluster and their clients
+    // must use the same one. `utils::serde::default_codec` when not set.
+    pub codec: Option<&'static dyn Codec>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            storage: Storage::default(),
+            address: String::new(),
+            bind_address: None,
+            service_id: DEFAULT_SERVICE_ID,
+            node_id: None,
+            codec: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn node_id(&self) -> u64 {
+        self.node_id.unwrap_or_else(|| hash_str(&self.address))
+    }
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.codec.unwrap_or_else(default_codec)
+    }
+    // Options of connections to other members, identifying them as coming from this node
+    // and, for injected faults, from its server
+    pub fn client_options(&self) -> ClientOptions {
+        ClientOptions {
+            node_id: self.node_id(),
+            sender_address: Some(self.address.clone()),
+            codec: self.codec(),
+            ..ClientOptions::default()
+        }
+    }
 }
 
 pub struct RaftService {
     meta: RwLock<RaftMeta>,
     pub id: u64,
     pub options: Options,
+    // Shared by the members of the config state machine and the clients of `join`/`leave`
+    clients: Arc<ClientPool>,
     rt: runtime::Runtime,
     _is_leader: AtomicBool,
 }
@@ -249,8 +303,7 @@ fn alter_term(meta: &mut RwLockWriteGuard<RaftMeta>, term: u64) {
 
 impl RaftService {
     pub fn new(opts: Options) -> Arc<RaftService> {
-        let server_address = opts.address.clone();
-        let server_id = hash_str(&server_address);
+        let server_id = opts.node_id();
 
         let mut term = 0;
         let mut logs = BTreeMap::new();
@@ -266,7 +319,8 @@ impl RaftService {
         )
         .unwrap();
 
-        let master_sm = MasterStateMachine::new(opts.service_id);
+        let clients = Arc::new(ClientPool::new_with_options(opts.client_options()));
+        let master_sm = MasterStateMachine::new(opts.service_id, &clients, opts.codec());
 
         let server_obj = RaftService {
             meta: RwLock::new(RaftMeta {
@@ -284,6 +338,7 @@ impl RaftService {
             }),
             id: server_id,
             options: opts,
+            clients,
             rt: runtime::Builder::new()
                 .enable_all()
                 .core_threads(10)
@@ -306,7 +361,11 @@ impl RaftService {
             let start_time = get_time();
             while get_time() < start_time + 5000 {
                 //waiting for 5 secs
-                if sm.configs.new_member(server_address.clone()).await {
+                if sm
+                    .configs
+                    .update_member(server.id, server_address.clone())
+                    .await
+                {
                     inited = true;
                     break;
                 }
@@ -405,10 +464,15 @@ impl RaftService {
     pub async fn new_server(opts: Options) -> (bool, Arc<RaftService>, Arc<Server>) {
         let address = opts.address.clone();
         let svr_id = opts.service_id;
+        let server_options = ServerOptions {
+            bind_address: opts.bind_address.clone(),
+            codec: opts.codec(),
+            ..ServerOptions::default()
+        };
         let service = RaftService::new(opts);
-        let server = Server::new(&address);
+        let server = Server::new_with_options(&address, server_options);
         Server::listen_and_resume(&server).await;
-        server.register_service(svr_id, &service).await;
+        server.register_service(svr_id, &service).await.unwrap();
         (RaftService::start(&service).await, service, server)
     }
     pub async fn probe_and_join(&self, servers: &Vec<String>) -> Result<bool, ExecError> {
@@ -455,28 +519,39 @@ impl RaftService {
     }
     pub async fn join(&self, servers: &Vec<String>) -> Result<bool, ExecError> {
         debug!("Trying to join cluster with id {}", self.id);
-        let client = RaftClient::new(servers, self.options.service_id).await;
+        let client =
+            RaftClient::new_with_pool(servers, self.options.service_id, &self.clients).await;
         if let Ok(client) = client {
             debug!(
                 "Executing in SM to create new member {}, {}",
                 &self.options.address, self.id
             );
-            let result = client
-                .execute(CONFIG_SM_ID, new_member_::new(&self.options.address))
+            let address = &self.options.address;
+            let mut result = client
+                .execute(CONFIG_SM_ID, add_member_::new(&self.id, address))
                 .await;
+            if let Ok(false) = result {
+                debug!(
+                    "Member {} existed, updating its address to {}",
+                    self.id, address
+                );
+                result = client
+                    .execute(CONFIG_SM_ID, update_member_address::new(&self.id, address))
+                    .await;
+            }
             debug!("Getting member address: {}", self.id);
-            let members = client.execute(CONFIG_SM_ID, member_address::new()).await;
+            let members = client.execute(CONFIG_SM_ID, member_list::new()).await;
             debug!("Updating local meta by acquiring lock: {}", self.id);
             let mut meta = self.write_meta().await;
             debug!("Local meta lock acquired: {}", self.id);
             if let Ok(members) = members {
                 debug!("We have following members for {}: {:?}", self.id, members);
-                for member in members {
+                for (id, address) in members {
                     meta.state_machine
                         .write()
                         .await
                         .configs
-                        .new_member(member)
+                        .update_member(id, address)
                         .await;
                 }
             }
@@ -502,9 +577,11 @@ impl RaftService {
             .iter()
             .map(|&(_, ref address)| address.clone())
             .collect();
-        if let Ok(client) = RaftClient::new(&servers, self.options.service_id).await {
+        if let Ok(client) =
+            RaftClient::new_with_pool(&servers, self.options.service_id, &self.clients).await
+        {
             client
-                .execute(CONFIG_SM_ID, del_member_::new(&self.options.address))
+                .execute(CONFIG_SM_ID, remove_member_::new(&self.id))
                 .await
                 .unwrap();
         } else {
@@ -571,10 +648,10 @@ impl RaftService {
     pub fn get_server_id(&self) -> u64 {
         self.id
     }
-    pub async fn register_state_machine(&self, state_machine: SubStateMachine) {
+    pub async fn register_state_machine(&self, state_machine: SubStateMachine) -> RegisterResult {
         let meta = self.meta.read().await;
         let mut master_sm = meta.state_machine.write().await;
-        master_sm.register(state_machine);
+        master_sm.register(state_machine)
     }
     fn switch_membership(&self, meta: &mut RwLockWriteGuard<RaftMeta>, membership: Membership) {
         self.reset_last_checked(meta);
@@ -904,7 +981,7 @@ impl RaftService {
                             member_id
                         );
                         let master_sm = master_sm.read().await;
-                        let snapshot = master_sm.snapshot().unwrap();
+                        let snapshot = master_sm.snapshot(master_sm.codec()).unwrap();
                         rpc.install_snapshot(term, leader_id, last_applied, term, snapshot)
                             .await
                             .unwrap();
@@ -1195,7 +1272,11 @@ impl Service for RaftService {
             if term_ok {
                 check_commit(&mut meta).await;
             }
-            meta.state_machine.write().await.recover(data);
+            {
+                let mut master_sm = meta.state_machine.write().await;
+                let codec = master_sm.codec();
+                master_sm.recover(codec, data).await;
+            }
             meta.term = last_included_term;
             meta.commit_index = last_included_index;
             meta.last_applied = last_included_index;
@@ -1309,19 +1390,19 @@ impl RaftStateMachine {
 
 #[cfg(test)]
 mod test {
-    use crate::raft::state_machine::master::ExecError;
+    use crate::raft::state_machine::master::{ExecError, RegisterResult};
     use crate::raft::state_machine::StateMachineCtl;
-    use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
+    use crate::raft::{Options, RaftService, DEFAULT_SERVICE_ID};
     use crate::rpc::Server;
+    use crate::tcp::{faults, DEFAULT_NETWORK};
     use crate::utils::time::async_wait_secs;
     use futures::FutureExt;
 
     #[tokio::test(threaded_scheduler)]
     async fn startup() {
         let (success, _, _) = RaftService::new_server(Options {
-            storage: Storage::default(),
             address: String::from("127.0.0.1:2000"),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         })
         .await;
         assert!(success);
@@ -1334,16 +1415,16 @@ mod test {
         let s2_addr = String::from("127.0.0.1:2002");
         let s3_addr = String::from("127.0.0.1:2003");
         let service1 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s1_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         info!("Starting server 1");
         let server1 = Server::new(&s1_addr);
         info!("Register raft service for server 1");
         server1
             .register_service(DEFAULT_SERVICE_ID, &service1)
-            .await;
+            .await
+            .unwrap();
         info!("Listening server 1");
         Server::listen_and_resume(&server1).await;
         info!("Start raft service server 1");
@@ -1356,13 +1437,13 @@ mod test {
         let server2 = Server::new(&s2_addr);
         info!("Register raft service for server 2");
         let service2 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s2_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         server2
             .register_service(DEFAULT_SERVICE_ID, &service2)
-            .await;
+            .await
+            .unwrap();
         info!("Listening server 2");
         Server::listen_and_resume(&server2).await;
         info!("Start raft service for server 2");
@@ -1381,16 +1462,16 @@ mod test {
         assert_eq!(service2.num_members().await, 2);
         info!("Starting server 3");
         let service3 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s3_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let server3 = Server::new(&s3_addr);
         Server::listen_and_resume(&server3).await;
         info!("Register raft service for server 3");
         server3
             .register_service(DEFAULT_SERVICE_ID, &service3)
-            .await;
+            .await
+            .unwrap();
         info!("Start raft service for server 3");
         assert!(RaftService::start(&service3).await);
         info!("Server 3 join server 1 and server 2");
@@ -1425,6 +1506,106 @@ mod test {
         assert_eq!(service3.num_members().await, 1);
     }
 
+    #[tokio::test(threaded_scheduler)]
+    async fn node_address_change() {
+        let _ = env_logger::try_init();
+        let s1_addr = String::from("127.0.0.1:2020");
+        let s2_addr = String::from("127.0.0.1:2021");
+        let s2_new_addr = String::from("127.0.0.1:2022");
+        let start = |address: &String, node_id: u64| {
+            let address = address.clone();
+            async move {
+                let (success, service, _server) = RaftService::new_server(Options {
+                    address,
+                    node_id: Some(node_id),
+                    ..Options::default()
+                })
+                .await;
+                assert!(success);
+                service
+            }
+        };
+        let service1 = start(&s1_addr, 1).await;
+        assert_eq!(service1.id, 1);
+        service1.bootstrap().await;
+        let service2 = start(&s2_addr, 2).await;
+        assert!(service2.join(&vec![s1_addr.clone()]).await.unwrap());
+        assert_eq!(service1.num_members().await, 2);
+
+        // The same node coming back on another address keeps its membership
+        let service2 = start(&s2_new_addr, service2.id).await;
+        assert!(service2.join(&vec![s1_addr.clone()]).await.unwrap());
+        async_wait_secs().await;
+        for service in &[&service1, &service2] {
+            let mut members = service.cluster_info().await.members;
+            members.sort();
+            assert_eq!(
+                members,
+                vec![(1, s1_addr.clone()), (2, s2_new_addr.clone())]
+            );
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn leader_partition() {
+        let _ = env_logger::try_init();
+        let addrs: Vec<String> = (2030..2033)
+            .map(|port| format!("127.0.0.1:{}", port))
+            .collect();
+        let mut services = vec![];
+        for address in &addrs {
+            let (success, service, _server) = RaftService::new_server(Options {
+                address: address.clone(),
+                ..Options::default()
+            })
+            .await;
+            assert!(success);
+            services.push(service);
+        }
+        services[0].bootstrap().await;
+        for service in &services[1..] {
+            assert!(service.join(&vec![addrs[0].clone()]).await.unwrap());
+        }
+        async_wait_secs().await;
+        let old_leader = services[0].leader_id().await;
+        let leader_pos = services.iter().position(|s| s.id == old_leader).unwrap();
+        let leader_addr = addrs[leader_pos].as_str();
+        let others: Vec<&str> = addrs
+            .iter()
+            .filter(|a| a.as_str() != leader_addr)
+            .map(|a| a.as_str())
+            .collect();
+
+        // Heartbeats of the leader no longer reach the others, they elect a new one
+        faults::partition(DEFAULT_NETWORK, &[leader_addr], &others);
+        let rest: Vec<_> = services.iter().filter(|s| s.id != old_leader).collect();
+        let mut new_leader = 0;
+        for _ in 0..20 {
+            async_wait_secs().await;
+            let leader = rest[0].leader_id().await;
+            if leader != old_leader && leader != 0 && rest[1].leader_id().await == leader {
+                new_leader = leader;
+                break;
+            }
+        }
+        assert_ne!(new_leader, 0);
+
+        // The old leader follows the new one once the partition heals
+        for other in &others {
+            faults::heal_link(DEFAULT_NETWORK, leader_addr, other);
+            faults::heal_link(DEFAULT_NETWORK, other, leader_addr);
+        }
+        let mut rejoined = false;
+        for _ in 0..10 {
+            async_wait_secs().await;
+            if services[leader_pos].leader_id().await == new_leader {
+                rejoined = true;
+                break;
+            }
+        }
+        assert!(rejoined);
+    }
+
     #[tokio::test(threaded_scheduler)]
     async fn log_replication() {
         let _ = env_logger::try_init();
@@ -1435,29 +1616,24 @@ mod test {
         let s4_addr = String::from("127.0.0.1:2007");
         let s5_addr = String::from("127.0.0.1:2008");
         let service1 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s1_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let service2 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s2_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let service3 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s3_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let service4 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s4_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let service5 = RaftService::new(Options {
-            storage: Storage::default(),
             address: s5_addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let server_list = vec![
             s1_addr.clone(),
@@ -1470,7 +1646,8 @@ mod test {
         info!("Register raft service for server 1");
         server1
             .register_service(DEFAULT_SERVICE_ID, &service1)
-            .await;
+            .await
+            .unwrap();
         info!("Listen server 1");
         Server::listen_and_resume(&server1).await;
         info!("Starting raft service for server 1");
@@ -1485,7 +1662,8 @@ mod test {
         info!("Register raft service for server 2");
         server2
             .register_service(DEFAULT_SERVICE_ID, &service2)
-            .await;
+            .await
+            .unwrap();
         info!("Start raft service for server 2");
         assert!(RaftService::start(&service2).await);
         info!("Server 2 join cluster");
@@ -1497,7 +1675,8 @@ mod test {
         info!("Register raft service for server 3");
         server3
             .register_service(DEFAULT_SERVICE_ID, &service3)
-            .await;
+            .await
+            .unwrap();
         info!("Listening for server 3");
         Server::listen_and_resume(&server3).await;
         info!("Starting raft service for server 3");
@@ -1511,7 +1690,8 @@ mod test {
         info!("Register raft service for server 4");
         server4
             .register_service(DEFAULT_SERVICE_ID, &service4)
-            .await;
+            .await
+            .unwrap();
         info!("Listening for server 4");
         Server::listen_and_resume(&server4).await;
         info!("Starting raft service for server 4");
@@ -1525,7 +1705,8 @@ mod test {
         info!("Register raft service for server 5");
         server5
             .register_service(DEFAULT_SERVICE_ID, &service5)
-            .await;
+            .await
+            .unwrap();
         info!("Listening for server 5");
         Server::listen_and_resume(&server5).await;
         info!("Starting raft service for server 5");
@@ -1557,6 +1738,8 @@ mod test {
     mod state_machine {
         use super::*;
         use crate::raft::client::RaftClient;
+        use crate::tcp::client::ClientOptions;
+        use crate::utils::serde::{Bincode, Codec};
         use crate::utils::time::async_wait;
         use futures::stream::FuturesUnordered;
         use std::sync::Arc;
@@ -1590,10 +1773,10 @@ mod test {
             fn id(&self) -> u64 {
                 15
             }
-            fn snapshot(&self) -> Option<Vec<u8>> {
+            fn snapshot(&self, _: &'static dyn Codec) -> Option<Vec<u8>> {
                 None
             }
-            fn recover(&mut self, _data: Vec<u8>) -> BoxFuture<()> {
+            fn recover(&mut self, _: &'static dyn Codec, _data: Vec<u8>) -> BoxFuture<()> {
                 future::ready(()).boxed()
             }
         }
@@ -1604,19 +1787,29 @@ mod test {
             info!("TESTING CALLBACK");
             let addr = String::from("127.0.0.1:2009");
             let raft_service = RaftService::new(Options {
-                storage: Storage::default(),
                 address: addr.clone(),
-                service_id: DEFAULT_SERVICE_ID,
+                ..Options::default()
             });
             let sm = SM { shots: 10 };
             let server = Server::new(&addr);
             let sm_id = sm.id();
             server
                 .register_service(DEFAULT_SERVICE_ID, &raft_service)
-                .await;
+                .await
+                .unwrap();
             Server::listen_and_resume(&server).await;
             RaftService::start(&raft_service).await;
-            raft_service.register_state_machine(Box::new(sm)).await;
+            assert_eq!(
+                raft_service.register_state_machine(Box::new(sm)).await,
+                RegisterResult::OK
+            );
+            // Does not replace the one above
+            assert_eq!(
+                raft_service
+                    .register_state_machine(Box::new(SM { shots: 0 }))
+                    .await,
+                RegisterResult::EXISTED
+            );
             raft_service.bootstrap().await;
 
             async_wait_secs().await;
@@ -1655,18 +1848,21 @@ mod test {
                     let addr = addr.clone();
                     async move {
                         let raft_service = RaftService::new(Options {
-                            storage: Storage::default(),
                             address: addr.clone(),
-                            service_id: DEFAULT_SERVICE_ID,
+                            ..Options::default()
                         });
                         let sm = SM { shots: 10 };
                         let server = Server::new(&addr);
                         server
                             .register_service(DEFAULT_SERVICE_ID, &raft_service)
-                            .await;
+                            .await
+                            .unwrap();
                         Server::listen_and_resume(&server).await;
                         RaftService::start(&raft_service).await;
-                        raft_service.register_state_machine(Box::new(sm)).await;
+                        assert_eq!(
+                            raft_service.register_state_machine(Box::new(sm)).await,
+                            RegisterResult::OK
+                        );
                         raft_service
                     }
                 })
@@ -1738,5 +1934,66 @@ mod test {
                 );
             }
         }
+
+        #[tokio::test(threaded_scheduler)]
+        async fn non_default_codec() {
+            let _ = env_logger::try_init();
+            let codec: &'static dyn Codec = &Bincode;
+            assert_ne!(codec, crate::utils::serde::default_codec());
+            let addrs: Vec<String> = (2040..2043)
+                .map(|port| format!("127.0.0.1:{}", port))
+                .collect();
+            let mut services = vec![];
+            for address in &addrs {
+                let (success, service, _server) = RaftService::new_server(Options {
+                    address: address.clone(),
+                    codec: Some(codec),
+                    ..Options::default()
+                })
+                .await;
+                assert!(success);
+                assert_eq!(
+                    service
+                        .register_state_machine(Box::new(SM { shots: 10 }))
+                        .await,
+                    RegisterResult::OK
+                );
+                services.push(service);
+            }
+            services[0].bootstrap().await;
+            for service in &services[1..] {
+                assert!(service.join(&vec![addrs[0].clone()]).await.unwrap());
+            }
+            async_wait_secs().await;
+
+            // Calls are encoded with the codec of the client pool, the one of the cluster
+            let client_options = ClientOptions {
+                codec,
+                ..ClientOptions::default()
+            };
+            let raft_client =
+                RaftClient::new_with_options(&addrs, DEFAULT_SERVICE_ID, client_options)
+                    .await
+                    .unwrap();
+            assert_eq!(raft_client.codec(), codec);
+            let sm_client = client::SMClient::new(15, &raft_client);
+            assert_eq!(
+                sm_client
+                    .answer_to_the_universe(&"Bob".to_string())
+                    .await
+                    .unwrap(),
+                "Bob, the answer is 42"
+            );
+            assert_eq!(sm_client.take_a_shot(&3).await.unwrap(), 7);
+            assert_eq!(sm_client.get_shot().await.unwrap(), 7);
+            // Snapshots record their codec
+            let snapshot = {
+                let meta = services[0].read_meta().await;
+                let master_sm = meta.state_machine.read().await;
+                master_sm.snapshot(master_sm.codec()).unwrap()
+            };
+            assert_eq!(&snapshot[..4], b"BFSS");
+            assert_eq!(snapshot[4], codec.id());
+        }
     }
 }
@@ -11,6 +11,7 @@ trait BoxedSubFunc = SubFunc + Send + Sync;
 
 pub struct SubscriptionService {
     pub subs: RwLock<HashMap<SubKey, Vec<(Box<dyn BoxedSubFunc>, u64)>>>,
+    // Advertised address of the local server, dialed by the raft leader for notifications
     pub server_address: String,
     pub session_id: u64,
 }
@@ -47,7 +48,10 @@ impl SubscriptionService {
             server_address: server.address().clone(),
             session_id: get_time() as u64,
         });
-        server.register_service(DEFAULT_SERVICE_ID, &service).await;
+        server
+            .register_service(DEFAULT_SERVICE_ID, &service)
+            .await
+            .unwrap();
         service
     }
 }
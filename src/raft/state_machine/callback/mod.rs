@@ -8,16 +8,18 @@ pub type SubKey = (u64, u64, u64, u64);
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_SM_CALLBACK_DEFAULT_SERVICE) as u64;
 
 service! {
-    rpc notify(key: SubKey, data: Vec<u8>);
+    oneway rpc notify(key: SubKey, data: Vec<u8>);
 }
 
 #[cfg(test)]
 mod test {
     use crate::raft::client::RaftClient;
     use crate::raft::state_machine::callback::server::SMCallback;
+    use crate::raft::state_machine::master::RegisterResult;
     use crate::raft::state_machine::StateMachineCtl;
-    use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
+    use crate::raft::{Options, RaftService, DEFAULT_SERVICE_ID};
     use crate::rpc::Server;
+    use crate::utils::serde::Codec;
     use crate::utils::time::async_wait_secs;
     use future::FutureExt;
     use std::sync::atomic::*;
@@ -51,10 +53,10 @@ mod test {
         fn id(&self) -> u64 {
             10
         }
-        fn snapshot(&self) -> Option<Vec<u8>> {
+        fn snapshot(&self, _: &'static dyn Codec) -> Option<Vec<u8>> {
             None
         }
-        fn recover(&mut self, _: Vec<u8>) -> BoxFuture<()> {
+        fn recover(&mut self, _: &'static dyn Codec, _: Vec<u8>) -> BoxFuture<()> {
             future::ready(()).boxed()
         }
     }
@@ -65,9 +67,8 @@ mod test {
         info!("TESTING CALLBACK");
         let addr = String::from("127.0.0.1:2110");
         let raft_service = RaftService::new(Options {
-            storage: Storage::default(),
             address: addr.clone(),
-            service_id: DEFAULT_SERVICE_ID,
+            ..Options::default()
         });
         let server = Server::new(&addr);
         let dummy_sm = Trigger {
@@ -77,12 +78,16 @@ mod test {
         let sm_id = dummy_sm.id();
         server
             .register_service(DEFAULT_SERVICE_ID, &raft_service)
-            .await;
+            .await
+            .unwrap();
         Server::listen_and_resume(&server).await;
         RaftService::start(&raft_service).await;
-        raft_service
-            .register_state_machine(Box::new(dummy_sm))
-            .await;
+        assert_eq!(
+            raft_service
+                .register_state_machine(Box::new(dummy_sm))
+                .await,
+            RegisterResult::OK
+        );
         raft_service.bootstrap().await;
 
         async_wait_secs().await;
@@ -162,7 +162,7 @@ impl SMCallback {
     ) -> Result<(usize, Vec<NotifyError>, Vec<Result<(), rpc::RPCError>>), NotifyError>
     where
         R: serde::Serialize + Send + Sync + Clone + Any + Unpin + 'static,
-        M: RaftMsg<R> + 'static,
+        M: RaftMsg<R>,
     {
         if !self.raft_service.is_leader() {
             debug!(
@@ -171,7 +171,9 @@ impl SMCallback {
             );
             return Err(NotifyError::IsNotLeader);
         }
-        let (fn_id, op_type, pattern_data) = msg.encode();
+        // Patterns and messages are encoded like the calls of subscribers
+        let codec = self.raft_service.options.codec();
+        let (fn_id, op_type, pattern_data) = msg.encode(codec);
         return match op_type {
             OpType::SUBSCRIBE => {
                 let pattern_id = hash_bytes(&pattern_data.as_slice());
@@ -202,7 +204,7 @@ impl SMCallback {
                                     if let Some(subscriber) =
                                         svr_subs.subscribers.get(&subscriber_id)
                                     {
-                                        let data = crate::utils::serde::serialize(&*message);
+                                        let data = codec.serialize(&*message);
                                         let client = &subscriber.client;
                                         debug!(
                                             "Sending out callback notification to sub id {}",
@@ -245,7 +247,7 @@ impl SMCallback {
         F: Fn(&R) + Sync + Send + 'static,
         R: 'static,
     {
-        let (_, op_type, pattern_data) = msg.encode();
+        let (_, op_type, pattern_data) = msg.encode(self.raft_service.options.codec());
         match op_type {
             OpType::SUBSCRIBE => {
                 let pattern_id = hash_bytes(&pattern_data.as_slice());
@@ -269,7 +271,7 @@ impl SMCallback {
 pub async fn notify<M, R, F>(callback: &Option<SMCallback>, msg: M, data: F)
 where
     F: FnOnce() -> R,
-    M: RaftMsg<R> + Send + 'static,
+    M: RaftMsg<R>,
     R: serde::Serialize + Send + Sync + Clone + Unpin + Any + 'static,
 {
     if let Some(ref callback) = *callback {
@@ -3,6 +3,7 @@ use crate::raft::state_machine::callback::SubKey;
 use crate::raft::state_machine::StateMachineCtl;
 use crate::raft::AsyncServiceClient;
 use crate::rpc;
+use crate::utils::serde::Codec;
 use async_std::sync::*;
 use bifrost_hasher::hash_str;
 use futures::FutureExt;
@@ -23,20 +24,38 @@ pub struct Configures {
     // keep it in arc lock for reference in callback server.rs
     pub subscriptions: Arc<RwLock<Subscriptions>>,
     service_id: u64,
+    // Connections to other members, made on behalf of the local node
+    clients: Arc<rpc::ClientPool>,
 }
 
-pub type MemberConfigSnapshot = HashSet<String>;
+// Member id to advertised address
+pub type MemberConfigSnapshot = HashMap<u64, String>;
+
+const SNAPSHOT_VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSnapshot {
+    version: u32,
     members: MemberConfigSnapshot,
     //TODO: snapshot for subscriptions
 }
 
+// Snapshots taken before node ids only have the member addresses, without a version
+#[derive(Deserialize, Debug)]
+struct LegacyConfigSnapshot {
+    members: HashSet<String>,
+}
+
 raft_state_machine! {
+    // Members identified by the hash of their address, kept to replay logs from before
+    // node ids
     def cmd new_member_(address: String) -> bool;
     def cmd del_member_(address: String);
+    def cmd add_member_(id: u64, address: String) -> bool;
+    def cmd remove_member_(id: u64);
+    def cmd update_member_address(id: u64, address: String) -> bool;
     def qry member_address() -> Vec<String>;
+    def qry member_list() -> Vec<(u64, String)>;
 
     def cmd subscribe(key: SubKey, address: String, session_id: u64) -> Result<u64, ()>;
     def cmd unsubscribe(sub_id: u64);
@@ -44,37 +63,58 @@ raft_state_machine! {
 
 impl StateMachineCmds for Configures {
     fn new_member_(&mut self, address: String) -> BoxFuture<bool> {
+        self.add_member_(hash_str(&address), address)
+    }
+    fn del_member_(&mut self, address: String) -> BoxFuture<()> {
+        self.remove_member_(hash_str(&address))
+    }
+    fn add_member_(&mut self, id: u64, address: String) -> BoxFuture<bool> {
         async move {
-            let addr = address.clone();
-            let id = hash_str(&addr);
             if !self.members.contains_key(&id) {
-                match rpc::DEFAULT_CLIENT_POOL.get(&address).await {
-                    Ok(client) => {
-                        self.members.insert(
-                            id,
-                            RaftMember {
-                                rpc: AsyncServiceClient::new(self.service_id, &client),
-                                address,
-                                id,
-                            },
-                        );
+                match self.connect_member(id, address).await {
+                    Some(member) => {
+                        self.members.insert(id, member);
                         return true;
                     }
-                    Err(_) => {}
+                    None => {}
                 }
             }
             false
         }
         .boxed()
     }
-    fn del_member_(&mut self, address: String) -> BoxFuture<()> {
-        let hash = hash_str(&address);
-        self.members.remove(&hash);
+    fn remove_member_(&mut self, id: u64) -> BoxFuture<()> {
+        self.members.remove(&id);
         future::ready(()).boxed()
     }
+    fn update_member_address(&mut self, id: u64, address: String) -> BoxFuture<bool> {
+        async move {
+            match self.members.get(&id) {
+                Some(member) if member.address != address => {}
+                _ => return false,
+            }
+            match self.connect_member(id, address).await {
+                Some(member) => {
+                    self.members.insert(id, member);
+                    true
+                }
+                None => false,
+            }
+        }
+        .boxed()
+    }
     fn member_address(&self) -> BoxFuture<Vec<String>> {
         future::ready(self.members.values().map(|m| m.address.clone()).collect()).boxed()
     }
+    fn member_list(&self) -> BoxFuture<Vec<(u64, String)>> {
+        future::ready(
+            self.members
+                .values()
+                .map(|m| (m.id, m.address.clone()))
+                .collect(),
+        )
+        .boxed()
+    }
     fn subscribe(
         &mut self,
         key: SubKey,
@@ -101,48 +141,78 @@ impl StateMachineCtl for Configures {
     fn id(&self) -> u64 {
         CONFIG_SM_ID
     }
-    fn snapshot(&self) -> Option<Vec<u8>> {
-        let mut snapshot = ConfigSnapshot {
-            members: HashSet::with_capacity(self.members.len()),
+    fn snapshot(&self, codec: &'static dyn Codec) -> Option<Vec<u8>> {
+        let snapshot = ConfigSnapshot {
+            version: SNAPSHOT_VERSION,
+            members: self.member_snapshot(),
         };
-        for (_, member) in self.members.iter() {
-            snapshot.members.insert(member.address.clone());
-        }
-        Some(crate::utils::serde::serialize(&snapshot))
+        Some(codec.serialize(&snapshot))
     }
-    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
-        let snapshot: ConfigSnapshot = crate::utils::serde::deserialize(&data).unwrap();
-        self.recover_members(snapshot.members).boxed()
+    fn recover(&mut self, codec: &'static dyn Codec, data: Vec<u8>) -> BoxFuture<()> {
+        let members = match codec.deserialize::<ConfigSnapshot>(&data) {
+            Some(snapshot) => snapshot.members,
+            None => {
+                let snapshot: LegacyConfigSnapshot = codec.deserialize(&data).unwrap();
+                snapshot
+                    .members
+                    .into_iter()
+                    .map(|address| (hash_str(&address), address))
+                    .collect()
+            }
+        };
+        self.recover_members(members).boxed()
     }
 }
 
 impl Configures {
-    pub fn new(service_id: u64) -> Configures {
+    pub fn new(service_id: u64, clients: &Arc<rpc::ClientPool>) -> Configures {
         Configures {
             members: HashMap::new(),
             service_id,
             subscriptions: Arc::new(RwLock::new(Subscriptions::new())),
+            clients: clients.clone(),
         }
     }
-    async fn recover_members(&mut self, snapshot: MemberConfigSnapshot) {
-        let mut curr_members: MemberConfigSnapshot = HashSet::with_capacity(self.members.len());
-        for (_, member) in self.members.iter() {
-            curr_members.insert(member.address.clone());
+    async fn connect_member(&self, id: u64, address: String) -> Option<RaftMember> {
+        match self.clients.get(&address).await {
+            Ok(client) => Some(RaftMember {
+                rpc: AsyncServiceClient::new(self.service_id, &client),
+                address,
+                id,
+            }),
+            Err(_) => None,
         }
-        let to_del = curr_members.difference(&snapshot);
-        let to_add = snapshot.difference(&curr_members);
-        for addr in to_del {
-            self.del_member(addr.clone()).await;
+    }
+    fn member_snapshot(&self) -> MemberConfigSnapshot {
+        self.members
+            .iter()
+            .map(|(id, member)| (*id, member.address.clone()))
+            .collect()
+    }
+    async fn recover_members(&mut self, snapshot: MemberConfigSnapshot) {
+        let curr_ids: Vec<u64> = self.members.keys().cloned().collect();
+        for id in curr_ids {
+            if !snapshot.contains_key(&id) {
+                self.del_member(id).await;
+            }
         }
-        for addr in to_add {
-            self.new_member(addr.clone()).await;
+        for (id, address) in snapshot {
+            self.update_member(id, address).await;
         }
     }
-    pub async fn new_member(&mut self, address: String) -> bool {
-        self.new_member_(address).await
+    pub async fn new_member(&mut self, id: u64, address: String) -> bool {
+        self.add_member_(id, address).await
+    }
+    // Add the member, or follow it to its new address if it is already known
+    pub async fn update_member(&mut self, id: u64, address: String) -> bool {
+        match self.members.get(&id) {
+            Some(member) if member.address == address => true,
+            Some(_) => self.update_member_address(id, address).await,
+            None => self.add_member_(id, address).await,
+        }
     }
-    pub async fn del_member(&mut self, address: String) {
-        self.del_member_(address).await
+    pub async fn del_member(&mut self, id: u64) {
+        self.remove_member_(id).await
     }
     pub fn member_existed(&self, id: u64) -> bool {
         self.members.contains_key(&id)
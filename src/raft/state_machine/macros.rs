@@ -23,7 +23,7 @@ macro_rules! raft_client_fn {
                 self.sm_id,
                 $fn_name::new($($arg,)*),
                 f
-            ).boxed()
+            )
         }
     };
     ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty) => {
@@ -51,28 +51,28 @@ macro_rules! raft_fn_op_type {
 
 #[macro_export]
 macro_rules! raft_dispatch_fn {
-    ($fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {{
-        let decoded: ($($in_,)*) = $crate::utils::serde::deserialize($d).unwrap();
+    ($fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {{
+        let decoded: ($($in_,)*) = $c.deserialize($d).unwrap();
         let ($($arg,)*) = decoded;
         let f_result = $s.$fn_name($($arg),*).await;
-        Some($crate::utils::serde::serialize(&f_result))
+        Some($c.serialize(&f_result))
     }};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_cmd {
-    (cmd $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (cmd $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
+        raft_dispatch_fn!($fn_name $s $c $d( $( $arg : $in_ ),* ))
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
+    ($others:ident $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_qry {
-    (qry $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (qry $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
+        raft_dispatch_fn!($fn_name $s $c $d( $( $arg : $in_ ),* ))
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
+    ($others:ident $fn_name:ident $s: ident $c: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
@@ -80,17 +80,19 @@ macro_rules! raft_sm_complete {
     () => {
         fn fn_dispatch_cmd<'a>(
             &'a mut self,
+            codec: &'static dyn $crate::utils::serde::Codec,
             fn_id: u64,
             data: &'a Vec<u8>,
         ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>> {
-            self.dispatch_cmd_(fn_id, data)
+            self.dispatch_cmd_(codec, fn_id, data)
         }
         fn fn_dispatch_qry<'a>(
             &'a self,
+            codec: &'static dyn $crate::utils::serde::Codec,
             fn_id: u64,
             data: &'a Vec<u8>,
         ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>> {
-            self.dispatch_qry_(fn_id, data)
+            self.dispatch_qry_(codec, fn_id, data)
         }
         fn op_type(&mut self, fn_id: u64) -> Option<$crate::raft::state_machine::OpType> {
             self.op_type_(fn_id)
@@ -156,39 +158,42 @@ macro_rules! raft_state_machine {
             def $smt:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
         )*
     ) => {
+        ::bifrost_plugins::check_fn_ids!($($fn_name,)*);
+
         #[allow(unused_imports)]
         use futures::prelude::*;
         use futures::future::BoxFuture;
 
+        // Arguments are only encoded once the codec of the raft service is known
         #[allow(dead_code)]
         #[allow(unused_imports)]
         pub mod commands {
             use super::*;
             use futures::prelude::*;
-            use serde::{Serialize, Deserialize};
+            use $crate::utils::serde::Codec;
             $(
-                #[derive(Serialize, Deserialize, Debug)]
                 #[allow(non_camel_case_types)]
-                pub struct $fn_name {
-                    pub data: Vec<u8>
+                pub struct $fn_name<'a> {
+                    args: ($(&'a $in_,)*),
+                    _args: ::std::marker::PhantomData<&'a ()>,
                 }
-                impl $crate::raft::RaftMsg<$out> for $fn_name {
-                    fn encode(self) -> (u64, $crate::raft::state_machine::OpType, Vec<u8>) {
+                impl<'a> $crate::raft::RaftMsg<$out> for $fn_name<'a> {
+                    fn encode(self, codec: &'static dyn Codec) -> (u64, $crate::raft::state_machine::OpType, Vec<u8>) {
                         (
                             ::bifrost_plugins::hash_ident!($fn_name) as u64,
                             raft_fn_op_type!($smt),
-                            self.data
+                            codec.serialize(&self.args)
                         )
                     }
-                    fn decode_return(data: &Vec<u8>) -> $out {
-                        $crate::utils::serde::deserialize(data).unwrap()
+                    fn decode_return(codec: &'static dyn Codec, data: &Vec<u8>) -> $out {
+                        codec.deserialize(data).unwrap()
                     }
                 }
-                impl $fn_name {
-                    pub fn new($($arg:&$in_),*) -> $fn_name {
-                        let req_data = ($($arg,)*);
+                impl<'a> $fn_name<'a> {
+                    pub fn new($($arg: &'a $in_),*) -> Self {
                         $fn_name {
-                            data: $crate::utils::serde::serialize(&req_data)
+                            args: ($($arg,)*),
+                            _args: ::std::marker::PhantomData,
                         }
                     }
                 }
@@ -213,11 +218,11 @@ macro_rules! raft_state_machine {
                    }
                 }
            }
-           fn dispatch_cmd_<'a>(&'a mut self, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+           fn dispatch_cmd_<'a>(&'a mut self, codec: &'static dyn $crate::utils::serde::Codec, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
                async move {
                     match fn_id as usize {
                         $(::bifrost_plugins::hash_ident!($fn_name) => {
-                            raft_dispatch_cmd!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                            raft_dispatch_cmd!($smt $fn_name self codec data( $( $arg : $in_ ),* ))
                         }),*
                         _ => {
                             debug!("Undefined function id: {}. We have {}", fn_id, concat!(stringify!($($fn_name),*)));
@@ -226,11 +231,11 @@ macro_rules! raft_state_machine {
                     }
                }.boxed()
            }
-           fn dispatch_qry_<'a>(&'a self, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+           fn dispatch_qry_<'a>(&'a self, codec: &'static dyn $crate::utils::serde::Codec, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
                async move {
                     match fn_id as usize {
                         $(::bifrost_plugins::hash_ident!($fn_name) => {
-                            raft_dispatch_qry!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                            raft_dispatch_qry!($smt $fn_name self codec data( $( $arg : $in_ ),* ))
                         }),*
                         _ => {
                             debug!("Undefined function id: {}", fn_id);
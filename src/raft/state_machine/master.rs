@@ -1,6 +1,7 @@
 use self::configs::{Configures, RaftMember, CONFIG_SM_ID};
 use super::super::*;
 use super::*;
+use crate::utils::serde::{codec_by_id, Codec, LEGACY_CODEC};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
@@ -18,6 +19,8 @@ pub enum ExecError {
     TooManyRetry,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
 pub enum RegisterResult {
     OK,
     EXISTED,
@@ -30,12 +33,20 @@ pub type SubStateMachine = Box<dyn StateMachineCtl>;
 pub type SnapshotDataItem = (u64, Vec<u8>);
 pub type SnapshotDataItems = Vec<SnapshotDataItem>;
 
+// Snapshots start with the magic and the id of the codec they are encoded with, like raft
+// log files. Snapshots taken before the header existed start right with the items.
+const SNAPSHOT_MAGIC: [u8; 4] = *b"BFSS";
+const SNAPSHOT_HEADER_LEN: usize = 5;
+
 raft_state_machine! {}
 
 pub struct MasterStateMachine {
     subs: HashMap<u64, SubStateMachine>,
-    snapshots: HashMap<u64, Vec<u8>>,
+    // Snapshots of state machines not registered yet, with their codec
+    snapshots: HashMap<u64, (&'static dyn Codec, Vec<u8>)>,
     pub configs: Configures,
+    // Of the raft service, for the calls to the state machines
+    codec: &'static dyn Codec,
 }
 
 impl StateMachineCmds for MasterStateMachine {}
@@ -45,27 +56,52 @@ impl StateMachineCtl for MasterStateMachine {
     fn id(&self) -> u64 {
         0
     }
-    fn snapshot(&self) -> Option<Vec<u8>> {
+    fn snapshot(&self, codec: &'static dyn Codec) -> Option<Vec<u8>> {
         let mut sms: SnapshotDataItems = Vec::with_capacity(self.subs.len());
         for (sm_id, smc) in self.subs.iter() {
-            let sub_snapshot = smc.snapshot();
+            let sub_snapshot = smc.snapshot(codec);
             if let Some(snapshot) = sub_snapshot {
                 sms.push((*sm_id, snapshot));
             }
         }
-        sms.push((self.configs.id(), self.configs.snapshot().unwrap()));
-        let data = crate::utils::serde::serialize(&sms);
+        sms.push((self.configs.id(), self.configs.snapshot(codec).unwrap()));
+        let mut data = Vec::with_capacity(SNAPSHOT_HEADER_LEN);
+        data.extend_from_slice(&SNAPSHOT_MAGIC);
+        data.push(codec.id());
+        data.extend(codec.serialize(&sms));
         Some(data)
     }
-    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
-        let sms: SnapshotDataItems = crate::utils::serde::deserialize(data.as_slice()).unwrap();
+    // The codec recorded in the snapshot wins over the given one
+    fn recover(&mut self, codec: &'static dyn Codec, data: Vec<u8>) -> BoxFuture<()> {
+        let (codec, items) = match snapshot_codec(&data) {
+            Some(recorded) => {
+                if recorded != codec {
+                    warn!(
+                        "Snapshot is encoded with {}, not {}",
+                        recorded.name(),
+                        codec.name()
+                    );
+                }
+                (recorded, &data[SNAPSHOT_HEADER_LEN..])
+            }
+            None => (LEGACY_CODEC, &data[..]),
+        };
+        let sms: SnapshotDataItems = codec.deserialize(items).unwrap();
         for (sm_id, snapshot) in sms {
-            self.snapshots.insert(sm_id, snapshot);
+            self.snapshots.insert(sm_id, (codec, snapshot));
         }
         future::ready(()).boxed()
     }
 }
 
+fn snapshot_codec(data: &[u8]) -> Option<&'static dyn Codec> {
+    if data.len() >= SNAPSHOT_HEADER_LEN && data[..4] == SNAPSHOT_MAGIC {
+        codec_by_id(data[4])
+    } else {
+        None
+    }
+}
+
 fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
     if let Some(d) = r {
         Ok(d)
@@ -75,25 +111,37 @@ fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
 }
 
 impl MasterStateMachine {
-    pub fn new(service_id: u64) -> MasterStateMachine {
+    pub fn new(
+        service_id: u64,
+        clients: &Arc<ClientPool>,
+        codec: &'static dyn Codec,
+    ) -> MasterStateMachine {
         let msm = MasterStateMachine {
             subs: HashMap::new(),
             snapshots: HashMap::new(),
-            configs: Configures::new(service_id),
+            configs: Configures::new(service_id, clients),
+            codec,
         };
         msm
     }
 
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.codec
+    }
+
     pub fn register(&mut self, mut smc: SubStateMachine) -> RegisterResult {
         let id = smc.id();
         if id < 2 {
+            warn!("State machine id {} is reserved, not registering it", id);
             return RegisterResult::RESERVED;
         }
+        // Ids are often hashes of names, another state machine may have the same one
         if self.subs.contains_key(&id) {
+            warn!("State machine id {} is taken, not registering it", id);
             return RegisterResult::EXISTED;
         };
-        if let Some(snapshot) = self.snapshots.remove(&id) {
-            smc.recover(snapshot);
+        if let Some((codec, snapshot)) = self.snapshots.remove(&id) {
+            smc.recover(codec, snapshot);
         }
         self.subs.insert(id, smc);
         RegisterResult::OK
@@ -106,11 +154,17 @@ impl MasterStateMachine {
     pub async fn commit_cmd(&mut self, entry: &LogEntry) -> ExecResult {
         match entry.sm_id {
             CONFIG_SM_ID => {
-                parse_output(self.configs.fn_dispatch_cmd(entry.fn_id, &entry.data).await)
+                let res = self
+                    .configs
+                    .fn_dispatch_cmd(self.codec, entry.fn_id, &entry.data);
+                parse_output(res.await)
             }
             _ => {
                 if let Some(sm) = self.subs.get_mut(&entry.sm_id) {
-                    parse_output(sm.as_mut().fn_dispatch_cmd(entry.fn_id, &entry.data).await)
+                    let res = sm
+                        .as_mut()
+                        .fn_dispatch_cmd(self.codec, entry.fn_id, &entry.data);
+                    parse_output(res.await)
                 } else {
                     debug!(
                         "Cannot find state machine {} for command, we have {:?}",
@@ -125,11 +179,15 @@ impl MasterStateMachine {
     pub async fn exec_qry(&self, entry: &LogEntry) -> ExecResult {
         match entry.sm_id {
             CONFIG_SM_ID => {
-                parse_output(self.configs.fn_dispatch_qry(entry.fn_id, &entry.data).await)
+                let res = self
+                    .configs
+                    .fn_dispatch_qry(self.codec, entry.fn_id, &entry.data);
+                parse_output(res.await)
             }
             _ => {
                 if let Some(sm) = self.subs.get(&entry.sm_id) {
-                    parse_output(sm.fn_dispatch_qry(entry.fn_id, &entry.data).await)
+                    let res = sm.fn_dispatch_qry(self.codec, entry.fn_id, &entry.data);
+                    parse_output(res.await)
                 } else {
                     debug!(
                         "Cannot find state machine {} for query, we have {:?}",
@@ -152,6 +210,14 @@ impl MasterStateMachine {
 impl Error for ExecError {}
 impl Display for ExecError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            ExecError::SmNotFound => write!(f, "state machine not found"),
+            ExecError::FnNotFound => write!(f, "state machine function not found"),
+            ExecError::ServersUnreachable => write!(f, "raft servers unreachable"),
+            ExecError::CannotConstructClient => write!(f, "cannot construct raft client"),
+            ExecError::NotCommitted => write!(f, "command not committed"),
+            ExecError::Unknown => write!(f, "unknown error"),
+            ExecError::TooManyRetry => write!(f, "too many retries"),
+        }
     }
 }
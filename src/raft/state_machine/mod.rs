@@ -1,4 +1,5 @@
 use crate::raft::client::RaftClient;
+use crate::utils::serde::Codec;
 use std::any::Any;
 use std::sync::Arc;
 
@@ -14,17 +15,25 @@ pub enum OpType {
     SUBSCRIBE,
 }
 
+// Snapshots, arguments and results are encoded with the codec given by the raft service,
+// the same on every member
 pub trait StateMachineCtl: Sync + Send + Any {
     fn id(&self) -> u64;
-    fn snapshot(&self) -> Option<Vec<u8>>;
-    fn recover(&mut self, data: Vec<u8>) -> ::futures::future::BoxFuture<()>;
+    fn snapshot(&self, codec: &'static dyn Codec) -> Option<Vec<u8>>;
+    fn recover(
+        &mut self,
+        codec: &'static dyn Codec,
+        data: Vec<u8>,
+    ) -> ::futures::future::BoxFuture<()>;
     fn fn_dispatch_qry<'a>(
         &'a self,
+        codec: &'static dyn Codec,
         fn_id: u64,
         data: &'a Vec<u8>,
     ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>>;
     fn fn_dispatch_cmd<'a>(
         &'a mut self,
+        codec: &'static dyn Codec,
         fn_id: u64,
         data: &'a Vec<u8>,
     ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>>;
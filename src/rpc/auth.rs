@@ -0,0 +1,214 @@
+// Shared-secret authentication of requests. Clients holding the cluster secret wrap every
+// request into an envelope signed with HMAC-SHA256, servers holding it refuse requests
+// that are not signed with the same secret.
+//
+// Envelope: [AUTH_SERVICE_ID u64][timestamp ms i64][nonce u64][mac 32 bytes][service id][fn id][args]
+// The mac covers timestamp, nonce and everything after the mac.
+use super::{RPCRequestError, RemoteError};
+use crate::utils::time::get_time;
+use bytes::{Buf, BufMut, BytesMut};
+use hmac::{Hmac, Mac, NewMac};
+use parking_lot::{Mutex as SyncMutex, RwLock};
+use sha2::Sha256;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::Arc;
+
+// Reserved service id marking a signed request, old servers answer it as unknown service
+pub const AUTH_SERVICE_ID: u64 = ::std::u64::MAX - 1;
+// Signed requests with timestamps further away from the server clock are refused.
+// Nonces are only remembered for this long.
+pub const MAX_CLOCK_SKEW_MS: i64 = 30_000;
+
+const MAC_LEN: usize = 32;
+const HEADER_LEN: usize = 8 + 8 + MAC_LEN;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub type Secret = Arc<Vec<u8>>;
+
+lazy_static! {
+    static ref CLUSTER_SECRET: RwLock<Option<Secret>> = RwLock::new(None);
+}
+
+pub fn secret(key: &[u8]) -> Secret {
+    Arc::new(key.to_vec())
+}
+
+// Secret of server and client options created from now on by default, including the
+// ones of raft, membership and the default client pool. Set it before starting them.
+pub fn set_cluster_secret(key: Option<&[u8]>) {
+    *CLUSTER_SECRET.write() = key.map(secret);
+}
+
+pub fn cluster_secret() -> Option<Secret> {
+    CLUSTER_SECRET.read().clone()
+}
+
+fn mac(secret: &Secret, timestamp: i64, nonce: u64, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret).expect("HMAC takes keys of any size");
+    mac.update(&timestamp.to_le_bytes());
+    mac.update(&nonce.to_le_bytes());
+    mac.update(payload);
+    mac
+}
+
+// Wrap a request payload, which starts with the service id, into a signed envelope
+pub fn sign(secret: &Secret, payload: BytesMut) -> BytesMut {
+    let timestamp = get_time();
+    let nonce = rand::random::<u64>();
+    let tag = mac(secret, timestamp, nonce, &payload)
+        .finalize()
+        .into_bytes();
+    let mut bytes = BytesMut::with_capacity(8 + HEADER_LEN + payload.len());
+    bytes.put_u64_le(AUTH_SERVICE_ID);
+    bytes.put_i64_le(timestamp);
+    bytes.put_u64_le(nonce);
+    bytes.put_slice(&tag);
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+fn unauthenticated(message: &str) -> RemoteError {
+    RemoteError::new(RPCRequestError::Unauthenticated, message)
+}
+
+// Take the payload out of an envelope without checking it, for servers without a secret
+pub fn unwrap(mut envelope: BytesMut) -> Result<BytesMut, RemoteError> {
+    if envelope.len() < HEADER_LEN {
+        return Err(RemoteError::new(
+            RPCRequestError::BadRequest,
+            "truncated auth envelope",
+        ));
+    }
+    envelope.advance(HEADER_LEN);
+    Ok(envelope)
+}
+
+// Nonces of accepted requests, to refuse replays. Kept until their requests would be
+// refused as expired anyway.
+#[derive(Default)]
+struct Nonces {
+    seen: HashSet<u64>,
+    // Soonest expiry first
+    expiry: BinaryHeap<Reverse<(i64, u64)>>,
+}
+
+impl Nonces {
+    fn expire(&mut self, now: i64) {
+        while let Some(&Reverse((expires, nonce))) = self.expiry.peek() {
+            if expires >= now {
+                break;
+            }
+            self.expiry.pop();
+            self.seen.remove(&nonce);
+        }
+    }
+
+    // False if the nonce is already known
+    fn insert(&mut self, nonce: u64, expires: i64) -> bool {
+        if !self.seen.insert(nonce) {
+            return false;
+        }
+        self.expiry.push(Reverse((expires, nonce)));
+        true
+    }
+}
+
+pub struct Verifier {
+    secret: Secret,
+    nonces: SyncMutex<Nonces>,
+}
+
+impl Verifier {
+    pub fn new(secret: Secret) -> Self {
+        Self {
+            secret,
+            nonces: SyncMutex::new(Nonces::default()),
+        }
+    }
+
+    // Check an envelope, after its AUTH_SERVICE_ID, and return the payload it carries
+    pub fn verify(&self, mut envelope: BytesMut) -> Result<BytesMut, RemoteError> {
+        if envelope.len() < HEADER_LEN {
+            return Err(unauthenticated("truncated auth envelope"));
+        }
+        let timestamp = envelope.get_i64_le();
+        let nonce = envelope.get_u64_le();
+        let tag = envelope.split_to(MAC_LEN);
+        mac(&self.secret, timestamp, nonce, &envelope)
+            .verify(&tag)
+            .map_err(|_| unauthenticated("bad signature"))?;
+        let now = get_time();
+        if (now - timestamp).abs() > MAX_CLOCK_SKEW_MS {
+            return Err(unauthenticated("request expired"));
+        }
+        let mut nonces = self.nonces.lock();
+        nonces.expire(now);
+        if !nonces.insert(nonce, timestamp + MAX_CLOCK_SKEW_MS) {
+            return Err(unauthenticated("request replayed"));
+        }
+        Ok(envelope)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload() -> BytesMut {
+        let mut payload = BytesMut::new();
+        payload.put_u64_le(1);
+        payload.put_u64_le(2);
+        payload.put_slice(b"args");
+        payload
+    }
+
+    fn verify(verifier: &Verifier, mut envelope: BytesMut) -> Result<BytesMut, RemoteError> {
+        assert_eq!(envelope.get_u64_le(), AUTH_SERVICE_ID);
+        verifier.verify(envelope)
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let verifier = Verifier::new(secret(b"secret"));
+        let envelope = sign(&secret(b"secret"), payload());
+        assert_eq!(verify(&verifier, envelope.clone()).unwrap(), payload());
+        // Same envelope again
+        let replayed = verify(&verifier, envelope.clone()).unwrap_err();
+        assert_eq!(replayed.kind, RPCRequestError::Unauthenticated);
+        assert_eq!(replayed.message, "request replayed");
+        // Payload changed on the way
+        let mut tampered = envelope.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(
+            verify(&verifier, tampered).unwrap_err().message,
+            "bad signature"
+        );
+        // Signed with another secret
+        let forged = sign(&secret(b"guess"), payload());
+        assert_eq!(
+            verify(&verifier, forged).unwrap_err().message,
+            "bad signature"
+        );
+        let mut unwrapped = envelope;
+        unwrapped.advance(8);
+        assert_eq!(unwrap(unwrapped).unwrap(), payload());
+    }
+
+    #[test]
+    fn expire_nonces() {
+        let mut nonces = Nonces::default();
+        assert!(nonces.insert(1, 200));
+        assert!(nonces.insert(2, 100));
+        assert!(!nonces.insert(1, 300));
+        nonces.expire(100);
+        assert!(!nonces.insert(2, 300));
+        // Only the expired one is forgotten
+        nonces.expire(150);
+        assert!(!nonces.insert(1, 300));
+        assert!(nonces.insert(2, 300));
+        assert_eq!(nonces.expiry.len(), 2);
+    }
+}
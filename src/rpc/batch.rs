@@ -0,0 +1,124 @@
+// Batched calls. Several calls to the same server travel in one request to
+// `BATCH_SERVICE_ID`, the server dispatches them concurrently and answers all their
+// results in one response, in the order of the calls.
+//
+// Request: [count u32] then for each call [length u32][service id u64][fn id][args]
+// Response: [count u32] then for each call [length u32][encoded result]
+use super::{RPCRequestError, RemoteError};
+use bytes::{Buf, BufMut, BytesMut};
+
+// Reserved service id for batches, next to `streaming::STREAM_SERVICE_ID`.
+// Old servers answer it as unknown service.
+pub const BATCH_SERVICE_ID: u64 = ::std::u64::MAX - 3;
+// Servers refuse larger batches, which would run more calls at once than a connection
+// dispatches requests by default. Clients split them.
+pub const MAX_BATCH_CALLS: usize = 128;
+
+fn encode(parts: &[BytesMut]) -> BytesMut {
+    let len = parts.iter().map(|part| 4 + part.len()).sum::<usize>();
+    let mut bytes = BytesMut::with_capacity(4 + len);
+    bytes.put_u32_le(parts.len() as u32);
+    for part in parts {
+        bytes.put_u32_le(part.len() as u32);
+        bytes.extend_from_slice(part);
+    }
+    bytes
+}
+
+fn decode(mut bytes: BytesMut) -> Option<Vec<BytesMut>> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let count = bytes.get_u32_le() as usize;
+    // Every part takes at least its length
+    if count > bytes.len() / 4 {
+        return None;
+    }
+    let mut parts = Vec::with_capacity(count);
+    for _ in 0..count {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let len = bytes.get_u32_le() as usize;
+        if len > bytes.len() {
+            return None;
+        }
+        parts.push(bytes.split_to(len));
+    }
+    if bytes.is_empty() {
+        Some(parts)
+    } else {
+        None
+    }
+}
+
+// Calls are service ids with data starting with the function id
+pub(crate) fn encode_calls(calls: &[(u64, BytesMut)]) -> BytesMut {
+    let len = calls.iter().map(|(_, data)| 12 + data.len()).sum::<usize>();
+    let mut bytes = BytesMut::with_capacity(4 + len);
+    bytes.put_u32_le(calls.len() as u32);
+    for (service_id, data) in calls {
+        bytes.put_u32_le(8 + data.len() as u32);
+        bytes.put_u64_le(*service_id);
+        bytes.extend_from_slice(data);
+    }
+    bytes
+}
+
+pub(crate) fn decode_calls(bytes: BytesMut) -> Result<Vec<(u64, BytesMut)>, RemoteError> {
+    let calls = decode(bytes).ok_or_else(|| bad_batch("malformed batch"))?;
+    if calls.len() > MAX_BATCH_CALLS {
+        return Err(bad_batch("too many calls in batch"));
+    }
+    calls
+        .into_iter()
+        .map(|mut call| {
+            if call.len() < 8 {
+                return Err(bad_batch("truncated call in batch"));
+            }
+            let service_id = call.get_u64_le();
+            Ok((service_id, call))
+        })
+        .collect()
+}
+
+// Results are encoded like the responses of single calls
+pub(crate) fn encode_results(results: &[BytesMut]) -> BytesMut {
+    encode(results)
+}
+
+pub(crate) fn decode_results(bytes: BytesMut) -> Option<Vec<BytesMut>> {
+    decode(bytes)
+}
+
+fn bad_batch(message: &str) -> RemoteError {
+    RemoteError::new(RPCRequestError::BadRequest, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let calls: Vec<_> = (0..3u64)
+            .map(|i| {
+                let mut data = BytesMut::new();
+                data.put_u64_le(i * 10);
+                data.put_slice(&vec![i as u8; i as usize]);
+                (i, data)
+            })
+            .collect();
+        assert_eq!(decode_calls(encode_calls(&calls)).unwrap(), calls);
+        let results: Vec<_> = calls.into_iter().map(|(_, data)| data).collect();
+        assert_eq!(decode_results(encode_results(&results)).unwrap(), results);
+        assert!(decode_results(encode_results(&[])).unwrap().is_empty());
+        // Cut short
+        let mut truncated = encode_calls(&[(1, BytesMut::from(&b"call"[..]))]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(decode_calls(truncated).is_err());
+        assert!(decode_results(BytesMut::from(&[9u8, 0, 0, 0][..])).is_none());
+        let too_many = vec![(1, BytesMut::new()); MAX_BATCH_CALLS + 1];
+        assert!(decode_calls(encode_calls(&too_many)).is_err());
+    }
+}
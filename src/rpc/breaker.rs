@@ -0,0 +1,152 @@
+// Circuit breaking. After enough consecutive failures of calls to a peer, further calls
+// fail fast with `RPCError::CircuitOpen` for a while. Then a single call is let through to
+// probe the peer, closing the circuit if it succeeds and opening it again if it fails.
+use super::{RPCError, RPCRequestError};
+use parking_lot::Mutex as SyncMutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct BreakerOptions {
+    // Consecutive failures opening the circuit
+    pub failure_threshold: u32,
+    // How long calls fail fast before one probes the peer
+    pub open_duration: Duration,
+}
+
+impl Default for BreakerOptions {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A probe went out at this time
+    HalfOpen { since: Instant },
+}
+
+pub struct CircuitBreaker {
+    options: BreakerOptions,
+    state: SyncMutex<State>,
+}
+
+// Failures telling the peer is in trouble, unlike errors of the call itself
+pub fn is_failure(error: &RPCError) -> bool {
+    match error {
+        RPCError::IOError(_) | RPCError::Timeout => true,
+        RPCError::RequestError(e) => matches!(
+            e.kind,
+            RPCRequestError::Overloaded | RPCRequestError::ShuttingDown
+        ),
+        _ => false,
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(options: BreakerOptions) -> Self {
+        Self {
+            options,
+            state: SyncMutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // Check before a call. Probes that never report back, because their call was dropped,
+    // are replaced after `open_duration`.
+    pub fn acquire(&self) -> Result<(), RPCError> {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            State::HalfOpen { since } if now >= since + self.options.open_duration => {
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            _ => Err(RPCError::CircuitOpen),
+        }
+    }
+
+    pub fn record<T>(&self, res: &Result<T, RPCError>) {
+        match res {
+            Err(e) if is_failure(e) => self.on_failure(),
+            _ => self.on_success(),
+        }
+    }
+
+    pub fn on_success(&self) {
+        *self.state.lock() = State::Closed { failures: 0 };
+    }
+
+    pub fn on_failure(&self) {
+        let mut state = self.state.lock();
+        match *state {
+            State::Closed { failures } if failures + 1 < self.options.failure_threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            // Late failures of calls made before the circuit opened leave it as is
+            State::Open { .. } => {}
+            _ => {
+                debug!("Circuit opens for {:?}", self.options.open_duration);
+                *state = State::Open {
+                    until: Instant::now() + self.options.open_duration,
+                };
+            }
+        }
+    }
+
+    // Whether calls fail fast right now
+    pub fn is_open(&self) -> bool {
+        match *self.state.lock() {
+            State::Open { until } => Instant::now() < until,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn open_and_probe() {
+        let breaker = CircuitBreaker::new(BreakerOptions {
+            failure_threshold: 3,
+            open_duration: Duration::from_millis(100),
+        });
+        let failed: Result<(), _> = Err(RPCError::Timeout);
+        // Errors of the call do not count
+        breaker.record::<()>(&Err(RPCError::ClientCannotDecodeResponse));
+        breaker.record(&failed);
+        breaker.record(&failed);
+        breaker.record(&Ok(()));
+        breaker.record(&failed);
+        breaker.record(&failed);
+        assert!(breaker.acquire().is_ok());
+        breaker.record(&failed);
+        assert!(breaker.is_open());
+        assert!(matches!(breaker.acquire(), Err(RPCError::CircuitOpen)));
+        sleep(Duration::from_millis(150));
+        // One probe at a time
+        assert!(breaker.acquire().is_ok());
+        assert!(breaker.acquire().is_err());
+        breaker.record(&failed);
+        assert!(breaker.acquire().is_err());
+        sleep(Duration::from_millis(150));
+        assert!(breaker.acquire().is_ok());
+        breaker.record(&Ok(()));
+        assert!(!breaker.is_open());
+        assert!(breaker.acquire().is_ok());
+        assert!(breaker.acquire().is_ok());
+    }
+}
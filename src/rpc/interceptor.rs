@@ -0,0 +1,101 @@
+// Interceptors wrap every call of a server or a client, for cross-cutting concerns like
+// authorization, logging, metrics and tracing. They see the raw request, can answer it
+// themselves or pass it down the chain with `next.run`.
+use super::{RPCClient, RPCError, RPCService, RemoteError};
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct CallInfo {
+    pub service_id: u64,
+    pub func_id: u64,
+    // Remote address of the call. On servers it is `None` for calls through the
+    // in-process shortcut.
+    pub peer: Option<String>,
+}
+
+pub trait ServerInterceptor: Send + Sync {
+    // `data` starts with the function id, followed by the serialized arguments
+    fn intercept<'a>(
+        &'a self,
+        call: &'a CallInfo,
+        data: BytesMut,
+        next: ServerNext<'a>,
+    ) -> BoxFuture<'a, Result<BytesMut, RemoteError>>;
+}
+
+pub trait ClientInterceptor: Send + Sync {
+    // `data` starts with the function id, followed by the serialized arguments
+    fn intercept<'a>(
+        &'a self,
+        call: &'a CallInfo,
+        data: BytesMut,
+        next: ClientNext<'a>,
+    ) -> BoxFuture<'a, Result<BytesMut, RPCError>>;
+}
+
+pub type ServerInterceptors = Arc<Vec<Arc<dyn ServerInterceptor>>>;
+pub type ClientInterceptors = Arc<Vec<Arc<dyn ClientInterceptor>>>;
+
+// Rest of the chain on a server, ends with the service dispatching the request
+pub struct ServerNext<'a> {
+    pub(crate) interceptors: &'a [Arc<dyn ServerInterceptor>],
+    pub(crate) service: &'a dyn RPCService,
+}
+
+impl<'a> ServerNext<'a> {
+    pub fn run(
+        self,
+        call: &'a CallInfo,
+        data: BytesMut,
+    ) -> BoxFuture<'a, Result<BytesMut, RemoteError>> {
+        match self.interceptors.split_first() {
+            Some((interceptor, rest)) => interceptor.intercept(
+                call,
+                data,
+                ServerNext {
+                    interceptors: rest,
+                    service: self.service,
+                },
+            ),
+            None => self.service.dispatch(data),
+        }
+    }
+}
+
+// Rest of the chain on a client, ends with sending the request
+pub struct ClientNext<'a> {
+    pub(crate) interceptors: &'a [Arc<dyn ClientInterceptor>],
+    pub(crate) client: &'a RPCClient,
+    // Oneway calls are answered with an empty response as soon as they are sent
+    pub(crate) oneway: bool,
+}
+
+impl<'a> ClientNext<'a> {
+    pub fn run(
+        self,
+        call: &'a CallInfo,
+        data: BytesMut,
+    ) -> BoxFuture<'a, Result<BytesMut, RPCError>> {
+        match self.interceptors.split_first() {
+            Some((interceptor, rest)) => interceptor.intercept(
+                call,
+                data,
+                ClientNext {
+                    interceptors: rest,
+                    client: self.client,
+                    oneway: self.oneway,
+                },
+            ),
+            None => Box::pin(self.client.send_raw(call.service_id, data, self.oneway)),
+        }
+    }
+}
+
+pub(crate) fn func_id(data: &BytesMut) -> u64 {
+    data.get(..8)
+        .map(|head| u64::from_le_bytes(head.try_into().unwrap()))
+        .unwrap_or(0)
+}
@@ -1,6 +1,18 @@
 #[macro_use]
 pub mod proto;
+pub mod auth;
+pub mod batch;
+pub mod breaker;
+pub mod interceptor;
+pub mod retry;
+pub mod streaming;
 
+use self::breaker::{BreakerOptions, CircuitBreaker};
+use self::interceptor::*;
+use self::retry::RetryPolicy;
+// Deadline of the request being handled, or of calls being made
+pub use crate::tcp::deadline;
+use crate::utils::serde::{with_codec, Codec};
 use crate::{tcp, DISABLE_SHORTCUT};
 use bifrost_hasher::hash_str;
 use bytes::{Buf, BufMut, BytesMut};
@@ -9,38 +21,249 @@ use futures::prelude::*;
 use futures::Future;
 use serde::{Deserialize, Serialize};
 use lightning::map::*;
+use parking_lot::Mutex as SyncMutex;
+use std::any::Any;
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
+use std::fmt;
 use std::io;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::delay_for;
 use tokio::time::*;
 
 lazy_static! {
     pub static ref DEFAULT_CLIENT_POOL: ClientPool = ClientPool::new();
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RPCRequestError {
     FunctionIdNotFound,
     ServiceIdNotFound,
     BadRequest,
     Other,
+    ResponseTooLarge,
+    HandlerPanicked,
+    Overloaded,
+    ShuttingDown,
+    Unauthenticated,
+}
+
+// Error reported by the remote side of a call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub kind: RPCRequestError,
+    pub message: String,
+    pub detail: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum RPCError {
     IOError(io::Error),
-    RequestError(RPCRequestError),
+    RequestError(RemoteError),
     ClientCannotDecodeResponse,
+    LimitExceeded(tcp::Limit),
+    // No response within the client timeout
+    Timeout,
+    // Peer failed too often lately, the call was not sent
+    CircuitOpen,
+}
+
+impl RPCRequestError {
+    fn code(&self) -> u8 {
+        match self {
+            RPCRequestError::FunctionIdNotFound => 1,
+            RPCRequestError::ServiceIdNotFound => 2,
+            RPCRequestError::ResponseTooLarge => 3,
+            RPCRequestError::BadRequest => 4,
+            RPCRequestError::HandlerPanicked => 5,
+            RPCRequestError::Overloaded => 6,
+            RPCRequestError::ShuttingDown => 7,
+            RPCRequestError::Unauthenticated => 8,
+            RPCRequestError::Other => 255,
+        }
+    }
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => RPCRequestError::FunctionIdNotFound,
+            2 => RPCRequestError::ServiceIdNotFound,
+            3 => RPCRequestError::ResponseTooLarge,
+            4 => RPCRequestError::BadRequest,
+            5 => RPCRequestError::HandlerPanicked,
+            6 => RPCRequestError::Overloaded,
+            7 => RPCRequestError::ShuttingDown,
+            8 => RPCRequestError::Unauthenticated,
+            _ => RPCRequestError::Other,
+        }
+    }
+}
+
+impl fmt::Display for RPCRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RPCRequestError::FunctionIdNotFound => write!(f, "function not found"),
+            RPCRequestError::ServiceIdNotFound => write!(f, "service not found"),
+            RPCRequestError::BadRequest => write!(f, "bad request"),
+            RPCRequestError::Other => write!(f, "request failed"),
+            RPCRequestError::ResponseTooLarge => write!(f, "response too large"),
+            RPCRequestError::HandlerPanicked => write!(f, "handler panicked"),
+            RPCRequestError::Overloaded => write!(f, "server overloaded"),
+            RPCRequestError::ShuttingDown => write!(f, "server shutting down"),
+            RPCRequestError::Unauthenticated => write!(f, "unauthenticated"),
+        }
+    }
+}
+
+impl Error for RPCRequestError {}
+
+impl RemoteError {
+    pub fn new<M: Into<String>>(kind: RPCRequestError, message: M) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            detail: None,
+        }
+    }
+    pub fn with_detail<D: Into<String>>(mut self, detail: D) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+    // [code u8][message length u32][message][detail], old peers only send the code
+    fn encode(&self) -> BytesMut {
+        let detail = self.detail.as_ref().map(|d| d.as_bytes()).unwrap_or(&[]);
+        let mut buf = BytesMut::with_capacity(5 + self.message.len() + detail.len());
+        buf.put_u8(self.kind.code());
+        buf.put_u32_le(self.message.len() as u32);
+        buf.put_slice(self.message.as_bytes());
+        buf.put_slice(detail);
+        buf
+    }
+    fn decode(mut data: BytesMut) -> Self {
+        let kind = RPCRequestError::from_code(data.get_u8());
+        let mut error = RemoteError::new(kind, "");
+        if data.len() >= 4 {
+            let len = data.get_u32_le() as usize;
+            if len <= data.len() {
+                let message = data.split_to(len);
+                error.message = String::from_utf8_lossy(&message).into_owned();
+                if !data.is_empty() {
+                    error.detail = Some(String::from_utf8_lossy(&data).into_owned());
+                }
+            }
+        }
+        error
+    }
+}
+
+impl From<RPCRequestError> for RemoteError {
+    fn from(kind: RPCRequestError) -> Self {
+        RemoteError::new(kind, "")
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        if let Some(ref detail) = self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for RemoteError {}
+
+impl RPCError {
+    // Kind of the error reported by the remote side, if any
+    pub fn request_error(&self) -> Option<RPCRequestError> {
+        match self {
+            RPCError::RequestError(e) => Some(e.kind),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RPCError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RPCError::IOError(e) => write!(f, "io error: {}", e),
+            RPCError::RequestError(e) => write!(f, "remote error: {}", e),
+            RPCError::ClientCannotDecodeResponse => write!(f, "cannot decode response"),
+            RPCError::LimitExceeded(limit) => write!(f, "{}", limit),
+            RPCError::Timeout => write!(f, "request timed out"),
+            RPCError::CircuitOpen => write!(f, "circuit open"),
+        }
+    }
+}
+
+impl Error for RPCError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RPCError::IOError(e) => Some(e),
+            RPCError::RequestError(e) => Some(e),
+            RPCError::LimitExceeded(limit) => Some(limit),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RPCError {
+    fn from(e: io::Error) -> Self {
+        match tcp::exceeded_limit(&e) {
+            Some(limit) => RPCError::LimitExceeded(limit),
+            None if e.kind() == io::ErrorKind::TimedOut => RPCError::Timeout,
+            None => RPCError::IOError(e),
+        }
+    }
+}
+
+// Service ids are often hashes of names, two of them hashing alike must not replace
+// one another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    // Another service of the server has the id
+    Existed(u64),
+    // The id is used by the protocol
+    Reserved(u64),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegisterError::Existed(id) => write!(f, "service id {} is taken", id),
+            RegisterError::Reserved(id) => write!(f, "service id {} is reserved", id),
+        }
+    }
+}
+
+impl Error for RegisterError {}
+
+fn is_reserved(service_id: u64) -> bool {
+    service_id == tcp::frame::CONTROL_SERVICE_ID
+        || service_id == auth::AUTH_SERVICE_ID
+        || service_id == streaming::STREAM_SERVICE_ID
+        || service_id == batch::BATCH_SERVICE_ID
 }
 
 pub trait RPCService: Sync + Send {
-    fn dispatch(&self, data: BytesMut) -> BoxFuture<Result<BytesMut, RPCRequestError>>;
+    fn dispatch(&self, data: BytesMut) -> BoxFuture<Result<BytesMut, RemoteError>>;
     fn register_shortcut_service(
         &self,
         service_ptr: usize,
+        network: tcp::NetworkId,
+        server_id: u64,
+        service_id: u64,
+        intercepted: Arc<AtomicBool>,
+    ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+    fn unregister_shortcut_service(
+        &self,
+        network: tcp::NetworkId,
         server_id: u64,
         service_id: u64,
     ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
@@ -48,27 +271,46 @@ pub trait RPCService: Sync + Send {
 
 pub struct Server {
     services: ObjectMap<Arc<dyn RPCService>>,
+    // ObjectMap cannot be iterated, keep the ids around for shutdown
+    service_ids: SyncMutex<BTreeSet<u64>>,
+    handle: SyncMutex<Option<Arc<tcp::server::ServerHandle>>>,
+    interceptors: SyncMutex<ServerInterceptors>,
+    // Set once the server has an interceptor, services called directly through
+    // `get_local` would bypass it
+    intercepted: Arc<AtomicBool>,
+    // Checks signed requests when the server has a secret
+    verifier: Option<auth::Verifier>,
+    // Opened by streaming calls, reaped in background while the server runs
+    streams: Arc<streaming::Streams>,
+    reaper: SyncMutex<Option<future::AbortHandle>>,
+    options: tcp::server::ServerOptions,
+    // Advertised address, the listener binds to `ServerOptions::bind_address` when set
     pub address: String,
     pub server_id: u64,
+    pub network: tcp::NetworkId,
 }
 
 unsafe impl Sync for Server {}
 
+pub type PeerUnreachableCallback = Arc<dyn Fn(u64, &String) + Send + Sync>;
+
 pub struct ClientPool {
     clients: ObjectMap<Arc<RPCClient>>,
+    options: tcp::client::ClientOptions,
+    unreachable_callbacks: Arc<SyncMutex<Vec<PeerUnreachableCallback>>>,
+    // Id each address was connected with, reported to unreachable callbacks
+    peer_ids: Arc<SyncMutex<HashMap<String, u64>>>,
+    interceptors: SyncMutex<ClientInterceptors>,
+    retry: SyncMutex<Option<RetryPolicy>>,
+    breaker_options: SyncMutex<Option<BreakerOptions>>,
+    // By server id, outliving clients of the pool that get closed and reconnected
+    breakers: SyncMutex<HashMap<u64, Arc<CircuitBreaker>>>,
 }
 
-fn encode_res(res: Result<BytesMut, RPCRequestError>) -> BytesMut {
+fn encode_res(res: Result<BytesMut, RemoteError>) -> BytesMut {
     match res {
         Ok(buffer) => [0u8; 1].iter().cloned().chain(buffer.into_iter()).collect(),
-        Err(e) => {
-            let err_id = match e {
-                RPCRequestError::FunctionIdNotFound => 1u8,
-                RPCRequestError::ServiceIdNotFound => 2u8,
-                _ => 255u8,
-            };
-            BytesMut::from(&[err_id][..])
-        }
+        Err(e) => e.encode(),
     }
 }
 
@@ -79,14 +321,21 @@ fn decode_res(res: io::Result<BytesMut>) -> Result<BytesMut, RPCError> {
                 res.advance(1);
                 Ok(res.split())
             } else {
-                match res[0] {
-                    1u8 => Err(RPCError::RequestError(RPCRequestError::FunctionIdNotFound)),
-                    2u8 => Err(RPCError::RequestError(RPCRequestError::ServiceIdNotFound)),
-                    _ => Err(RPCError::RequestError(RPCRequestError::Other)),
-                }
+                Err(RPCError::RequestError(RemoteError::decode(res)))
             }
         }
-        Err(e) => Err(RPCError::IOError(e)),
+        Err(e) => Err(RPCError::from(e)),
+    }
+}
+
+// Message of a panic payload, which is a `&str` or a `String` for `panic!` with formatting
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown panic")
     }
 }
 
@@ -97,63 +346,266 @@ pub fn read_u64_head(mut data: BytesMut) -> (u64, BytesMut) {
 
 impl Server {
     pub fn new(address: &String) -> Arc<Server> {
+        Self::new_with_options(address, tcp::server::ServerOptions::default())
+    }
+    pub fn new_with_options(
+        address: &String,
+        mut options: tcp::server::ServerOptions,
+    ) -> Arc<Server> {
+        if options.closing_response.is_none() {
+            let closing = RemoteError::new(RPCRequestError::ShuttingDown, address.clone());
+            options.closing_response = Some(closing.encode().freeze());
+        }
+        if options.overloaded_response.is_none() {
+            let overloaded = RemoteError::new(RPCRequestError::Overloaded, address.clone());
+            options.overloaded_response = Some(overloaded.encode().freeze());
+        }
         Arc::new(Server {
             services: ObjectMap::with_capacity(16),
+            service_ids: SyncMutex::new(BTreeSet::new()),
+            handle: SyncMutex::new(None),
+            interceptors: SyncMutex::new(Arc::new(vec![])),
+            intercepted: Arc::new(AtomicBool::new(false)),
+            verifier: options.secret.clone().map(auth::Verifier::new),
+            streams: Arc::new(streaming::Streams::default()),
+            reaper: SyncMutex::new(None),
+            network: options.network,
+            options,
             address: address.clone(),
             server_id: hash_str(address),
         })
     }
     pub async fn listen(server: &Arc<Server>) -> Result<(), Box<dyn Error>> {
+        let handle = Self::start(server).await?;
+        handle.stopped().await;
+        Ok(())
+    }
+
+    // Bind the address and return once the server is ready to take requests
+    pub async fn start(
+        server: &Arc<Server>,
+    ) -> Result<Arc<tcp::server::ServerHandle>, Box<dyn Error>> {
         let address = &server.address;
-        let server = server.clone();
-        tcp::server::Server::new(
+        let options = server.options.clone();
+        let this = server.clone();
+        let handle = tcp::server::Server::start(
             address,
             Arc::new(move |data| {
-                let server = server.clone();
-                async move {
-                    let (svr_id, data) = read_u64_head(data);
-                    let service = server.services.get(&(svr_id as usize));
-                    trace!("Processing request for service {}", svr_id);
-                    match service {
-                        Some(service) => {
-                            let svr_res = service.dispatch(data).await;
-                            encode_res(svr_res)
+                let server = this.clone();
+                // Handlers encode and decode with the codec of the server
+                let codec = server.options.codec;
+                let streams = server.streams.clone();
+                let handling = async move {
+                    let (svr_id, data) = match server.open(data) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            warn!(
+                                "Refused request from {:?}: {}",
+                                tcp::server::peer_address(),
+                                e
+                            );
+                            return encode_res(Err(e));
                         }
-                        None => encode_res(Err(RPCRequestError::ServiceIdNotFound)),
+                    };
+                    if svr_id == batch::BATCH_SERVICE_ID {
+                        server.call_batch(data).await
+                    } else {
+                        server.call(svr_id, data).await
                     }
-                }
-                .boxed()
+                };
+                with_codec(codec, streaming::with_streams(streams, handling)).boxed()
             }),
+            &options,
         )
-        .await
+        .await?;
+        *server.handle.lock() = Some(handle.clone());
+        let (reaper, reaper_reg) = future::AbortHandle::new_pair();
+        let reaping = streaming::reap_streams(Arc::downgrade(&server.streams), handle.clone());
+        tokio::spawn(future::Abortable::new(reaping, reaper_reg));
+        if let Some(previous) = server.reaper.lock().replace(reaper) {
+            previous.abort();
+        }
+        Ok(handle)
+    }
+
+    // Dispatch a request through the interceptors and answer the encoded response
+    async fn call(&self, svr_id: u64, data: BytesMut) -> BytesMut {
+        let service = if svr_id == streaming::STREAM_SERVICE_ID {
+            Some(streaming::service())
+        } else {
+            self.services.get(&(svr_id as usize))
+        };
+        trace!("Processing request for service {}", svr_id);
+        match service {
+            Some(service) => {
+                let call = CallInfo {
+                    service_id: svr_id,
+                    func_id: interceptor::func_id(&data),
+                    peer: tcp::server::peer_address(),
+                };
+                let interceptors = self.interceptors.lock().clone();
+                let next = ServerNext {
+                    interceptors: &interceptors,
+                    service: &*service,
+                };
+                let svr_res = AssertUnwindSafe(next.run(&call, data))
+                    .catch_unwind()
+                    .await
+                    .unwrap_or_else(|payload| {
+                        let msg = panic_message(&*payload);
+                        error!("Service {} panicked: {}", svr_id, msg);
+                        Err(RemoteError::new(RPCRequestError::HandlerPanicked, msg))
+                    });
+                self.limit_response(svr_id, encode_res(svr_res))
+            }
+            None => encode_res(Err(RemoteError::new(
+                RPCRequestError::ServiceIdNotFound,
+                format!("no service {}", svr_id),
+            ))),
+        }
+    }
+
+    // Calls of a batch run concurrently, each like a request of its own
+    async fn call_batch(&self, data: BytesMut) -> BytesMut {
+        let calls = match batch::decode_calls(data) {
+            Ok(calls) => calls,
+            Err(e) => return encode_res(Err(e)),
+        };
+        trace!("Processing batch of {} calls", calls.len());
+        let results = future::join_all(
+            calls
+                .into_iter()
+                .map(|(svr_id, data)| self.call(svr_id, data)),
+        )
+        .await;
+        let res = encode_res(Ok(batch::encode_results(&results)));
+        self.limit_response(batch::BATCH_SERVICE_ID, res)
+    }
+
+    fn limit_response(&self, svr_id: u64, res: BytesMut) -> BytesMut {
+        // Leave room for the msg id the tcp server prepends
+        let max_res_len = self.options.max_frame_length.saturating_sub(8);
+        if res.len() > max_res_len {
+            warn!(
+                "Response of service {} is {} bytes, exceeds frame limit",
+                svr_id,
+                res.len()
+            );
+            encode_res(Err(RemoteError::new(
+                RPCRequestError::ResponseTooLarge,
+                format!("{} bytes response", res.len()),
+            )))
+        } else {
+            res
+        }
+    }
+
+    // Service id and payload of a request, out of its auth envelope if signed
+    fn open(&self, data: BytesMut) -> Result<(u64, BytesMut), RemoteError> {
+        let (svr_id, data) = read_u64_head(data);
+        match (&self.verifier, svr_id == auth::AUTH_SERVICE_ID) {
+            (Some(verifier), true) => Ok(read_u64_head(verifier.verify(data)?)),
+            (Some(_), false) => Err(RemoteError::new(
+                RPCRequestError::Unauthenticated,
+                "request not signed",
+            )),
+            (None, true) => Ok(read_u64_head(auth::unwrap(data)?)),
+            (None, false) => Ok((svr_id, data)),
+        }
     }
 
     pub async fn listen_and_resume(server: &Arc<Server>) {
-        let server = server.clone();
-        tokio::spawn(async move {
-            Self::listen(&server).await.unwrap();
-        });
-        delay_for(Duration::from_secs(1)).await
+        if let Err(e) = Self::start(server).await {
+            error!(
+                "Cannot listen on {}, error {:?}",
+                server
+                    .options
+                    .bind_address
+                    .as_ref()
+                    .unwrap_or(&server.address),
+                e
+            );
+        }
+    }
+
+    // Stop accepting, wait at most `deadline` for in-flight requests and release the
+    // address along with the shortcuts, so it can be bound again in this process.
+    // Returns false if some requests were still running when connections were closed.
+    pub async fn shutdown(&self, deadline: Duration) -> bool {
+        let handle = self.handle.lock().take();
+        let drained = match handle {
+            Some(handle) => handle.close(deadline).await,
+            None => true,
+        };
+        if let Some(reaper) = self.reaper.lock().take() {
+            reaper.abort();
+        }
+        self.streams.clear();
+        let service_ids: Vec<_> = self.service_ids.lock().iter().cloned().collect();
+        for service_id in service_ids {
+            if let Some(service) = self.services.get(&(service_id as usize)) {
+                service
+                    .unregister_shortcut_service(self.network, self.server_id, service_id)
+                    .await;
+            }
+        }
+        drained
+    }
+
+    // Interceptors run in the order they are added, around every request dispatched to
+    // the services of this server
+    pub fn add_interceptor<I>(&self, interceptor: I)
+    where
+        I: ServerInterceptor + 'static,
+    {
+        let mut interceptors = self.interceptors.lock();
+        let mut chain = (**interceptors).clone();
+        chain.push(Arc::new(interceptor));
+        *interceptors = Arc::new(chain);
+        self.intercepted.store(true, Relaxed);
     }
 
-    pub async fn register_service<T>(&self, service_id: u64, service: &Arc<T>)
+    pub async fn register_service<T>(
+        &self,
+        service_id: u64,
+        service: &Arc<T>,
+    ) -> Result<(), RegisterError>
     where
         T: RPCService + Sized + 'static,
     {
+        if is_reserved(service_id) {
+            return Err(RegisterError::Reserved(service_id));
+        }
+        if !self.service_ids.lock().insert(service_id) {
+            return Err(RegisterError::Existed(service_id));
+        }
         let service = service.clone();
         if !DISABLE_SHORTCUT {
             let service_ptr = Arc::into_raw(service.clone()) as usize;
             service
-                .register_shortcut_service(service_ptr, self.server_id, service_id)
+                .register_shortcut_service(
+                    service_ptr,
+                    self.network,
+                    self.server_id,
+                    service_id,
+                    self.intercepted.clone(),
+                )
                 .await;
         } else {
             debug!("SERVICE SHORTCUT DISABLED");
         }
         self.services.insert(&(service_id as usize), service);
+        Ok(())
     }
 
     pub async fn remove_service(&self, service_id: u64) {
+        if let Some(service) = self.services.get(&(service_id as usize)) {
+            service
+                .unregister_shortcut_service(self.network, self.server_id, service_id)
+                .await;
+        }
         self.services.remove(&(service_id as usize));
+        self.service_ids.lock().remove(&service_id);
     }
     pub fn address(&self) -> &String {
         &self.address
@@ -162,7 +614,14 @@ impl Server {
 
 pub struct RPCClient {
     client: tcp::client::Client,
+    interceptors: SyncMutex<ClientInterceptors>,
+    // Requests are signed with it when set
+    secret: Option<auth::Secret>,
+    retry: SyncMutex<Option<Arc<RetryPolicy>>>,
+    breaker: SyncMutex<Option<Arc<CircuitBreaker>>>,
+    codec: &'static dyn Codec,
     pub server_id: u64,
+    pub network: tcp::NetworkId,
     pub address: String,
 }
 
@@ -179,15 +638,185 @@ impl RPCClient {
         svr_id: u64,
         data: BytesMut,
     ) -> Result<BytesMut, RPCError> {
-        let client = &self.client;
-        let payload = prepend_u64(svr_id, data);
-        let res = client.send_msg(payload).await;
-        decode_res(res)
+        let retry = self.retry.lock().clone();
+        let this = self.get_ref();
+        match retry {
+            // Stream items are not retried, the server may have taken them
+            Some(retry) if svr_id != streaming::STREAM_SERVICE_ID => {
+                retry
+                    .run(move || this.send(svr_id, data.clone(), false))
+                    .await
+            }
+            _ => self.send(svr_id, data, false).await,
+        }
+    }
+    // Returns once the request is sent, the server answers nothing
+    pub async fn send_oneway(
+        self: Pin<&Self>,
+        svr_id: u64,
+        data: BytesMut,
+    ) -> Result<(), RPCError> {
+        self.send(svr_id, data, true).await.map(|_| ())
+    }
+    async fn send(&self, svr_id: u64, data: BytesMut, oneway: bool) -> Result<BytesMut, RPCError> {
+        let interceptors = self.interceptors.lock().clone();
+        if interceptors.is_empty() {
+            return self.send_raw(svr_id, data, oneway).await;
+        }
+        let call = CallInfo {
+            service_id: svr_id,
+            func_id: interceptor::func_id(&data),
+            peer: Some(self.address.clone()),
+        };
+        let next = ClientNext {
+            interceptors: &interceptors,
+            client: self,
+            oneway,
+        };
+        next.run(&call, data).await
+    }
+    // Send calls to several services of the server in one request, each with data starting
+    // with the function id like for `send_async`. The server runs them concurrently and
+    // the results come back in the order of the calls. More than `MAX_BATCH_CALLS` calls
+    // are split into several batches. With interceptors, or to servers without batches,
+    // the calls are sent one by one instead.
+    pub async fn send_batch(
+        self: Pin<&Self>,
+        mut calls: Vec<(u64, BytesMut)>,
+    ) -> Result<Vec<Result<BytesMut, RPCError>>, RPCError> {
+        if calls.is_empty() {
+            return Ok(vec![]);
+        }
+        if !self.interceptors.lock().is_empty() {
+            return Ok(self.send_each(calls).await);
+        }
+        let mut batches = vec![];
+        while calls.len() > batch::MAX_BATCH_CALLS {
+            let rest = calls.split_off(batch::MAX_BATCH_CALLS);
+            batches.push(calls);
+            calls = rest;
+        }
+        batches.push(calls);
+        let results =
+            future::try_join_all(batches.into_iter().map(|calls| self.send_one_batch(calls)))
+                .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+    async fn send_one_batch(
+        &self,
+        calls: Vec<(u64, BytesMut)>,
+    ) -> Result<Vec<Result<BytesMut, RPCError>>, RPCError> {
+        let req = batch::encode_calls(&calls);
+        match self.send_raw(batch::BATCH_SERVICE_ID, req, false).await {
+            Ok(res) => match batch::decode_results(res) {
+                Some(results) if results.len() == calls.len() => Ok(results
+                    .into_iter()
+                    .map(|res| {
+                        if res.is_empty() {
+                            Err(RPCError::ClientCannotDecodeResponse)
+                        } else {
+                            decode_res(Ok(res))
+                        }
+                    })
+                    .collect()),
+                _ => Err(RPCError::ClientCannotDecodeResponse),
+            },
+            Err(RPCError::RequestError(ref e)) if e.kind == RPCRequestError::ServiceIdNotFound => {
+                Ok(self.send_each(calls).await)
+            }
+            Err(e) => Err(e),
+        }
+    }
+    async fn send_each(&self, calls: Vec<(u64, BytesMut)>) -> Vec<Result<BytesMut, RPCError>> {
+        future::join_all(
+            calls
+                .into_iter()
+                .map(|(svr_id, data)| self.send(svr_id, data, false)),
+        )
+        .await
+    }
+    // Oneway requests resolve to an empty response
+    pub(crate) async fn send_raw(
+        &self,
+        svr_id: u64,
+        data: BytesMut,
+        oneway: bool,
+    ) -> Result<BytesMut, RPCError> {
+        let breaker = self.breaker.lock().clone();
+        if let Some(ref breaker) = breaker {
+            breaker.acquire()?;
+        }
+        let mut payload = prepend_u64(svr_id, data);
+        if let Some(ref secret) = self.secret {
+            payload = auth::sign(secret, payload);
+        }
+        let res = if oneway {
+            self.client
+                .send_oneway(payload)
+                .await
+                .map(|_| BytesMut::new())
+                .map_err(RPCError::from)
+        } else {
+            decode_res(self.client.send_msg(payload).await)
+        };
+        if let Some(ref breaker) = breaker {
+            breaker.record(&res);
+        }
+        res
+    }
+    // Retry calls made through `send_async`, including the ones of service clients
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        *self.retry.lock() = policy.map(Arc::new);
+    }
+    // Fail calls fast while the breaker is open
+    pub fn set_circuit_breaker(&self, breaker: Option<Arc<CircuitBreaker>>) {
+        *self.breaker.lock() = breaker;
+    }
+    // Interceptors run in the order they are added, around every request of this client
+    pub fn add_interceptor<I>(&self, interceptor: I)
+    where
+        I: ClientInterceptor + 'static,
+    {
+        self.add_interceptor_arc(Arc::new(interceptor));
+    }
+    fn add_interceptor_arc(&self, interceptor: Arc<dyn ClientInterceptor>) {
+        let mut interceptors = self.interceptors.lock();
+        let mut chain = (**interceptors).clone();
+        chain.push(interceptor);
+        *interceptors = Arc::new(chain);
+    }
+    // Whether a call may go straight to the service object through `get_local`, which
+    // skips the transport along with injected faults and interceptors. `get_local` only
+    // resolves services of servers without interceptors.
+    pub fn can_call_local(&self) -> bool {
+        !tcp::faults::enabled(self.network) && self.interceptors.lock().is_empty()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+    // Arguments and results of calls through this client are encoded with it
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.codec
+    }
+    pub fn connection_state(&self) -> tcp::client::ConnectionState {
+        self.client.state()
     }
     pub async fn new_async(addr: &String) -> io::Result<Arc<RPCClient>> {
-        let client = tcp::client::Client::connect(addr).await?;
+        Self::new_async_with_options(addr, &tcp::client::ClientOptions::default()).await
+    }
+    pub async fn new_async_with_options(
+        addr: &String,
+        options: &tcp::client::ClientOptions,
+    ) -> io::Result<Arc<RPCClient>> {
+        let client = tcp::client::Client::connect_with_options(addr, options).await?;
         Ok(Arc::new(RPCClient {
+            interceptors: SyncMutex::new(Arc::new(vec![])),
+            secret: options.secret.clone(),
+            retry: SyncMutex::new(None),
+            breaker: SyncMutex::new(None),
+            codec: options.codec,
             server_id: client.server_id,
+            network: client.network,
             client,
             address: addr.clone(),
         }))
@@ -196,11 +825,89 @@ impl RPCClient {
 
 impl ClientPool {
     pub fn new() -> ClientPool {
+        Self::new_with_options(tcp::client::ClientOptions::default())
+    }
+
+    pub fn new_with_options(mut options: tcp::client::ClientOptions) -> ClientPool {
+        let unreachable_callbacks: Arc<SyncMutex<Vec<PeerUnreachableCallback>>> =
+            Arc::new(SyncMutex::new(vec![]));
+        let peer_ids: Arc<SyncMutex<HashMap<String, u64>>> =
+            Arc::new(SyncMutex::new(HashMap::new()));
+        let callbacks = unreachable_callbacks.clone();
+        let ids = peer_ids.clone();
+        let on_unreachable = options.on_unreachable.take();
+        options.on_unreachable = Some(Arc::new(move |address: &String| {
+            if let Some(ref on_unreachable) = on_unreachable {
+                on_unreachable(address);
+            }
+            let server_id = ids
+                .lock()
+                .get(address)
+                .cloned()
+                .unwrap_or_else(|| hash_str(address));
+            let callbacks = callbacks.lock().clone();
+            for callback in callbacks {
+                callback(server_id, address);
+            }
+        }));
         ClientPool {
             clients: ObjectMap::with_capacity(16),
+            options,
+            unreachable_callbacks,
+            peer_ids,
+            interceptors: SyncMutex::new(Arc::new(vec![])),
+            retry: SyncMutex::new(None),
+            breaker_options: SyncMutex::new(None),
+            breakers: SyncMutex::new(HashMap::new()),
         }
     }
 
+    // Of the clients the pool connects
+    pub fn codec(&self) -> &'static dyn Codec {
+        self.options.codec
+    }
+
+    // Set on clients the pool connects from now on
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        *self.retry.lock() = policy;
+    }
+
+    // Give every peer a circuit breaker, for clients the pool connects from now on.
+    // Connecting to a peer with an open circuit fails fast too.
+    pub fn set_circuit_breaker(&self, options: Option<BreakerOptions>) {
+        *self.breaker_options.lock() = options;
+    }
+
+    pub fn circuit_breaker(&self, server_id: u64) -> Option<Arc<CircuitBreaker>> {
+        let options = self.breaker_options.lock().clone()?;
+        let mut breakers = self.breakers.lock();
+        let breaker = breakers
+            .entry(server_id)
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(options)));
+        Some(breaker.clone())
+    }
+
+    // Added to clients the pool connects from now on
+    pub fn add_interceptor<I>(&self, interceptor: I)
+    where
+        I: ClientInterceptor + 'static,
+    {
+        let mut interceptors = self.interceptors.lock();
+        let mut chain = (**interceptors).clone();
+        chain.push(Arc::new(interceptor));
+        *interceptors = Arc::new(chain);
+    }
+
+    // Subscribe to connections of clients in this pool being lost, by dead peer detection or
+    // broken streams. Callback takes the id the peer was connected with, like the node id
+    // given to `get_by_id`, and its address.
+    pub fn on_peer_unreachable<F>(&self, callback: F)
+    where
+        F: Fn(u64, &String) + Send + Sync + 'static,
+    {
+        self.unreachable_callbacks.lock().push(Arc::new(callback));
+    }
+
     pub async fn get(&self, addr: &String) -> io::Result<Arc<RPCClient>> {
         let addr_clone = addr.clone();
         let server_id = hash_str(addr);
@@ -212,18 +919,94 @@ impl ClientPool {
         F: FnOnce(u64) -> String,
     {
         let clients = &self.clients;
-        if clients.contains(&(server_id as usize)) {
-            let client = clients.get(&(server_id as usize)).unwrap().clone();
-            Ok(client)
-        } else {
-            let client = timeout(
-                Duration::from_secs(5),
-                RPCClient::new_async(&addr_fn(server_id)),
-            )
-            .await??;
-            clients.insert(&(server_id as usize), client.clone());
-            Ok(client)
+        if let Some(client) = clients.get(&(server_id as usize)) {
+            if client.connection_state() != tcp::client::ConnectionState::Closed {
+                return Ok(client.clone());
+            }
+            debug!("Client for {} is closed, reconnecting", client.address);
+            clients.remove(&(server_id as usize));
         }
+        let breaker = self.circuit_breaker(server_id);
+        if let Some(ref breaker) = breaker {
+            if breaker.is_open() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    RPCError::CircuitOpen.to_string(),
+                ));
+            }
+        }
+        let address = addr_fn(server_id);
+        self.peer_ids.lock().insert(address.clone(), server_id);
+        let connect = timeout(
+            Duration::from_secs(5),
+            RPCClient::new_async_with_options(&address, &self.options),
+        )
+        .await
+        .map_err(io::Error::from)
+        .and_then(|res| res);
+        let client = match (connect, &breaker) {
+            (Ok(client), _) => client,
+            (Err(e), Some(breaker)) => {
+                breaker.on_failure();
+                return Err(e);
+            }
+            (Err(e), None) => return Err(e),
+        };
+        for interceptor in self.interceptors.lock().iter() {
+            client.add_interceptor_arc(interceptor.clone());
+        }
+        client.set_retry_policy(self.retry.lock().clone());
+        client.set_circuit_breaker(breaker);
+        clients.insert(&(server_id as usize), client.clone());
+        Ok(client)
+    }
+}
+
+// An isolated in-process network. Servers created in it are only reachable by clients of
+// its pool, so several clusters can run in one process with the same addresses.
+pub struct Network {
+    pub id: tcp::NetworkId,
+    pub client_pool: ClientPool,
+    servers: SyncMutex<Vec<Arc<Server>>>,
+}
+
+impl Network {
+    pub fn new() -> Arc<Network> {
+        Self::new_with_options(tcp::client::ClientOptions::default())
+    }
+
+    pub fn new_with_options(mut client_options: tcp::client::ClientOptions) -> Arc<Network> {
+        let id = tcp::new_network_id();
+        client_options.network = id;
+        Arc::new(Network {
+            id,
+            client_pool: ClientPool::new_with_options(client_options),
+            servers: SyncMutex::new(vec![]),
+        })
+    }
+
+    pub fn server(&self, address: &String) -> Arc<Server> {
+        let options = tcp::server::ServerOptions {
+            network: self.id,
+            ..tcp::server::ServerOptions::default()
+        };
+        let server = Server::new_with_options(address, options);
+        self.servers.lock().push(server.clone());
+        server
+    }
+
+    pub async fn client(&self, address: &String) -> io::Result<Arc<RPCClient>> {
+        self.client_pool.get(address).await
+    }
+
+    // Take down every server created in this network, along with its injected faults
+    pub async fn shutdown(&self) {
+        let servers: Vec<_> = self.servers.lock().drain(..).collect();
+        for server in servers {
+            server.shutdown(Duration::from_secs(0)).await;
+        }
+        tcp::shortcut::deregister_network(self.id).await;
+        tcp::faults::heal(self.id);
     }
 }
 
@@ -263,7 +1046,10 @@ mod test {
             {
                 let addr = addr.clone();
                 let server = Server::new(&addr);
-                server.register_service(0, &Arc::new(HelloServer)).await;
+                server
+                    .register_service(0, &Arc::new(HelloServer))
+                    .await
+                    .unwrap();
                 Server::listen_and_resume(&server).await;
             }
             delay_for(Duration::from_millis(1000)).await;
@@ -320,7 +1106,10 @@ mod test {
             {
                 let addr = addr.clone();
                 let server = Server::new(&addr); // 0 is service id
-                server.register_service(0, &Arc::new(HelloServer)).await;
+                server
+                    .register_service(0, &Arc::new(HelloServer))
+                    .await
+                    .unwrap();
                 Server::listen_and_resume(&server).await;
             }
             delay_for(Duration::from_millis(1000)).await;
@@ -411,7 +1200,8 @@ mod test {
                     let server = Server::new(&addr); // 0 is service id
                     server
                         .register_service(id, &Arc::new(IdServer { id: id }))
-                        .await;
+                        .await
+                        .unwrap();
                     Server::listen_and_resume(&server).await;
                     id += 1;
                 }
@@ -443,6 +1233,38 @@ mod test {
                 id += 1;
             }
         }
+
+        #[tokio::test(threaded_scheduler)]
+        async fn isolated_networks() {
+            let _ = env_logger::try_init();
+            // Both networks use the same address, which is never bound
+            let addr = String::from("10.0.0.1:1510");
+            let networks = vec![Network::new(), Network::new()];
+            for (id, network) in networks.iter().enumerate() {
+                let server = network.server(&addr);
+                server
+                    .register_service(0, &Arc::new(IdServer { id: id as u64 }))
+                    .await
+                    .unwrap();
+                Server::listen_and_resume(&server).await;
+            }
+            for (id, network) in networks.iter().enumerate() {
+                let client = network.client(&addr).await.unwrap();
+                let service_client = AsyncServiceClient::new(0, &client);
+                assert_eq!(service_client.query_server_id().await.unwrap(), id as u64);
+            }
+            let other_addr = String::from("10.0.0.2:1510");
+            Server::listen_and_resume(&networks[1].server(&other_addr)).await;
+            assert!(networks[0].client(&other_addr).await.is_err());
+
+            let client = networks[0].client(&addr).await.unwrap();
+            networks[0].shutdown().await;
+            let service_client = AsyncServiceClient::new(0, &client);
+            assert!(service_client.query_server_id().await.is_err());
+            let client = networks[1].client(&addr).await.unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            assert_eq!(service_client.query_server_id().await.unwrap(), 1);
+        }
     }
 
     mod parallel {
@@ -460,7 +1282,10 @@ mod test {
             {
                 let addr = addr.clone();
                 let server = Server::new(&addr); // 0 is service id
-                server.register_service(0, &Arc::new(HelloServer)).await;
+                server
+                    .register_service(0, &Arc::new(HelloServer))
+                    .await
+                    .unwrap();
                 Server::listen_and_resume(&server).await;
             }
             delay_for(Duration::from_millis(1000)).await;
@@ -515,4 +1340,1016 @@ mod test {
             while futs.next().await.is_some() {}
         }
     }
+
+    mod tls {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::{RPCClient, Server};
+        use crate::tcp::client::ClientOptions;
+        use crate::tcp::server::ServerOptions;
+        use crate::tcp::tls::{ClientTls, ServerTls};
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn tls_rpc() {
+            let _ = env_logger::try_init();
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let cert_der = cert.serialize_der().unwrap();
+            let key_der = cert.serialize_private_key_der();
+            // Bind on the wildcard address but dial the loopback one, so the client
+            // does not pick up the in-process shortcut and actually goes through TLS
+            let bind_addr = String::from("0.0.0.0:1420");
+            let addr = String::from("127.0.0.1:1420");
+            {
+                let server = Server::new_with_options(
+                    &bind_addr,
+                    ServerOptions {
+                        tls: Some(ServerTls::new(vec![cert_der.clone()], key_der).unwrap()),
+                        ..ServerOptions::default()
+                    },
+                );
+                server
+                    .register_service(0, &Arc::new(HelloServer))
+                    .await
+                    .unwrap();
+                Server::listen_and_resume(&server).await;
+            }
+            let options = ClientOptions {
+                tls: Some(ClientTls::new(vec![cert_der], "localhost").unwrap()),
+                ..ClientOptions::default()
+            };
+            let client = RPCClient::new_async_with_options(&addr, &options)
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let res = service_client
+                .hello(Greeting {
+                    name: String::from("Jack"),
+                    time: 12,
+                })
+                .await
+                .unwrap();
+            assert_eq!(res.text, String::from("Hello, Jack. It is 12 now!"));
+
+            // Plain text clients cannot talk to a TLS server, their handshake fails
+            assert!(RPCClient::new_async(&addr).await.is_err());
+        }
+    }
+
+    mod unix_socket {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::{Server, DEFAULT_CLIENT_POOL};
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn unix_socket_rpc() {
+            let _ = env_logger::try_init();
+            let addr = String::from("unix:/tmp/bifrost-unix-socket-rpc.sock");
+            {
+                let server = Server::new(&addr);
+                server
+                    .register_service(0, &Arc::new(HelloServer))
+                    .await
+                    .unwrap();
+                Server::listen_and_resume(&server).await;
+            }
+            // Same socket file under another address, skipping the in-process shortcut
+            let remote_addr = String::from("unix:/tmp/./bifrost-unix-socket-rpc.sock");
+            for addr in &[addr, remote_addr] {
+                let client = DEFAULT_CLIENT_POOL.get(addr).await.unwrap();
+                let service_client = AsyncServiceClient::new(0, &client);
+                let res = service_client
+                    .hello(Greeting {
+                        name: String::from("Jack"),
+                        time: 12,
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(res.text, String::from("Hello, Jack. It is 12 now!"));
+            }
+        }
+    }
+
+    mod shutdown {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::{RPCClient, RPCError, Server};
+        use crate::tcp::shortcut;
+
+        async fn hello(client: &Arc<RPCClient>) -> Result<Respond, RPCError> {
+            AsyncServiceClient::new(0, client)
+                .hello(Greeting {
+                    name: String::from("Jack"),
+                    time: 12,
+                })
+                .await
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn shutdown_and_rebind() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1430");
+            let remote_addr = String::from("127.0.0.1:1430");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            // Server is bound once `listen_and_resume` returns
+            let client = RPCClient::new_async(&remote_addr).await.unwrap();
+            assert_eq!(hello(&client).await.unwrap().owner, 42);
+            assert!(get_local(server.network, server.server_id, 0)
+                .await
+                .is_some());
+
+            assert!(server.shutdown(Duration::from_secs(1)).await);
+            assert!(get_local(server.network, server.server_id, 0)
+                .await
+                .is_none());
+            assert!(!shortcut::is_local(server.network, server.server_id).await);
+            assert!(hello(&client).await.is_err());
+
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&remote_addr).await.unwrap();
+            assert_eq!(hello(&client).await.unwrap().owner, 42);
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod limits {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::{RPCClient, RPCError, Server};
+        use crate::tcp::client::ClientOptions;
+        use crate::tcp::Limit;
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn request_too_large() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1450");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let options = ClientOptions {
+                max_frame_length: 1024,
+                ..ClientOptions::default()
+            };
+            let client =
+                RPCClient::new_async_with_options(&String::from("127.0.0.1:1450"), &options)
+                    .await
+                    .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let res = service_client
+                .hello(Greeting {
+                    name: "Jack".repeat(512),
+                    time: 12,
+                })
+                .await;
+            match res {
+                Err(RPCError::LimitExceeded(Limit::FrameLength)) => {}
+                _ => panic!("Expected frame length limit error"),
+            }
+            // Connection is still usable after a refused request
+            let res = service_client
+                .hello(Greeting {
+                    name: String::from("Jack"),
+                    time: 12,
+                })
+                .await;
+            assert_eq!(res.unwrap().owner, 42);
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod peer_unreachable {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::{ClientPool, Server};
+        use parking_lot::Mutex;
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn notify_unreachable() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1460");
+            let remote_addr = String::from("127.0.0.1:1460");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let pool = ClientPool::new();
+            let unreachable = Arc::new(Mutex::new(vec![]));
+            {
+                let unreachable = unreachable.clone();
+                pool.on_peer_unreachable(move |server_id, _| unreachable.lock().push(server_id));
+            }
+            // Reported with the node id it was connected with, not the one of its address
+            let node_id = 42;
+            let client = pool
+                .get_by_id(node_id, |_| remote_addr.clone())
+                .await
+                .unwrap();
+            assert!(client.is_connected());
+            server.shutdown(Duration::from_secs(1)).await;
+            delay_for(Duration::from_millis(500)).await;
+            assert!(!client.is_connected());
+            assert_eq!(*unreachable.lock(), vec![node_id]);
+        }
+    }
+
+    mod advertise {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::{RPCClient, Server};
+        use crate::tcp::server::ServerOptions;
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn bind_and_advertise() {
+            let _ = env_logger::try_init();
+            let advertised = String::from("127.0.0.1:1471");
+            let options = ServerOptions {
+                bind_address: Some(String::from("0.0.0.0:1470")),
+                ..ServerOptions::default()
+            };
+            let server = Server::new_with_options(&advertised, options);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::start(&server).await.unwrap();
+            assert_eq!(server.address(), &advertised);
+            // Dialed by host name through the bound port
+            let client = RPCClient::new_async(&String::from("localhost:1470"))
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let res = service_client
+                .hello(Greeting {
+                    name: String::from("Jack"),
+                    time: 12,
+                })
+                .await;
+            assert_eq!(res.unwrap().owner, 42);
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod errors {
+        use super::*;
+        use crate::rpc::{RPCClient, RPCError, RPCRequestError, Server};
+        use crate::tcp::server::ServerOptions;
+
+        service! {
+            rpc boom(msg: String);
+            rpc nap(millis: u64);
+        }
+
+        struct BoomServer;
+
+        impl Service for BoomServer {
+            fn boom(&self, msg: String) -> BoxFuture<()> {
+                async move { panic!("{}", msg) }.boxed()
+            }
+            fn nap(&self, millis: u64) -> BoxFuture<()> {
+                delay_for(Duration::from_millis(millis)).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(BoomServer);
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn remote_errors() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1480");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(BoomServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1480"))
+                .await
+                .unwrap();
+            let err = AsyncServiceClient::new(0, &client)
+                .boom(String::from("kaboom"))
+                .await
+                .unwrap_err();
+            match err {
+                RPCError::RequestError(ref e) => {
+                    assert_eq!(e.kind, RPCRequestError::HandlerPanicked);
+                    assert_eq!(e.message, "kaboom");
+                }
+                _ => panic!("Expected handler panic, got {:?}", err),
+            }
+            assert_eq!(err.to_string(), "remote error: handler panicked: kaboom");
+            // The server survives the panic
+            let err = AsyncServiceClient::new(1, &client)
+                .boom(String::from("kaboom"))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.request_error(),
+                Some(RPCRequestError::ServiceIdNotFound)
+            );
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn overloaded() {
+            let _ = env_logger::try_init();
+            let options = ServerOptions {
+                max_concurrent_requests: 1,
+                ..ServerOptions::default()
+            };
+            let server = Server::new_with_options(&String::from("0.0.0.0:1580"), options);
+            server
+                .register_service(0, &Arc::new(BoomServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1580"))
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            // The second request arrives while the first one takes the only slot
+            let (first, second) = futures::join!(service_client.nap(500), async {
+                delay_for(Duration::from_millis(100)).await;
+                service_client.nap(0).await
+            });
+            assert!(first.is_ok());
+            assert_eq!(
+                second.unwrap_err().request_error(),
+                Some(RPCRequestError::Overloaded)
+            );
+            // Accepted again once the slot is free
+            assert!(service_client.nap(0).await.is_ok());
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod interceptors {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::interceptor::*;
+        use crate::rpc::{RPCClient, RPCError, RPCRequestError, RemoteError, Server};
+        use bytes::BytesMut;
+        use futures::prelude::*;
+        use parking_lot::Mutex;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering::Relaxed;
+
+        // Records peers and denies calls to service 1
+        struct Guard(Arc<Mutex<Vec<Option<String>>>>);
+
+        impl ServerInterceptor for Guard {
+            fn intercept<'a>(
+                &'a self,
+                call: &'a CallInfo,
+                data: BytesMut,
+                next: ServerNext<'a>,
+            ) -> BoxFuture<'a, Result<BytesMut, RemoteError>> {
+                self.0.lock().push(call.peer.clone());
+                if call.service_id == 1 {
+                    let denied = RemoteError::new(RPCRequestError::Other, "denied");
+                    return future::ready(Err(denied)).boxed();
+                }
+                next.run(call, data)
+            }
+        }
+
+        struct Counter(Arc<AtomicUsize>);
+
+        impl ClientInterceptor for Counter {
+            fn intercept<'a>(
+                &'a self,
+                call: &'a CallInfo,
+                data: BytesMut,
+                next: ClientNext<'a>,
+            ) -> BoxFuture<'a, Result<BytesMut, RPCError>> {
+                self.0.fetch_add(1, Relaxed);
+                next.run(call, data)
+            }
+        }
+
+        async fn hello(client: &Arc<RPCClient>, service_id: u64) -> Result<Respond, RPCError> {
+            AsyncServiceClient::new(service_id, client)
+                .hello(Greeting {
+                    name: String::from("Jack"),
+                    time: 12,
+                })
+                .await
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn intercept_calls() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1490");
+            let peers = Arc::new(Mutex::new(vec![]));
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            server
+                .register_service(1, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            server.add_interceptor(Guard(peers.clone()));
+            Server::listen_and_resume(&server).await;
+
+            // In-process calls are intercepted too
+            let local = RPCClient::new_async(&addr).await.unwrap();
+            assert_eq!(hello(&local, 0).await.unwrap().owner, 42);
+
+            let remote = RPCClient::new_async(&String::from("127.0.0.1:1490"))
+                .await
+                .unwrap();
+            let calls = Arc::new(AtomicUsize::new(0));
+            remote.add_interceptor(Counter(calls.clone()));
+            assert_eq!(hello(&remote, 0).await.unwrap().owner, 42);
+            match hello(&remote, 1).await {
+                Err(RPCError::RequestError(e)) => assert_eq!(e.message, "denied"),
+                res => panic!("Expected call to be denied, got {:?}", res),
+            }
+            assert_eq!(calls.load(Relaxed), 2);
+            let peers = peers.lock().clone();
+            assert_eq!(peers.len(), 3);
+            assert_eq!(peers[0], None);
+            assert!(peers[1].is_some());
+            assert!(get_local(server.network, server.server_id, 0)
+                .await
+                .is_none());
+
+            // Servers without interceptors keep the direct shortcut
+            let plain = Server::new(&String::from("0.0.0.0:1491"));
+            plain
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&plain).await;
+            assert!(get_local(plain.network, plain.server_id, 0).await.is_some());
+            plain.shutdown(Duration::from_secs(1)).await;
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod auth {
+        use super::struct_service::*;
+        use super::*;
+        use crate::rpc::auth::secret;
+        use crate::rpc::{RPCClient, RPCError, RPCRequestError, Server};
+        use crate::tcp::client::ClientOptions;
+        use crate::tcp::server::ServerOptions;
+
+        async fn hello(addr: &String, key: Option<&[u8]>) -> Result<Respond, RPCError> {
+            let options = ClientOptions {
+                secret: key.map(secret),
+                ..ClientOptions::default()
+            };
+            let client = RPCClient::new_async_with_options(addr, &options)
+                .await
+                .unwrap();
+            AsyncServiceClient::new(0, &client)
+                .hello(Greeting {
+                    name: String::from("Jack"),
+                    time: 12,
+                })
+                .await
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn signed_requests() {
+            let _ = env_logger::try_init();
+            let options = ServerOptions {
+                secret: Some(secret(b"cluster secret")),
+                ..ServerOptions::default()
+            };
+            let server = Server::new_with_options(&String::from("0.0.0.0:1520"), options);
+            server
+                .register_service(0, &Arc::new(HelloServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let addr = String::from("127.0.0.1:1520");
+            let res = hello(&addr, Some(b"cluster secret")).await;
+            assert_eq!(res.unwrap().owner, 42);
+            for key in &[None, Some(&b"guess"[..])] {
+                let err = hello(&addr, *key).await.unwrap_err();
+                assert_eq!(err.request_error(), Some(RPCRequestError::Unauthenticated));
+            }
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod deadlines {
+        use super::*;
+        use crate::rpc::{deadline, RPCClient, RPCError, Server};
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering::Relaxed;
+        use std::time::Instant;
+
+        service! {
+            rpc remaining(sleep_ms: u64) -> Option<u64>;
+        }
+
+        struct SleepyServer(AtomicUsize);
+
+        impl Service for SleepyServer {
+            fn remaining(&self, sleep_ms: u64) -> BoxFuture<Option<u64>> {
+                self.0.fetch_add(1, Relaxed);
+                async move {
+                    delay_for(Duration::from_millis(sleep_ms)).await;
+                    deadline::remaining().map(|r| r.as_millis() as u64)
+                }
+                .boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(SleepyServer);
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn propagate_deadlines() {
+            let _ = env_logger::try_init();
+            let service = Arc::new(SleepyServer(AtomicUsize::new(0)));
+            let server = Server::new(&String::from("0.0.0.0:1510"));
+            server.register_service(0, &service).await.unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1510"))
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            assert_eq!(service_client.remaining(0).await.unwrap(), None);
+            let remaining = service_client
+                .with_timeout(Duration::from_millis(500))
+                .remaining(0)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(remaining <= 500);
+            // Client gives up at the deadline, before the default timeout
+            let started = Instant::now();
+            let res = service_client
+                .with_timeout(Duration::from_millis(200))
+                .remaining(1000)
+                .await;
+            match res {
+                Err(RPCError::Timeout) => {}
+                res => panic!("Expected timeout, got {:?}", res),
+            }
+            assert!(started.elapsed() < Duration::from_millis(1000));
+            // Expired calls are not sent
+            let calls = service.0.load(Relaxed);
+            let res = deadline::with_deadline(Instant::now(), service_client.remaining(0)).await;
+            assert!(matches!(res, Err(RPCError::Timeout)));
+            assert_eq!(service.0.load(Relaxed), calls);
+            server.shutdown(Duration::from_secs(2)).await;
+        }
+    }
+
+    mod streams {
+        use super::*;
+        use crate::rpc::{RPCClient, Server};
+        use futures::stream::BoxStream;
+
+        service! {
+            rpc stream count(from: u64, to: u64) -> stream u64;
+            rpc upload sum(offset: u64; numbers: stream u64) -> u64;
+        }
+
+        struct CountServer;
+
+        impl Service for CountServer {
+            fn count(&self, from: u64, to: u64) -> BoxStream<'static, u64> {
+                futures::stream::iter(from..to).boxed()
+            }
+            fn sum(
+                &self,
+                offset: u64,
+                numbers: BoxStream<'static, u64>,
+            ) -> BoxFuture<'static, u64> {
+                numbers
+                    .fold(offset, |sum, n| future::ready(sum + n))
+                    .boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(CountServer);
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn stream_items() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1530");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(CountServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let remote = RPCClient::new_async(&String::from("127.0.0.1:1530"))
+                .await
+                .unwrap();
+            let local = RPCClient::new_async(&addr).await.unwrap();
+            for client in &[remote, local] {
+                let service_client = AsyncServiceClient::new(0, client);
+                // Takes several pulls
+                let numbers: Vec<u64> = service_client
+                    .count(0, 1000)
+                    .await
+                    .unwrap()
+                    .map(|n| n.unwrap())
+                    .collect()
+                    .await;
+                assert_eq!(numbers, (0..1000).collect::<Vec<_>>());
+                // Left before the end
+                let first: Vec<_> = service_client
+                    .count(0, 1000)
+                    .await
+                    .unwrap()
+                    .take(10)
+                    .collect()
+                    .await;
+                assert_eq!(first.len(), 10);
+                let sum = service_client
+                    .sum(5, futures::stream::iter(0..1000u64))
+                    .await
+                    .unwrap();
+                assert_eq!(sum, 5 + 999 * 1000 / 2);
+            }
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn drop_streams() {
+            let _ = env_logger::try_init();
+            let server = Server::new(&String::from("0.0.0.0:1531"));
+            server
+                .register_service(0, &Arc::new(CountServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1531"))
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            let mut numbers = service_client.count(0, 1000).await.unwrap();
+            let first: Vec<_> = numbers.by_ref().take(10).collect().await;
+            assert_eq!(first.len(), 10);
+            assert_eq!(server.streams.len(), 1);
+            // Kept while its connection is open
+            let handle = server.handle.lock().clone().unwrap();
+            server.streams.reap(|conn_id| handle.is_connected(conn_id));
+            assert_eq!(server.streams.len(), 1);
+            server.streams.reap(|_| false);
+            assert_eq!(server.streams.len(), 0);
+            let rest: Vec<_> = numbers.collect().await;
+            assert!(rest.len() < 990);
+            assert!(rest.last().unwrap().is_err());
+            // Dropped along with the server
+            let _numbers = service_client.count(0, 1000).await.unwrap();
+            assert_eq!(server.streams.len(), 1);
+            server.shutdown(Duration::from_secs(1)).await;
+            assert_eq!(server.streams.len(), 0);
+        }
+    }
+
+    mod oneway {
+        use super::*;
+        use crate::rpc::{RPCClient, Server};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        service! {
+            oneway rpc add(n: u64);
+            rpc total() -> u64;
+        }
+
+        struct CounterServer {
+            total: AtomicU64,
+        }
+
+        impl Service for CounterServer {
+            fn add(&self, n: u64) -> BoxFuture<()> {
+                async move {
+                    // Slower than the caller waits for
+                    delay_for(Duration::from_millis(200)).await;
+                    self.total.fetch_add(n, Ordering::SeqCst);
+                }
+                .boxed()
+            }
+            fn total(&self) -> BoxFuture<u64> {
+                future::ready(self.total.load(Ordering::SeqCst)).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(CounterServer);
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn oneway_calls() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1540");
+            let server = Server::new(&addr);
+            let service = Arc::new(CounterServer {
+                total: AtomicU64::new(0),
+            });
+            server.register_service(0, &service).await.unwrap();
+            Server::listen_and_resume(&server).await;
+            let remote = RPCClient::new_async(&String::from("127.0.0.1:1540"))
+                .await
+                .unwrap();
+            let local = RPCClient::new_async(&addr).await.unwrap();
+            let mut expected = 0;
+            for client in &[remote, local] {
+                let service_client = AsyncServiceClient::new(0, client);
+                service_client.add(1).await.unwrap();
+                service_client.add(2).await.unwrap();
+                // Returned before the handler is done
+                assert_eq!(service_client.total().await.unwrap(), expected);
+                expected += 3;
+                delay_for(Duration::from_millis(500)).await;
+                assert_eq!(service_client.total().await.unwrap(), expected);
+            }
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod batches {
+        use super::*;
+        use crate::rpc::{prepend_u64, RPCClient, RPCRequestError, Server};
+        use crate::utils::serde::{deserialize, serialize};
+        use bytes::BytesMut;
+        use std::pin::Pin;
+
+        service! {
+            rpc double(n: u64) -> u64;
+        }
+
+        struct DoubleServer;
+
+        impl Service for DoubleServer {
+            fn double(&self, n: u64) -> BoxFuture<u64> {
+                future::ready(n * 2).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(DoubleServer);
+
+        fn double_call(n: u64) -> BytesMut {
+            let args = BytesMut::from(serialize(&(n,)).as_slice());
+            prepend_u64(::bifrost_plugins::hash_ident!(double) as u64, args)
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn batch_calls() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1550");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(DoubleServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let remote = RPCClient::new_async(&String::from("127.0.0.1:1550"))
+                .await
+                .unwrap();
+            let local = RPCClient::new_async(&addr).await.unwrap();
+            for client in &[remote, local] {
+                // Split in several batches
+                let mut calls: Vec<_> = (0..300).map(|n| (0, double_call(n))).collect();
+                calls.push((1, double_call(300)));
+                let results = RPCClient::send_batch(Pin::new(&**client), calls)
+                    .await
+                    .unwrap();
+                assert_eq!(results.len(), 301);
+                for (n, res) in results.iter().take(300).enumerate() {
+                    let doubled: u64 = deserialize(res.as_ref().unwrap()).unwrap();
+                    assert_eq!(doubled, n as u64 * 2);
+                }
+                // Failed alone
+                assert_eq!(
+                    results[300].as_ref().unwrap_err().request_error(),
+                    Some(RPCRequestError::ServiceIdNotFound)
+                );
+            }
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod attribute_macros {
+        use super::*;
+        use crate::rpc::{RPCClient, Server};
+        use futures::stream::StreamExt;
+
+        // The same service declared with both macros, the client of each calls the server
+        // of the other
+        mod legacy {
+            use super::*;
+            use futures::stream::BoxStream;
+
+            service! {
+                rpc greet(name: String) -> String;
+                rpc stream count(to: u64) -> stream u64;
+            }
+
+            pub struct GreetServer;
+
+            impl Service for GreetServer {
+                fn greet(&self, name: String) -> BoxFuture<String> {
+                    future::ready(format!("Hello, {}!", name)).boxed()
+                }
+                fn count(&self, to: u64) -> BoxStream<'static, u64> {
+                    futures::stream::iter(0..to).boxed()
+                }
+            }
+            dispatch_rpc_service_functions!(GreetServer);
+        }
+
+        mod attr {
+            use futures::future::{self, BoxFuture, FutureExt};
+            use futures::stream::{BoxStream, StreamExt};
+
+            #[crate::macros::service]
+            pub trait Service {
+                /// Greets by name
+                fn greet(&self, name: String) -> String;
+                #[stream]
+                fn count(&self, to: u64) -> u64;
+                async fn add(&self, a: u64, b: u64) -> u64 {
+                    a + b
+                }
+            }
+
+            pub struct GreetServer;
+
+            impl Service for GreetServer {
+                fn greet(&self, name: String) -> BoxFuture<String> {
+                    future::ready(format!("Hello, {}!", name)).boxed()
+                }
+                fn count(&self, to: u64) -> BoxStream<'static, u64> {
+                    futures::stream::iter(0..to).boxed()
+                }
+            }
+            dispatch_rpc_service_functions!(GreetServer);
+        }
+
+        // Borrowed arguments and a type parameter on the trait
+        mod generic {
+            use futures::future::{self, BoxFuture, FutureExt};
+            use std::collections::HashMap;
+
+            #[crate::macros::service]
+            pub trait Store<V> {
+                fn get(&self, key: &str) -> Option<V>;
+                fn first<'k>(&self, keys: Vec<&'k str>) -> Option<V>;
+            }
+
+            pub struct StoreServer(pub HashMap<String, u64>);
+
+            impl Store<u64> for StoreServer {
+                fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<u64>> {
+                    future::ready(self.0.get(key).cloned()).boxed()
+                }
+                fn first<'a, 'k>(&'a self, keys: Vec<&'k str>) -> BoxFuture<'a, Option<u64>>
+                where
+                    'k: 'a,
+                {
+                    let found = keys.iter().find_map(|key| self.0.get(*key).cloned());
+                    future::ready(found).boxed()
+                }
+            }
+            dispatch_rpc_service_functions!(StoreServer);
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn generics_and_lifetimes() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1565");
+            let server = Server::new(&addr);
+            let values = vec![(String::from("a"), 1), (String::from("b"), 2)];
+            server
+                .register_service(
+                    0,
+                    &Arc::new(generic::StoreServer(values.into_iter().collect())),
+                )
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1565"))
+                .await
+                .unwrap();
+            let store = generic::AsyncServiceClient::<u64>::new(0, &client);
+            let key = String::from("b");
+            assert_eq!(store.get(&key).await.unwrap(), Some(2));
+            assert_eq!(store.get("c").await.unwrap(), None);
+            assert_eq!(store.first(vec!["c", "a", &key]).await.unwrap(), Some(1));
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn wire_compatible() {
+            let _ = env_logger::try_init();
+            let addr = String::from("0.0.0.0:1560");
+            let server = Server::new(&addr);
+            server
+                .register_service(0, &Arc::new(legacy::GreetServer))
+                .await
+                .unwrap();
+            server
+                .register_service(1, &Arc::new(attr::GreetServer))
+                .await
+                .unwrap();
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1560"))
+                .await
+                .unwrap();
+            let to_legacy = attr::AsyncServiceClient::new(0, &client);
+            let to_attr = legacy::AsyncServiceClient::new(1, &client);
+            assert_eq!(
+                to_legacy.greet(String::from("Jack")).await.unwrap(),
+                "Hello, Jack!"
+            );
+            assert_eq!(
+                to_attr.greet(String::from("Jill")).await.unwrap(),
+                "Hello, Jill!"
+            );
+            let numbers: Vec<u64> = to_legacy
+                .count(10)
+                .await
+                .unwrap()
+                .map(|n| n.unwrap())
+                .collect()
+                .await;
+            assert_eq!(numbers, (0..10).collect::<Vec<_>>());
+            let numbers: Vec<u64> = to_attr
+                .count(10)
+                .await
+                .unwrap()
+                .map(|n| n.unwrap())
+                .collect()
+                .await;
+            assert_eq!(numbers, (0..10).collect::<Vec<_>>());
+            // Default bodies, remotely and through the shortcut
+            let local = RPCClient::new_async(&addr).await.unwrap();
+            for client in &[client, local] {
+                let service_client = attr::AsyncServiceClient::new(1, client);
+                assert_eq!(service_client.add(1, 2).await.unwrap(), 3);
+            }
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
+
+    mod registry {
+        use super::*;
+        use crate::rpc::batch::BATCH_SERVICE_ID;
+        use crate::rpc::{RPCClient, RegisterError, Server};
+
+        service! {
+            rpc ping() -> u64;
+        }
+
+        struct PingServer(u64);
+
+        impl Service for PingServer {
+            fn ping(&self) -> BoxFuture<u64> {
+                future::ready(self.0).boxed()
+            }
+        }
+        dispatch_rpc_service_functions!(PingServer);
+
+        #[tokio::test(threaded_scheduler)]
+        pub async fn colliding_ids() {
+            let _ = env_logger::try_init();
+            let server = Server::new(&String::from("0.0.0.0:1570"));
+            server
+                .register_service(0, &Arc::new(PingServer(1)))
+                .await
+                .unwrap();
+            assert_eq!(
+                server.register_service(0, &Arc::new(PingServer(2))).await,
+                Err(RegisterError::Existed(0))
+            );
+            assert_eq!(
+                server
+                    .register_service(BATCH_SERVICE_ID, &Arc::new(PingServer(2)))
+                    .await,
+                Err(RegisterError::Reserved(BATCH_SERVICE_ID))
+            );
+            Server::listen_and_resume(&server).await;
+            let client = RPCClient::new_async(&String::from("127.0.0.1:1570"))
+                .await
+                .unwrap();
+            let service_client = AsyncServiceClient::new(0, &client);
+            // Not replaced
+            assert_eq!(service_client.ping().await.unwrap(), 1);
+            server.remove_service(0).await;
+            server
+                .register_service(0, &Arc::new(PingServer(2)))
+                .await
+                .unwrap();
+            assert_eq!(service_client.ping().await.unwrap(), 2);
+            server.shutdown(Duration::from_secs(1)).await;
+        }
+    }
 }
@@ -1,15 +1,15 @@
 #[macro_export]
 macro_rules! dispatch_rpc_service_functions {
     ($s:ty) => {
-        use $crate::bytes::BytesMut;
         impl $crate::rpc::RPCService for $s {
             fn dispatch<'a>(
                 &'a self,
-                data: BytesMut,
+                data: $crate::bytes::BytesMut,
             ) -> ::std::pin::Pin<
                 Box<
-                    dyn Future<Output = Result<$crate::bytes::BytesMut, $crate::rpc::RPCRequestError>>
-                        + Send
+                    dyn ::std::future::Future<
+                            Output = Result<$crate::bytes::BytesMut, $crate::rpc::RemoteError>,
+                        > + Send
                         + 'a,
                 >,
             >
@@ -21,15 +21,27 @@ macro_rules! dispatch_rpc_service_functions {
             fn register_shortcut_service(
                 &self,
                 service_ptr: usize,
+                network: $crate::tcp::NetworkId,
                 server_id: u64,
                 service_id: u64,
-            ) -> ::std::pin::Pin<Box<dyn Future<Output = ()> + Send>> {
-                async move {
+                intercepted: ::std::sync::Arc<::std::sync::atomic::AtomicBool>,
+            ) -> ::std::pin::Pin<Box<dyn ::std::future::Future<Output = ()> + Send>> {
+                $crate::macros::export::futures::FutureExt::boxed(async move {
                     let mut cbs = RPC_SVRS.write().await;
-                    let service = unsafe { Arc::from_raw(service_ptr as *const $s) };
-                    cbs.insert((server_id, service_id), service);
-                }
-                .boxed()
+                    let service = unsafe { ::std::sync::Arc::from_raw(service_ptr as *const $s) };
+                    cbs.insert((network, server_id, service_id), (service, intercepted));
+                })
+            }
+            fn unregister_shortcut_service(
+                &self,
+                network: $crate::tcp::NetworkId,
+                server_id: u64,
+                service_id: u64,
+            ) -> ::std::pin::Pin<Box<dyn ::std::future::Future<Output = ()> + Send>> {
+                $crate::macros::export::futures::FutureExt::boxed(async move {
+                    let mut cbs = RPC_SVRS.write().await;
+                    cbs.remove(&(network, server_id, service_id));
+                })
             }
         }
     };
@@ -39,61 +51,140 @@ macro_rules! dispatch_rpc_service_functions {
 #[macro_export]
 macro_rules! service {
     (
-        $(
+        {
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)*;
-        )*
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ); // No return, no error
+
+            $( $unexpanded:tt )*
+        }
+        [ $( $unary:tt )* ] $streams:tt $uploads:tt $oneways:tt
     ) => {
-        service! {{
-            $(
+        service! {
+            { $( $unexpanded )* }
+
+            [
+                $( $unary )*
+
                 $(#[$attr])*
-                rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)*;
-            )*
-        }}
+                rpc $fn_name( $( $arg : $in_ ),* ) -> ();
+            ]
+            $streams $uploads $oneways
+        }
     };
     (
         {
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ); // No return, no error
+            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
 
             $( $unexpanded:tt )*
         }
-        $( $expanded:tt )*
+        [ $( $unary:tt )* ] $streams:tt $uploads:tt $oneways:tt
     ) => {
         service! {
             { $( $unexpanded )* }
 
-            $( $expanded )*
+            [
+                $( $unary )*
 
-            $(#[$attr])*
-            rpc $fn_name( $( $arg : $in_ ),* ) -> ();
+                $(#[$attr])*
+                rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+            ]
+            $streams $uploads $oneways
         }
     };
     (
         {
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            rpc stream $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> stream $out:ty; // Server streaming
 
             $( $unexpanded:tt )*
         }
-        $( $expanded:tt )*
+        $unary:tt [ $( $streams:tt )* ] $uploads:tt $oneways:tt
     ) => {
         service! {
             { $( $unexpanded )* }
 
-            $( $expanded )*
+            $unary
+            [
+                $( $streams )*
 
-            $(#[$attr])*
-            rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+                $(#[$attr])*
+                $fn_name( $( $arg : $in_ ),* ) -> $out;
+            ]
+            $uploads $oneways
         }
     };
     (
-        {} // all expanded
-        $(
+        {
+            $(#[$attr:meta])*
+            rpc upload $fn_name:ident( $( $arg:ident : $in_:ty ),* ; $items:ident : stream $item:ty ) -> $out:ty; // Client streaming
+
+            $( $unexpanded:tt )*
+        }
+        $unary:tt $streams:tt [ $( $uploads:tt )* ] $oneways:tt
+    ) => {
+        service! {
+            { $( $unexpanded )* }
+
+            $unary $streams
+            [
+                $( $uploads )*
+
+                $(#[$attr])*
+                $fn_name( $( $arg : $in_ ),* ; $items : $item ) -> $out;
+            ]
+            $oneways
+        }
+    };
+    (
+        {
             $(#[$attr:meta])*
-            rpc $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
-        )*
+            oneway rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ); // No response
+
+            $( $unexpanded:tt )*
+        }
+        $unary:tt $streams:tt $uploads:tt [ $( $oneways:tt )* ]
+    ) => {
+        service! {
+            { $( $unexpanded )* }
+
+            $unary $streams $uploads
+            [
+                $( $oneways )*
+
+                $(#[$attr])*
+                $fn_name( $( $arg : $in_ ),* );
+            ]
+        }
+    };
+    (
+        {} // all expanded
+        [
+            $(
+                $(#[$attr:meta])*
+                rpc $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            )*
+        ]
+        [
+            $(
+                $(#[$s_attr:meta])*
+                $s_fn_name:ident ( $( $s_arg:ident : $s_in:ty ),* ) -> $s_out:ty;
+            )*
+        ]
+        [
+            $(
+                $(#[$u_attr:meta])*
+                $u_fn_name:ident ( $( $u_arg:ident : $u_in:ty ),* ; $u_items:ident : $u_item:ty ) -> $u_out:ty;
+            )*
+        ]
+        [
+            $(
+                $(#[$o_attr:meta])*
+                $o_fn_name:ident ( $( $o_arg:ident : $o_in:ty ),* );
+            )*
+        ]
     ) => {
+        ::bifrost_plugins::check_fn_ids!($($fn_name,)* $($s_fn_name,)* $($u_fn_name,)* $($o_fn_name,)*);
 
         use std::sync::Arc;
         use $crate::rpc::*;
@@ -102,8 +193,9 @@ macro_rules! service {
         use std::pin::Pin;
 
         lazy_static! {
+            // With the flag of their server telling if it has interceptors
             pub static ref RPC_SVRS:
-            async_std::sync::RwLock<::std::collections::BTreeMap<(u64, u64), Arc<dyn Service>>>
+            async_std::sync::RwLock<::std::collections::BTreeMap<($crate::tcp::NetworkId, u64, u64), (Arc<dyn Service>, Arc<::std::sync::atomic::AtomicBool>)>>
             = async_std::sync::RwLock::new(::std::collections::BTreeMap::new());
         }
 
@@ -112,7 +204,19 @@ macro_rules! service {
                 $(#[$attr])*
                 fn $fn_name<'a>(&'a self, $($arg:$in_),*) -> ::futures::future::BoxFuture<$out>;
            )*
-           fn inner_dispatch<'a>(&'a self, data: $crate::bytes::BytesMut) -> Pin<Box<dyn core::future::Future<Output = Result<$crate::bytes::BytesMut, RPCRequestError>> + Send + 'a>> {
+           $(
+                $(#[$s_attr])*
+                fn $s_fn_name(&self, $($s_arg:$s_in),*) -> ::futures::stream::BoxStream<'static, $s_out>;
+           )*
+           $(
+                $(#[$u_attr])*
+                fn $u_fn_name(&self, $($u_arg:$u_in,)* $u_items: ::futures::stream::BoxStream<'static, $u_item>) -> ::futures::future::BoxFuture<'static, $u_out>;
+           )*
+           $(
+                $(#[$o_attr])*
+                fn $o_fn_name<'a>(&'a self, $($o_arg:$o_in),*) -> ::futures::future::BoxFuture<()>;
+           )*
+           fn inner_dispatch<'a>(&'a self, data: $crate::bytes::BytesMut) -> Pin<Box<dyn core::future::Future<Output = Result<$crate::bytes::BytesMut, RemoteError>> + Send + 'a>> {
                let (func_id, body) = read_u64_head(data);
                async move {
                 match func_id as usize {
@@ -123,11 +227,55 @@ macro_rules! service {
                             let res_data = $crate::bytes::BytesMut::from($crate::utils::serde::serialize(&f_result).as_slice());
                             Ok(res_data)
                         } else {
-                            Err(RPCRequestError::BadRequest)
+                            Err(RemoteError::new(
+                                RPCRequestError::BadRequest,
+                                concat!("cannot decode arguments of ", stringify!($fn_name)),
+                            ))
                         }
                     }),*
+                    $(::bifrost_plugins::hash_ident!($s_fn_name) => {
+                        if let Some(data) = $crate::utils::serde::deserialize(body.as_ref()) {
+                            let ($($s_arg,)*) : ($($s_in,)*) = data;
+                            let stream_id = $crate::rpc::streaming::open_download(self.$s_fn_name($($s_arg,)*))?;
+                            Ok($crate::bytes::BytesMut::from($crate::utils::serde::serialize(&stream_id).as_slice()))
+                        } else {
+                            Err(RemoteError::new(
+                                RPCRequestError::BadRequest,
+                                concat!("cannot decode arguments of ", stringify!($s_fn_name)),
+                            ))
+                        }
+                    })*
+                    $(::bifrost_plugins::hash_ident!($u_fn_name) => {
+                        if let Some(data) = $crate::utils::serde::deserialize(body.as_ref()) {
+                            let ($($u_arg,)*) : ($($u_in,)*) = data;
+                            let stream_id = $crate::rpc::streaming::open_upload(
+                                move |$u_items| self.$u_fn_name($($u_arg,)* $u_items)
+                            )?;
+                            Ok($crate::bytes::BytesMut::from($crate::utils::serde::serialize(&stream_id).as_slice()))
+                        } else {
+                            Err(RemoteError::new(
+                                RPCRequestError::BadRequest,
+                                concat!("cannot decode arguments of ", stringify!($u_fn_name)),
+                            ))
+                        }
+                    })*
+                    $(::bifrost_plugins::hash_ident!($o_fn_name) => {
+                        if let Some(data) = $crate::utils::serde::deserialize(body.as_ref()) {
+                            let ($($o_arg,)*) : ($($o_in,)*) = data;
+                            self.$o_fn_name($($o_arg,)*).await;
+                            Ok($crate::bytes::BytesMut::from($crate::utils::serde::serialize(&()).as_slice()))
+                        } else {
+                            Err(RemoteError::new(
+                                RPCRequestError::BadRequest,
+                                concat!("cannot decode arguments of ", stringify!($o_fn_name)),
+                            ))
+                        }
+                    })*
                     _ => {
-                        Err(RPCRequestError::FunctionIdNotFound)
+                        Err(RemoteError::new(
+                            RPCRequestError::FunctionIdNotFound,
+                            format!("no function {}", func_id),
+                        ))
                     }
                 }
                }.boxed()
@@ -135,10 +283,11 @@ macro_rules! service {
         }
 
         #[allow(dead_code)]
-        pub async fn get_local(server_id: u64, service_id: u64) -> Option<Arc<dyn Service>> {
+        pub async fn get_local(network: $crate::tcp::NetworkId, server_id: u64, service_id: u64) -> Option<Arc<dyn Service>> {
             let svrs = RPC_SVRS.read().await;
-            match svrs.get(&(server_id, service_id)) {
-                Some(s) => Some(s.clone()),
+            match svrs.get(&(network, server_id, service_id)) {
+                // Calling the service object would skip the interceptors of its server
+                Some((s, intercepted)) if !intercepted.load(::std::sync::atomic::Ordering::Relaxed) => Some(s.clone()),
                 _ => None
             }
         }
@@ -147,6 +296,8 @@ macro_rules! service {
         pub struct AsyncServiceClient {
             pub service_id: u64,
             pub client: Arc<RPCClient>,
+            // Deadline of every call from this client, counted from when the call starts
+            pub timeout: Option<::std::time::Duration>,
         }
 
         #[allow(dead_code)]
@@ -155,13 +306,54 @@ macro_rules! service {
                 #[allow(non_camel_case_types)]
                 $(#[$attr])*
                 pub async fn $fn_name(&self, $($arg:$in_),*) -> Result<$out, RPCError> {
-                    ImmeServiceClient::$fn_name(self.service_id, &self.client, $($arg),*).await
+                    let call = ImmeServiceClient::$fn_name(self.service_id, &self.client, $($arg),*);
+                    match self.timeout {
+                        Some(timeout) => $crate::tcp::deadline::with_timeout(timeout, call).await,
+                        None => call.await
+                    }
+                }
+           )*
+           $(
+                $(#[$s_attr])*
+                pub async fn $s_fn_name(&self, $($s_arg:$s_in),*) -> Result<$crate::rpc::streaming::RPCStream<$s_out>, RPCError> {
+                    let call = ImmeServiceClient::$s_fn_name(self.service_id, &self.client, $($s_arg),*);
+                    match self.timeout {
+                        Some(timeout) => $crate::tcp::deadline::with_timeout(timeout, call).await,
+                        None => call.await
+                    }
+                }
+           )*
+           $(
+                $(#[$u_attr])*
+                pub async fn $u_fn_name<S>(&self, $($u_arg:$u_in,)* $u_items: S) -> Result<$u_out, RPCError>
+                where
+                    S: ::futures::stream::Stream<Item = $u_item> + Send + 'static
+                {
+                    let call = ImmeServiceClient::$u_fn_name(self.service_id, &self.client, $($u_arg,)* $u_items);
+                    match self.timeout {
+                        Some(timeout) => $crate::tcp::deadline::with_timeout(timeout, call).await,
+                        None => call.await
+                    }
+                }
+           )*
+           $(
+                $(#[$o_attr])*
+                pub async fn $o_fn_name(&self, $($o_arg:$o_in),*) -> Result<(), RPCError> {
+                    ImmeServiceClient::$o_fn_name(self.service_id, &self.client, $($o_arg),*).await
                 }
            )*
            pub fn new(service_id: u64, client: &Arc<RPCClient>) -> Arc<AsyncServiceClient> {
                 Arc::new(AsyncServiceClient{
                     service_id: service_id,
-                    client: client.clone()
+                    client: client.clone(),
+                    timeout: None
+                })
+           }
+           pub fn with_timeout(&self, timeout: ::std::time::Duration) -> Arc<AsyncServiceClient> {
+                Arc::new(AsyncServiceClient{
+                    service_id: self.service_id,
+                    client: self.client.clone(),
+                    timeout: Some(timeout)
                 })
            }
            pub fn server_id(&self) -> u64 {
@@ -175,16 +367,22 @@ macro_rules! service {
                 /// Judgement: Use data ownership transfer instead of borrowing.
                 /// Some applications highly depend on RPC shortcut to achieve performance advantages.
                 /// Cloning for shortcut will significantly increase overhead. Eg. Hivemind immutable queue
+                /// Calls in a `tcp::deadline` scope carry its deadline to the server.
                 pub async fn $fn_name(service_id: u64, client: &Arc<RPCClient>, $($arg:$in_),*) -> Result<$out, RPCError> {
-                    if let Some(ref local) = get_local(client.server_id, service_id).await {
+                    let local = if client.can_call_local() {
+                        get_local(client.network, client.server_id, service_id).await
+                    } else {
+                        None
+                    };
+                    if let Some(ref local) = local {
                         Ok(local.$fn_name($($arg),*).await)
                     } else {
                         let req_data = ($($arg,)*);
-                        let req_data_bytes = $crate::bytes::BytesMut::from($crate::utils::serde::serialize(&req_data).as_slice());
+                        let req_data_bytes = $crate::bytes::BytesMut::from(client.codec().serialize(&req_data).as_slice());
                         let req_bytes = prepend_u64(::bifrost_plugins::hash_ident!($fn_name) as u64, req_data_bytes);
                         let res_bytes = RPCClient::send_async(Pin::new(&*client), service_id, req_bytes).await;
                         if let Ok(res_bytes) = res_bytes {
-                            if let Some(data) = $crate::utils::serde::deserialize(&res_bytes) {
+                            if let Some(data) = client.codec().deserialize(&res_bytes) {
                                 Ok(data)
                             } else {
                                 Err(RPCError::ClientCannotDecodeResponse)
@@ -195,8 +393,91 @@ macro_rules! service {
                     }
                 }
            )*
+           $(
+                $(#[$s_attr])*
+                /// Items are pulled from the server as the returned stream is consumed
+                pub async fn $s_fn_name(service_id: u64, client: &Arc<RPCClient>, $($s_arg:$s_in),*) -> Result<$crate::rpc::streaming::RPCStream<$s_out>, RPCError> {
+                    let local = if client.can_call_local() {
+                        get_local(client.network, client.server_id, service_id).await
+                    } else {
+                        None
+                    };
+                    if let Some(ref local) = local {
+                        Ok($crate::rpc::streaming::RPCStream::local(local.$s_fn_name($($s_arg),*)))
+                    } else {
+                        let req_data = ($($s_arg,)*);
+                        let req_data_bytes = $crate::bytes::BytesMut::from(client.codec().serialize(&req_data).as_slice());
+                        let req_bytes = prepend_u64(::bifrost_plugins::hash_ident!($s_fn_name) as u64, req_data_bytes);
+                        let res_bytes = RPCClient::send_async(Pin::new(&*client), service_id, req_bytes).await?;
+                        match client.codec().deserialize(&res_bytes) {
+                            Some(stream_id) => Ok($crate::rpc::streaming::RPCStream::remote(client.clone(), stream_id)),
+                            None => Err(RPCError::ClientCannotDecodeResponse)
+                        }
+                    }
+                }
+           )*
+           $(
+                $(#[$u_attr])*
+                /// Items are pushed to the server as fast as its handler takes them
+                pub async fn $u_fn_name<S>(service_id: u64, client: &Arc<RPCClient>, $($u_arg:$u_in,)* $u_items: S) -> Result<$u_out, RPCError>
+                where
+                    S: ::futures::stream::Stream<Item = $u_item> + Send + 'static
+                {
+                    let local = if client.can_call_local() {
+                        get_local(client.network, client.server_id, service_id).await
+                    } else {
+                        None
+                    };
+                    if let Some(ref local) = local {
+                        Ok(local.$u_fn_name($($u_arg,)* ::futures::stream::StreamExt::boxed($u_items)).await)
+                    } else {
+                        let req_data = ($($u_arg,)*);
+                        let req_data_bytes = $crate::bytes::BytesMut::from(client.codec().serialize(&req_data).as_slice());
+                        let req_bytes = prepend_u64(::bifrost_plugins::hash_ident!($u_fn_name) as u64, req_data_bytes);
+                        let res_bytes = RPCClient::send_async(Pin::new(&*client), service_id, req_bytes).await?;
+                        let stream_id: u64 = match client.codec().deserialize(&res_bytes) {
+                            Some(stream_id) => stream_id,
+                            None => return Err(RPCError::ClientCannotDecodeResponse)
+                        };
+                        let res_bytes = $crate::rpc::streaming::upload(client, stream_id, $u_items).await?;
+                        match client.codec().deserialize(&res_bytes) {
+                            Some(data) => Ok(data),
+                            None => Err(RPCError::ClientCannotDecodeResponse)
+                        }
+                    }
+                }
+           )*
+           $(
+                $(#[$o_attr])*
+                /// Returns once the request is sent, without waiting for the server to handle it.
+                /// Errors of the handler never get back to the caller.
+                pub async fn $o_fn_name(service_id: u64, client: &Arc<RPCClient>, $($o_arg:$o_in),*) -> Result<(), RPCError> {
+                    let local = if client.can_call_local() {
+                        get_local(client.network, client.server_id, service_id).await
+                    } else {
+                        None
+                    };
+                    if let Some(local) = local {
+                        ::tokio::spawn(async move { local.$o_fn_name($($o_arg),*).await });
+                        Ok(())
+                    } else {
+                        let req_data = ($($o_arg,)*);
+                        let req_data_bytes = $crate::bytes::BytesMut::from(client.codec().serialize(&req_data).as_slice());
+                        let req_bytes = prepend_u64(::bifrost_plugins::hash_ident!($o_fn_name) as u64, req_data_bytes);
+                        RPCClient::send_oneway(Pin::new(&*client), service_id, req_bytes).await
+                    }
+                }
+           )*
         }
-    }
+    };
+    (
+        $( $body:tt )*
+    ) => {
+        service! {
+            { $( $body )* }
+            [] [] [] []
+        }
+    };
 }
 
 mod syntax_test {
@@ -204,6 +485,10 @@ mod syntax_test {
         rpc test(a: u32, b: u32) -> bool;
         rpc test2(a: u32);
         rpc test3(a: u32, b: u32, c: u32, d: u32);
+        rpc stream test4(a: u32) -> stream u64;
+        rpc upload test5(a: u32; items: stream String) -> bool;
+        rpc upload test6(; items: stream u8) -> ();
+        oneway rpc test7(a: u32, b: String);
     }
 }
 
@@ -0,0 +1,197 @@
+// Retries of failed calls, with exponential backoff and jitter. Only errors that leave the
+// request unhandled are retried by default, calls that may have run on the server, timed
+// out or lost their connection after being written are not, since they could run twice.
+use super::{deadline, RPCError, RPCRequestError};
+use std::cmp::min;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+pub type Retryable = Arc<dyn Fn(&RPCError) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    // Attempts of a call, including the first one
+    pub max_attempts: u32,
+    // Wait before the second attempt, doubled for every further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Whether an error is worth another attempt, `is_retryable` when not set
+    pub retryable: Option<Retryable>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retryable: None,
+        }
+    }
+}
+
+// Requests that were never written, and servers refusing the request for now
+pub fn is_retryable(error: &RPCError) -> bool {
+    match error {
+        RPCError::IOError(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::NotConnected
+        ),
+        // The breaker is there to fail fast, waiting for it to close is up to the caller
+        RPCError::CircuitOpen => false,
+        RPCError::RequestError(e) => matches!(
+            e.kind,
+            RPCRequestError::Overloaded | RPCRequestError::ShuttingDown
+        ),
+        _ => false,
+    }
+}
+
+impl RetryPolicy {
+    pub fn should_retry(&self, error: &RPCError) -> bool {
+        match self.retryable {
+            Some(ref retryable) => retryable(error),
+            None => is_retryable(error),
+        }
+    }
+
+    // Wait after the failed attempt, counted from 1. Randomized between half and all of
+    // the exponential backoff, so callers failing together do not retry together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(0);
+        let backoff = match self.initial_backoff.checked_mul(factor) {
+            Some(backoff) if factor > 0 => min(backoff, self.max_backoff),
+            _ => self.max_backoff,
+        };
+        let half = backoff / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    // Run the call until it succeeds, fails with an error not worth retrying, runs out
+    // of attempts or would retry past the deadline of the current scope
+    pub async fn run<F, Fut, T>(&self, call: F) -> Result<T, RPCError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RPCError>>,
+    {
+        self.run_with(|e| self.should_retry(e), call).await
+    }
+
+    // Like `run`, for calls failing with errors of their own, retried when `retryable`
+    // returns true for them
+    pub async fn run_with<R, F, Fut, T, E>(&self, retryable: R, mut call: F) -> Result<T, E>
+    where
+        R: Fn(&E) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: fmt::Debug,
+    {
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Err(e) if attempt < self.max_attempts && retryable(&e) => {
+                    let backoff = self.backoff(attempt);
+                    if deadline::remaining().map_or(false, |remaining| remaining <= backoff) {
+                        return Err(e);
+                    }
+                    debug!(
+                        "Attempt {} failed with {:?}, retrying in {:?}",
+                        attempt, e, backoff
+                    );
+                    delay_for(backoff).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::RemoteError;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(30),
+            retryable: None,
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = policy();
+        for (attempt, full) in &[(1, 10), (2, 20), (3, 30), (10, 30), (40, 30)] {
+            let backoff = policy.backoff(*attempt);
+            assert!(backoff >= Duration::from_millis(full / 2));
+            assert!(backoff <= Duration::from_millis(*full));
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn retry_calls() {
+        let policy = policy();
+        let attempts = &AtomicU32::new(0);
+        // Succeeds on the third attempt
+        let res = policy
+            .run(move || async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(RPCError::IOError(io::ErrorKind::ConnectionRefused.into())),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(res.unwrap(), 2);
+        // Out of attempts
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(move || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(RPCError::IOError(io::ErrorKind::NotConnected.into()))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        // Not retried
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(move || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(RPCError::RequestError(RemoteError::new(
+                    RPCRequestError::BadRequest,
+                    "",
+                )))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        // Lost with the connection after it was written, may have run
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(move || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(RPCError::IOError(io::ErrorKind::ConnectionAborted.into()))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        // Open breakers fail fast
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<(), _> = policy
+            .run(move || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(RPCError::CircuitOpen)
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,616 @@
+// Streaming calls, declared in `service!` as
+//   rpc stream scan(from: u64) -> stream Entry;
+//   rpc upload store(name: String; chunks: stream Vec<u8>) -> u64;
+//
+// The call itself opens a stream on the server and answers its id. Items then travel in
+// batches over plain requests to `STREAM_SERVICE_ID`, so streams share the connection with
+// other calls and go through the shortcut, interceptors and authentication like them.
+// Flow control is by credit: clients pull at most `DEFAULT_CREDIT` items at a time from
+// the server, and servers accept pushed items only as fast as the handler takes them.
+// Streams belong to the server and the connection they were opened on, they are dropped
+// when that connection closes, when they sit idle, and when the server shuts down.
+use super::{
+    prepend_u64, read_u64_head, RPCClient, RPCError, RPCRequestError, RPCService, RemoteError,
+};
+use crate::tcp::server::{connection_id, ServerHandle};
+use crate::tcp::{deadline, NetworkId};
+use crate::utils::serde::codec;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, AbortHandle, Abortable, BoxFuture, Shared};
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use parking_lot::Mutex as SyncMutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::{delay_for, timeout};
+
+// Reserved service id for moving stream items, next to `auth::AUTH_SERVICE_ID`
+pub const STREAM_SERVICE_ID: u64 = ::std::u64::MAX - 2;
+// Items in flight per stream
+pub const DEFAULT_CREDIT: usize = 64;
+// Batches stop growing past this many bytes, to stay well within the frame limit
+pub const MAX_BATCH_BYTES: usize = 1024 * 1024;
+// Servers hold a pull or push at most this long before answering with what they have,
+// below the default client timeout
+const WAIT: Duration = Duration::from_millis(500);
+// Streams nobody pulled from or pushed to for this long are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// How often servers look for idle streams and streams of closed connections
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+// Uploads give up waiting for the handler after this long, unless the call has a deadline
+const FINISH_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FN_PULL: u64 = 1;
+const FN_PUSH: u64 = 2;
+const FN_FINISH: u64 = 3;
+const FN_CLOSE: u64 = 4;
+
+type Items = Vec<Vec<u8>>;
+
+enum Kind {
+    Download(Arc<async_std::sync::Mutex<BoxStream<'static, Vec<u8>>>>),
+    Upload {
+        // Dropped on finish, which ends the stream of the handler
+        sender: Option<mpsc::Sender<Vec<u8>>>,
+        result: Shared<oneshot::Receiver<Vec<u8>>>,
+        abort: AbortHandle,
+    },
+}
+
+struct Entry {
+    kind: Kind,
+    touched: Instant,
+    // Connection the stream was opened on, `None` through the shortcut
+    conn_id: Option<u64>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if let Kind::Upload { ref abort, .. } = self.kind {
+            abort.abort();
+        }
+    }
+}
+
+// Open streams of a server
+#[derive(Default)]
+pub(crate) struct Streams {
+    entries: SyncMutex<HashMap<u64, Entry>>,
+}
+
+tokio::task_local! {
+    // Streams of the server handling the request
+    static STREAMS: Arc<Streams>;
+}
+
+lazy_static! {
+    static ref SERVICE: Arc<StreamService> = Arc::new(StreamService);
+}
+
+impl Streams {
+    fn register(&self, kind: Kind) -> u64 {
+        let mut entries = self.entries.lock();
+        let mut id = rand::random::<u64>();
+        while entries.contains_key(&id) {
+            id = rand::random::<u64>();
+        }
+        let entry = Entry {
+            kind,
+            touched: Instant::now(),
+            conn_id: connection_id(),
+        };
+        entries.insert(id, entry);
+        id
+    }
+
+    // Run `f` on the stream if it was opened on the connection of the request
+    fn with<R, F>(&self, id: u64, f: F) -> Result<R, RemoteError>
+    where
+        F: FnOnce(&mut Kind) -> Option<R>,
+    {
+        let mut entries = self.entries.lock();
+        match entries.get_mut(&id) {
+            Some(entry) if entry.conn_id == connection_id() => {
+                entry.touched = Instant::now();
+                f(&mut entry.kind).ok_or_else(|| no_stream(id))
+            }
+            _ => Err(no_stream(id)),
+        }
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        self.entries.lock().remove(&id).is_some()
+    }
+
+    // Drop streams idle for too long, and the ones of connections that are gone
+    pub(crate) fn reap<C>(&self, connected: C)
+    where
+        C: Fn(u64) -> bool,
+    {
+        let now = Instant::now();
+        self.entries.lock().retain(|id, entry| {
+            let alive = now.duration_since(entry.touched) < IDLE_TIMEOUT
+                && entry.conn_id.map_or(true, &connected);
+            if !alive {
+                debug!("Dropping stream {}", id);
+            }
+            alive
+        });
+    }
+
+    pub(crate) fn clear(&self) {
+        let entries: Vec<_> = self.entries.lock().drain().collect();
+        debug!("Dropping {} streams", entries.len());
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+}
+
+// Reap the streams of the server every `REAP_INTERVAL`, until the server is gone
+pub(crate) async fn reap_streams(streams: Weak<Streams>, handle: Arc<ServerHandle>) {
+    loop {
+        delay_for(REAP_INTERVAL).await;
+        match streams.upgrade() {
+            Some(streams) => streams.reap(|conn_id| handle.is_connected(conn_id)),
+            None => return,
+        }
+    }
+}
+
+// Run a request of the server owning `streams`
+pub(crate) async fn with_streams<F: Future>(streams: Arc<Streams>, f: F) -> F::Output {
+    STREAMS.scope(streams, f).await
+}
+
+// Streams of the server handling the request
+fn streams() -> Result<Arc<Streams>, RemoteError> {
+    STREAMS.try_with(|streams| streams.clone()).map_err(|_| {
+        RemoteError::new(
+            RPCRequestError::Other,
+            "streams only work in requests to a server",
+        )
+    })
+}
+
+// Serve the items of a stream returned by a handler, returns the stream id for the client
+pub fn open_download<T>(stream: BoxStream<'static, T>) -> Result<u64, RemoteError>
+where
+    T: Serialize + Send + 'static,
+{
+    // Items are encoded as they are pulled, with the codec of the call opening the stream
+    let codec = codec();
+    let items = stream.map(move |item| codec.serialize(&item)).boxed();
+    let streams = streams()?;
+    Ok(streams.register(Kind::Download(Arc::new(async_std::sync::Mutex::new(items)))))
+}
+
+// Run a handler taking a stream of uploaded items, returns the stream id for the client
+pub fn open_upload<T, R, F>(handler: F) -> Result<u64, RemoteError>
+where
+    T: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static,
+    F: FnOnce(BoxStream<'static, T>) -> BoxFuture<'static, R>,
+{
+    let streams = streams()?;
+    let codec = codec();
+    let (sender, receiver) = mpsc::channel(DEFAULT_CREDIT);
+    let items = receiver
+        .filter_map(move |item: Vec<u8>| future::ready(codec.deserialize(&item)))
+        .boxed();
+    let (result_tx, result_rx) = oneshot::channel();
+    let (abort, abort_reg) = AbortHandle::new_pair();
+    let handling = handler(items);
+    // A panicking handler drops the result sender, which is reported on finish
+    tokio::spawn(Abortable::new(
+        async move {
+            let _ = result_tx.send(codec.serialize(&handling.await));
+        },
+        abort_reg,
+    ));
+    Ok(streams.register(Kind::Upload {
+        sender: Some(sender),
+        result: result_rx.shared(),
+        abort,
+    }))
+}
+
+fn no_stream(id: u64) -> RemoteError {
+    RemoteError::new(RPCRequestError::BadRequest, format!("no stream {}", id))
+}
+
+fn bad_request() -> RemoteError {
+    RemoteError::new(RPCRequestError::BadRequest, "malformed stream request")
+}
+
+// [count u32]([length u32][item])*
+fn put_items(buf: &mut BytesMut, items: &[Vec<u8>]) {
+    buf.put_u32_le(items.len() as u32);
+    for item in items {
+        buf.put_u32_le(item.len() as u32);
+        buf.put_slice(item);
+    }
+}
+
+fn get_items(buf: &mut BytesMut) -> Option<Items> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let count = buf.get_u32_le() as usize;
+    let mut items = Vec::with_capacity(count.min(DEFAULT_CREDIT));
+    for _ in 0..count {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = buf.get_u32_le() as usize;
+        if buf.len() < len {
+            return None;
+        }
+        items.push(buf.split_to(len).to_vec());
+    }
+    Some(items)
+}
+
+fn get_u64(buf: &mut BytesMut) -> Result<u64, RemoteError> {
+    if buf.len() < 8 {
+        return Err(bad_request());
+    }
+    Ok(buf.get_u64_le())
+}
+
+// Take up to `credit` items, waiting a while for the first one.
+// Answers [end u8][items].
+async fn pull(streams: &Streams, id: u64, credit: usize) -> Result<BytesMut, RemoteError> {
+    let stream = streams.with(id, |kind| match kind {
+        Kind::Download(stream) => Some(stream.clone()),
+        _ => None,
+    })?;
+    let mut stream = stream.lock().await;
+    let mut items = vec![];
+    let mut end = false;
+    match timeout(WAIT, stream.next()).await {
+        Err(_) => {}
+        Ok(None) => end = true,
+        Ok(Some(item)) => {
+            let mut bytes = item.len();
+            items.push(item);
+            while items.len() < credit && bytes < MAX_BATCH_BYTES {
+                match stream.next().now_or_never() {
+                    Some(Some(item)) => {
+                        bytes += item.len();
+                        items.push(item);
+                    }
+                    Some(None) => {
+                        end = true;
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    if end {
+        streams.remove(id);
+    }
+    let mut res = BytesMut::new();
+    res.put_u8(end as u8);
+    put_items(&mut res, &items);
+    Ok(res)
+}
+
+// Hand items to the handler as long as it keeps up.
+// Answers [accepted u32][closed u8], closed when the handler stopped taking items.
+async fn push(streams: &Streams, id: u64, items: Items) -> Result<BytesMut, RemoteError> {
+    let sender = streams.with(id, |kind| match kind {
+        Kind::Upload { sender, .. } => Some(sender.clone()),
+        _ => None,
+    })?;
+    let mut accepted = 0u32;
+    let mut closed = sender.is_none();
+    if let Some(mut sender) = sender {
+        let until = Instant::now() + WAIT;
+        for item in items {
+            let wait = until.saturating_duration_since(Instant::now());
+            match timeout(wait, sender.send(item)).await {
+                Ok(Ok(())) => accepted += 1,
+                Ok(Err(_)) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    let mut res = BytesMut::new();
+    res.put_u32_le(accepted);
+    res.put_u8(closed as u8);
+    Ok(res)
+}
+
+// End the uploaded stream and wait a while for the handler.
+// Answers [ready u8][serialized result].
+async fn finish(streams: &Streams, id: u64) -> Result<BytesMut, RemoteError> {
+    let result = streams.with(id, |kind| match kind {
+        Kind::Upload { sender, result, .. } => {
+            sender.take();
+            Some(result.clone())
+        }
+        _ => None,
+    })?;
+    let mut res = BytesMut::new();
+    match timeout(WAIT, result).await {
+        Ok(Ok(result)) => {
+            streams.remove(id);
+            res.put_u8(1);
+            res.put_slice(&result);
+        }
+        Ok(Err(_)) => {
+            streams.remove(id);
+            return Err(RemoteError::new(
+                RPCRequestError::HandlerPanicked,
+                format!("upload handler of stream {} failed", id),
+            ));
+        }
+        Err(_) => res.put_u8(0),
+    }
+    Ok(res)
+}
+
+// Dropping an upload aborts its handler
+fn close(streams: &Streams, id: u64) {
+    if streams.with(id, |_| Some(())).is_ok() && streams.remove(id) {
+        trace!("Closing stream {}", id);
+    }
+}
+
+pub(crate) fn service() -> Arc<dyn RPCService> {
+    SERVICE.clone()
+}
+
+struct StreamService;
+
+impl RPCService for StreamService {
+    fn dispatch(&self, data: BytesMut) -> BoxFuture<Result<BytesMut, RemoteError>> {
+        async move {
+            if data.len() < 16 {
+                return Err(bad_request());
+            }
+            let (func_id, mut body) = read_u64_head(data);
+            let id = get_u64(&mut body)?;
+            let streams = streams()?;
+            match func_id {
+                FN_PULL if body.len() >= 4 => pull(&streams, id, body.get_u32_le() as usize).await,
+                FN_PUSH => {
+                    let items = get_items(&mut body).ok_or_else(bad_request)?;
+                    push(&streams, id, items).await
+                }
+                FN_FINISH => finish(&streams, id).await,
+                FN_CLOSE => {
+                    close(&streams, id);
+                    Ok(BytesMut::new())
+                }
+                _ => Err(bad_request()),
+            }
+        }
+        .boxed()
+    }
+    fn register_shortcut_service(
+        &self,
+        _service_ptr: usize,
+        _network: NetworkId,
+        _server_id: u64,
+        _service_id: u64,
+        _intercepted: Arc<AtomicBool>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        future::ready(()).boxed()
+    }
+    fn unregister_shortcut_service(
+        &self,
+        _network: NetworkId,
+        _server_id: u64,
+        _service_id: u64,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        future::ready(()).boxed()
+    }
+}
+
+fn request(func_id: u64, id: u64) -> BytesMut {
+    let mut body = BytesMut::new();
+    body.put_u64_le(id);
+    prepend_u64(func_id, body)
+}
+
+async fn call(client: &Arc<RPCClient>, req: BytesMut) -> Result<BytesMut, RPCError> {
+    RPCClient::send_async(Pin::new(&**client), STREAM_SERVICE_ID, req).await
+}
+
+fn malformed() -> RPCError {
+    RPCError::ClientCannotDecodeResponse
+}
+
+// Stream of a streaming call, items are pulled from the server as it is consumed.
+// Dropping it before the end closes the stream on the server.
+pub struct RPCStream<T> {
+    inner: BoxStream<'static, Result<T, RPCError>>,
+}
+
+// Closes the remote stream unless it was read to the end
+struct Remote {
+    client: Arc<RPCClient>,
+    id: u64,
+    buffer: VecDeque<Vec<u8>>,
+    // Server has sent the last item and dropped the stream
+    end: bool,
+    // Streams end after their first error
+    failed: bool,
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        if !self.end {
+            let client = self.client.clone();
+            let req = request(FN_CLOSE, self.id);
+            tokio::spawn(async move {
+                let _ = call(&client, req).await;
+            });
+        }
+    }
+}
+
+impl Remote {
+    async fn next(&mut self) -> Option<Result<Vec<u8>, RPCError>> {
+        loop {
+            if self.failed {
+                return None;
+            }
+            if let Some(item) = self.buffer.pop_front() {
+                return Some(Ok(item));
+            }
+            if self.end {
+                return None;
+            }
+            let mut req = request(FN_PULL, self.id);
+            req.put_u32_le(DEFAULT_CREDIT as u32);
+            let mut res = match call(&self.client, req).await {
+                Ok(res) if !res.is_empty() => res,
+                Ok(_) => return Some(Err(self.fail(malformed()))),
+                Err(e) => return Some(Err(self.fail(e))),
+            };
+            let end = res.get_u8() != 0;
+            match get_items(&mut res) {
+                Some(items) => self.buffer.extend(items),
+                None => return Some(Err(self.fail(malformed()))),
+            }
+            self.end = end;
+        }
+    }
+
+    fn fail(&mut self, e: RPCError) -> RPCError {
+        self.failed = true;
+        e
+    }
+}
+
+impl<T> RPCStream<T>
+where
+    T: Send + 'static,
+{
+    // Items straight from the handler, for services in this process
+    pub fn local(stream: BoxStream<'static, T>) -> Self {
+        Self {
+            inner: stream.map(Ok).boxed(),
+        }
+    }
+
+    pub fn remote(client: Arc<RPCClient>, id: u64) -> Self
+    where
+        T: DeserializeOwned,
+    {
+        let codec = client.codec();
+        let remote = Remote {
+            client,
+            id,
+            buffer: VecDeque::new(),
+            end: false,
+            failed: false,
+        };
+        let inner = stream::unfold(remote, |mut remote| async move {
+            let item = match remote.next().await {
+                Some(Ok(item)) => codec
+                    .deserialize(&item)
+                    .ok_or_else(|| remote.fail(malformed())),
+                Some(Err(e)) => Err(e),
+                None => return None,
+            };
+            Some((item, remote))
+        })
+        .boxed();
+        Self { inner }
+    }
+}
+
+impl<T> Stream for RPCStream<T> {
+    type Item = Result<T, RPCError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+// Push the items of an upload opened on the server and return the serialized result of
+// its handler
+pub async fn upload<S>(client: &Arc<RPCClient>, id: u64, items: S) -> Result<BytesMut, RPCError>
+where
+    S: Stream + Send,
+    S::Item: Serialize,
+{
+    futures::pin_mut!(items);
+    let codec = client.codec();
+    let mut pending: VecDeque<Vec<u8>> = VecDeque::new();
+    let mut ended = false;
+    loop {
+        if pending.is_empty() && !ended {
+            match items.next().await {
+                Some(item) => pending.push_back(codec.serialize(&item)),
+                None => ended = true,
+            }
+        }
+        let mut bytes: usize = pending.iter().map(|item| item.len()).sum();
+        while !ended && pending.len() < DEFAULT_CREDIT && bytes < MAX_BATCH_BYTES {
+            match items.next().now_or_never() {
+                Some(Some(item)) => {
+                    let item = codec.serialize(&item);
+                    bytes += item.len();
+                    pending.push_back(item);
+                }
+                Some(None) => ended = true,
+                None => break,
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
+        let batch: Items = pending.iter().cloned().collect();
+        let mut req = request(FN_PUSH, id);
+        put_items(&mut req, &batch);
+        let mut res = call(client, req).await?;
+        if res.len() < 5 {
+            return Err(malformed());
+        }
+        let accepted = res.get_u32_le() as usize;
+        let closed = res.get_u8() != 0;
+        pending.drain(..accepted.min(pending.len()));
+        if closed {
+            debug!("Upload handler of stream {} stopped taking items", id);
+            break;
+        }
+    }
+    // Wait for the handler until the deadline of the call
+    let until = deadline::deadline().unwrap_or_else(|| Instant::now() + FINISH_TIMEOUT);
+    loop {
+        let mut res = call(client, request(FN_FINISH, id)).await?;
+        if res.is_empty() {
+            return Err(malformed());
+        }
+        if res.get_u8() != 0 {
+            return Ok(res);
+        }
+        if Instant::now() >= until {
+            debug!("Upload handler of stream {} did not finish in time", id);
+            // Out of the scope of the expired deadline
+            let client = client.clone();
+            tokio::spawn(async move {
+                let _ = call(&client, request(FN_CLOSE, id)).await;
+            });
+            return Err(RPCError::Timeout);
+        }
+    }
+}
@@ -1,47 +1,434 @@
+use std::cmp::min;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::tcp::{shortcut, STANDALONE_ADDRESS};
+use crate::rpc::auth::{self, Secret};
+use crate::tcp::{shortcut, NetworkId, DEFAULT_NETWORK, STANDALONE_ADDRESS};
 use crate::DISABLE_SHORTCUT;
 use bifrost_hasher::hash_str;
 
+use crate::tcp::faults::{self, Action};
+use crate::tcp::frame::{self, Compression};
+use crate::tcp::handshake::{self, Handshake};
 use crate::tcp::server::TcpReq;
+use crate::tcp::tls::ClientTls;
+use crate::tcp::{
+    codec, deadline, limit_exceeded, unix_socket_path, BoxedStream, Limit, Transport,
+    DEFAULT_MAX_FRAME_LENGTH,
+};
+use crate::utils::serde::{default_codec, Codec};
 use async_std::sync::Mutex;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::{AbortHandle, Abortable, BoxFuture};
 use futures::prelude::*;
-use futures::stream::SplitSink;
+use futures::stream::{SplitSink, SplitStream};
 use futures::SinkExt;
 use parking_lot::Mutex as SyncMutex;
 use std::collections::HashMap;
-use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU64, AtomicU8};
 use tokio::io;
-use tokio::net::TcpStream;
+use tokio::net::{self, TcpStream, UnixStream};
 use tokio::sync::oneshot;
 use tokio::time;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::Framed;
+
+const INITIAL_RECONNECT_BACKOFF_MS: u64 = 100;
+
+type Writer = SplitSink<Transport, Bytes>;
+type Reader = SplitStream<Transport>;
+type ResSender = oneshot::Sender<io::Result<BytesMut>>;
+pub type UnreachableCallback = Arc<dyn Fn(&String) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ClientOptions {
+    pub timeout: Duration,
+    pub tls: Option<ClientTls>,
+    // Attempts to reestablish a broken connection before the client is closed for good
+    pub reconnect_attempts: u32,
+    pub max_reconnect_backoff: Duration,
+    // Frames larger than this are refused on both directions of the connection
+    pub max_frame_length: usize,
+    // Requests waiting for response, new requests fail fast beyond this
+    pub max_in_flight: usize,
+    // Compression to ask the server for. Servers that don't support it will leave the
+    // connection uncompressed.
+    pub compression: Compression,
+    // Payloads smaller than this are always sent as is
+    pub compression_threshold: usize,
+    // Server id of the node making connections, sent to the server in the handshake.
+    // 0 if this node does not run a server.
+    pub node_id: u64,
+    // Server address of the node making connections, which faults injected on its links
+    // are keyed by. Not set if this node does not run a server.
+    pub sender_address: Option<String>,
+    // Ping the server when nothing was received for this long
+    pub ping_interval: Duration,
+    // Connection is considered dead when nothing was received for this long, pings included
+    pub idle_timeout: Duration,
+    // Called with the server address when an established connection is lost
+    pub on_unreachable: Option<UnreachableCallback>,
+    pub network: NetworkId,
+    // RPC requests are signed with this secret, defaults to the cluster secret
+    pub secret: Option<Secret>,
+    // Encoding of RPC arguments and results, the server must use the same one
+    pub codec: &'static dyn Codec,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            tls: None,
+            reconnect_attempts: 10,
+            max_reconnect_backoff: Duration::from_secs(5),
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            max_in_flight: 1024,
+            compression: Compression::None,
+            compression_threshold: 1024,
+            node_id: 0,
+            sender_address: None,
+            ping_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(15),
+            on_unreachable: None,
+            network: DEFAULT_NETWORK,
+            secret: auth::cluster_secret(),
+            codec: default_codec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+    Closed,
+}
+
+impl ConnectionState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => ConnectionState::Connected,
+            1 => ConnectionState::Reconnecting,
+            _ => ConnectionState::Closed,
+        }
+    }
+    fn to_u8(self) -> u8 {
+        match self {
+            ConnectionState::Connected => 0,
+            ConnectionState::Reconnecting => 1,
+            ConnectionState::Closed => 2,
+        }
+    }
+}
+
+struct Connection {
+    address: String,
+    options: ClientOptions,
+    writer: Mutex<Option<Writer>>,
+    senders: SyncMutex<HashMap<u64, ResSender>>,
+    reader_abort: SyncMutex<Option<AbortHandle>>,
+    state: AtomicU8,
+    // Compression negotiated with the server, can change across reconnects
+    compression: AtomicU8,
+    // Handshake features both sides understand, can also change across reconnects
+    features: AtomicU8,
+}
 
 pub struct Client {
-    //client: Option<SplitSink<Bytes>>,
-    client: Option<Mutex<SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>>>,
+    conn: Option<Arc<Connection>>,
     msg_counter: AtomicU64,
-    senders: Arc<SyncMutex<HashMap<u64, oneshot::Sender<BytesMut>>>>,
     timeout: Duration,
+    // Sending end of the links faults are looked up for, 0 for any sender
+    sender: u64,
+    pub network: NetworkId,
     pub server_id: u64,
 }
 
+// Host names are looked up on every attempt, so reconnects follow a peer that came back
+// behind another IP. Resolved addresses are tried in order.
+async fn connect_tcp(address: &String) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in net::lookup_host(address.as_str()).await? {
+        trace!("Resolved {} to {}", address, addr);
+        match TcpStream::connect(addr).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                debug!("Cannot connect to {} at {}: {}", address, addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolved to no address", address),
+        )
+    }))
+}
+
+async fn open_transport(
+    address: &String,
+    options: &ClientOptions,
+) -> io::Result<(Transport, Compression, u8)> {
+    let timeout = options.timeout;
+    debug!("Create socket on {}", address);
+    let socket: BoxedStream = match unix_socket_path(address) {
+        Some(path) => Box::new(time::timeout(timeout, UnixStream::connect(path)).await??),
+        None => Box::new(time::timeout(timeout, connect_tcp(address)).await??),
+    };
+    let stream = match &options.tls {
+        Some(tls) => time::timeout(timeout, tls.connect(socket)).await??,
+        None => socket,
+    };
+    let mut transport = Framed::new(stream, codec(options.max_frame_length));
+    let (compression, features) =
+        time::timeout(timeout, handshake(&mut transport, options)).await??;
+    Ok((transport, compression, features))
+}
+
+// Returns the compression and features the server accepted
+async fn handshake(
+    transport: &mut Transport,
+    options: &ClientOptions,
+) -> io::Result<(Compression, u8)> {
+    let local = Handshake::local(options.node_id, options.compression, options.codec);
+    let req = frame::control_frame(0, frame::CONTROL_HANDSHAKE, &local.encode());
+    transport.send(req.freeze()).await?;
+    match transport.next().await {
+        Some(Ok(mut res)) if res.len() >= 8 => {
+            let (_, flags) = frame::split_msg_id(res.get_u64_le());
+            if flags & frame::FLAG_CONTROL != 0
+                && frame::read_control(&mut res) == Some(frame::CONTROL_HANDSHAKE)
+            {
+                let remote = Handshake::decode(res.as_ref())?;
+                local.check(&remote)?;
+                trace!("Handshake accepted by server {}", remote.server_id);
+                Ok((remote.compression, remote.features & handshake::FEATURES))
+            } else {
+                // Server predates control frames and answered it as a request
+                Ok((Compression::None, 0))
+            }
+        }
+        Some(Ok(_)) => Ok((Compression::None, 0)),
+        Some(Err(e)) => Err(e),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed during handshake",
+        )),
+    }
+}
+
+impl Connection {
+    fn state(&self) -> ConnectionState {
+        ConnectionState::from_u8(self.state.load(Relaxed))
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.state.store(state.to_u8(), Relaxed);
+    }
+
+    fn compression(&self) -> Compression {
+        Compression::from_id(self.compression.load(Relaxed))
+    }
+
+    fn has_feature(&self, feature: u8) -> bool {
+        self.features.load(Relaxed) & feature != 0
+    }
+
+    fn listen(self: &Arc<Self>, mut reader: Reader) {
+        let conn = self.clone();
+        let (abort_handle, abort_reg) = AbortHandle::new_pair();
+        *self.reader_abort.lock() = Some(abort_handle);
+        debug!("Streaming messages for {}", self.address);
+        tokio::spawn(Abortable::new(
+            async move {
+                let mut last_received = Instant::now();
+                loop {
+                    let res = match time::timeout(conn.options.ping_interval, reader.next()).await {
+                        Ok(Some(res)) => res,
+                        Ok(None) => break,
+                        Err(_) => {
+                            if last_received.elapsed() >= conn.options.idle_timeout {
+                                warn!(
+                                    "Nothing received from {} in {}ms, consider it dead",
+                                    conn.address,
+                                    last_received.elapsed().as_millis()
+                                );
+                                break;
+                            }
+                            conn.ping().await;
+                            continue;
+                        }
+                    };
+                    match res {
+                        Ok(mut data) => {
+                            last_received = Instant::now();
+                            let (res_msg_id, flags) = frame::split_msg_id(data.get_u64_le());
+                            if flags & frame::FLAG_CONTROL != 0 {
+                                trace!("Received control frame from {}", conn.address);
+                                continue;
+                            }
+                            trace!("Received msg for {}, size {}", res_msg_id, data.len());
+                            let sender = conn.senders.lock().remove(&res_msg_id);
+                            match sender {
+                                Some(sender) => {
+                                    let max_length = conn.options.max_frame_length;
+                                    let _ =
+                                        sender.send(frame::decode_payload(flags, data, max_length));
+                                }
+                                None => warn!(
+                                    "Received msg {} from {} with no pending request",
+                                    res_msg_id, conn.address
+                                ),
+                            }
+                        }
+                        Err(e) => {
+                            error!("Error on decoding from {}, error = {:?}", conn.address, e);
+                            break;
+                        }
+                    }
+                }
+                debug!("Stream from TCP server {} broken", conn.address);
+                conn.reconnect().await;
+            },
+            abort_reg,
+        ));
+    }
+
+    async fn ping(&self) {
+        let ping = frame::control_frame(0, frame::CONTROL_PING, &[]);
+        let mut writer = self.writer.lock().await;
+        if let Some(writer) = &mut *writer {
+            trace!("Ping {}", self.address);
+            match time::timeout(self.options.timeout, writer.send(ping.freeze())).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Cannot ping {}, error {:?}", self.address, e),
+                Err(_) => debug!("Ping {} timeout", self.address),
+            }
+        }
+    }
+
+    // Forget a request the caller stopped waiting for, and let the server abort it
+    fn cancel(self: &Arc<Self>, msg_id: u64) {
+        if self.senders.lock().remove(&msg_id).is_none()
+            || !self.has_feature(handshake::FEATURE_CANCEL)
+            || self.state() != ConnectionState::Connected
+        {
+            return;
+        }
+        let conn = self.clone();
+        tokio::spawn(async move {
+            let cancel = frame::control_frame(0, frame::CONTROL_CANCEL, &msg_id.to_le_bytes());
+            let mut writer = conn.writer.lock().await;
+            if let Some(writer) = &mut *writer {
+                trace!("Cancel msg {} on {}", msg_id, conn.address);
+                match time::timeout(conn.options.timeout, writer.send(cancel.freeze())).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("Cannot cancel on {}, error {:?}", conn.address, e),
+                    Err(_) => debug!("Cancel on {} timeout", conn.address),
+                }
+            }
+        });
+    }
+
+    fn fail_pending(&self, kind: io::ErrorKind, reason: &'static str) {
+        let senders: Vec<_> = self.senders.lock().drain().collect();
+        if !senders.is_empty() {
+            debug!(
+                "Failing {} pending requests to {}: {}",
+                senders.len(),
+                self.address,
+                reason
+            );
+        }
+        for (_, sender) in senders {
+            let _ = sender.send(Err(io::Error::new(kind, reason)));
+        }
+    }
+
+    fn reconnect(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        async move {
+            if self.state() == ConnectionState::Closed {
+                return;
+            }
+            self.set_state(ConnectionState::Reconnecting);
+            if let Some(ref on_unreachable) = self.options.on_unreachable {
+                on_unreachable(&self.address);
+            }
+            *self.writer.lock().await = None;
+            self.fail_pending(io::ErrorKind::ConnectionAborted, "connection broken");
+            let mut backoff = Duration::from_millis(INITIAL_RECONNECT_BACKOFF_MS);
+            for attempt in 0..self.options.reconnect_attempts {
+                time::delay_for(backoff).await;
+                if self.state() == ConnectionState::Closed {
+                    return;
+                }
+                match open_transport(&self.address, &self.options).await {
+                    Ok((transport, compression, features)) => {
+                        let (writer, reader) = transport.split();
+                        self.compression.store(compression.id(), Relaxed);
+                        self.features.store(features, Relaxed);
+                        *self.writer.lock().await = Some(writer);
+                        self.set_state(ConnectionState::Connected);
+                        self.listen(reader);
+                        info!(
+                            "Reconnected to {} after {} attempts",
+                            self.address,
+                            attempt + 1
+                        );
+                        return;
+                    }
+                    Err(e) => debug!(
+                        "Reconnect to {} failed, attempt {}, error {:?}",
+                        self.address, attempt, e
+                    ),
+                }
+                backoff = min(backoff * 2, self.options.max_reconnect_backoff);
+            }
+            warn!("Giving up reconnecting to {}", self.address);
+            self.set_state(ConnectionState::Closed);
+        }
+        .boxed()
+    }
+
+    fn close(&self) {
+        self.set_state(ConnectionState::Closed);
+        if let Some(abort_handle) = self.reader_abort.lock().take() {
+            abort_handle.abort();
+        }
+        // Not `NotConnected`, which tells requests that were never sent
+        self.fail_pending(io::ErrorKind::ConnectionAborted, "client closed");
+    }
+}
+
 impl Client {
     pub async fn connect_with_timeout(address: &String, timeout: Duration) -> io::Result<Self> {
+        let options = ClientOptions {
+            timeout,
+            ..ClientOptions::default()
+        };
+        Client::connect_with_options(address, &options).await
+    }
+    pub async fn connect_with_options(
+        address: &String,
+        options: &ClientOptions,
+    ) -> io::Result<Self> {
+        let timeout = options.timeout;
         let server_id = hash_str(address);
-        let senders = Arc::new(SyncMutex::new(HashMap::new()));
         debug!(
             "TCP connect to {}, server id {}, timeout {}ms",
             address,
             server_id,
             timeout.as_millis()
         );
-        let client = {
-            if !DISABLE_SHORTCUT && shortcut::is_local(server_id).await {
+        let network = options.network;
+        let conn = {
+            // Virtual networks can only be reached through the shortcut
+            let virtual_network = network != DEFAULT_NETWORK;
+            if (virtual_network || !DISABLE_SHORTCUT)
+                && shortcut::is_local(network, server_id).await
+            {
                 debug!("Local connection, using shortcut");
                 None
             } else {
@@ -51,59 +438,410 @@ impl Client {
                         "STANDALONE server is not found",
                     ));
                 }
-                debug!("Create socket on {}", address);
-                let socket = time::timeout(timeout, TcpStream::connect(address)).await??;
-                let transport = Framed::new(socket, LengthDelimitedCodec::new());
-                let (writer, mut reader) = transport.split();
-                let cloned_senders = senders.clone();
-                debug!("Streaming messages for {}", address);
-                let address = address.clone();
-                tokio::spawn(async move {
-                    while let Some(res) = reader.next().await {
-                        if let Ok(mut data) = res {
-                            let res_msg_id = data.get_u64_le();
-                            trace!("Received msg for {}, size {}", res_msg_id, data.len());
-                            let sender: oneshot::Sender<BytesMut> =
-                                cloned_senders.lock().remove(&res_msg_id).unwrap();
-                            sender.send(data).unwrap();
-                        }
-                    }
-                    debug!("Stream from TCP server {} broken", address);
+                if virtual_network {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("server {} is not found in network {}", address, network),
+                    ));
+                }
+                let (transport, compression, features) = open_transport(address, options).await?;
+                let (writer, reader) = transport.split();
+                let conn = Arc::new(Connection {
+                    address: address.clone(),
+                    options: options.clone(),
+                    writer: Mutex::new(Some(writer)),
+                    senders: SyncMutex::new(HashMap::new()),
+                    reader_abort: SyncMutex::new(None),
+                    state: AtomicU8::new(ConnectionState::Connected.to_u8()),
+                    compression: AtomicU8::new(compression.id()),
+                    features: AtomicU8::new(features),
                 });
-                Some(Mutex::new(writer))
+                conn.listen(reader);
+                Some(conn)
             }
         };
         Ok(Client {
-            client,
+            conn,
             server_id,
-            senders,
             timeout,
+            sender: options.sender_address.as_deref().map_or(0, faults::node_id),
+            network,
             msg_counter: AtomicU64::new(0),
         })
     }
     pub async fn connect(address: &String) -> io::Result<Self> {
-        Client::connect_with_timeout(address, Duration::from_secs(2)).await
+        Client::connect_with_options(address, &ClientOptions::default()).await
     }
     pub async fn send_msg(&self, msg: TcpReq) -> io::Result<BytesMut> {
-        if let Some(ref transport) = self.client {
-            let msg_id = self.msg_counter.fetch_add(1, Relaxed);
-            let mut frame = BytesMut::with_capacity(8 + msg.len());
+        match faults::action(self.network, self.sender, self.server_id) {
+            None => self.send_once(msg).await,
+            Some(Action::Refuse) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "link is partitioned",
+            )),
+            Some(Action::Drop) => {
+                time::delay_for(self.timeout).await;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "request dropped"))
+            }
+            Some(Action::Deliver { delay, duplicate }) => {
+                time::delay_for(delay).await;
+                if duplicate {
+                    let (res, _) = futures::join!(self.send_once(msg.clone()), self.send_once(msg));
+                    res
+                } else {
+                    self.send_once(msg).await
+                }
+            }
+        }
+    }
+    // Send a request nobody waits for the response of. Returns once the frame is written,
+    // or handed to the server in this process.
+    pub async fn send_oneway(&self, msg: TcpReq) -> io::Result<()> {
+        match faults::action(self.network, self.sender, self.server_id) {
+            None => self.send_oneway_once(msg).await,
+            Some(Action::Refuse) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "link is partitioned",
+            )),
+            Some(Action::Drop) => Ok(()),
+            Some(Action::Deliver { delay, duplicate }) => {
+                time::delay_for(delay).await;
+                if duplicate {
+                    self.send_oneway_once(msg.clone()).await?;
+                }
+                self.send_oneway_once(msg).await
+            }
+        }
+    }
+    async fn send_oneway_once(&self, msg: TcpReq) -> io::Result<()> {
+        match self.conn {
+            Some(ref conn) if !conn.has_feature(handshake::FEATURE_ONEWAY) => {
+                // Server answers anyway, wait for it to keep the connection in step
+                self.send_once(msg).await.map(|_| ())
+            }
+            Some(ref conn) => {
+                if conn.state() != ConnectionState::Connected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!("connection to {} is {:?}", conn.address, conn.state()),
+                    ));
+                }
+                let msg_id = self.msg_counter.fetch_add(1, Relaxed) & frame::MSG_ID_MASK;
+                let mut req = BytesMut::with_capacity(8 + msg.len());
+                req.put_u64_le(0);
+                let flags = frame::FLAG_ONEWAY
+                    | frame::encode_payload(
+                        conn.compression(),
+                        conn.options.compression_threshold,
+                        msg.as_ref(),
+                        &mut req,
+                    );
+                req[..8].copy_from_slice(&frame::join_msg_id(msg_id, flags).to_le_bytes());
+                if req.len() > conn.options.max_frame_length {
+                    return Err(limit_exceeded(Limit::FrameLength));
+                }
+                trace!("Sending oneway msg {}, size {}", msg_id, req.len());
+                let mut writer = conn.writer.lock().await;
+                match &mut *writer {
+                    Some(writer) => time::timeout(self.timeout, writer.send(req.freeze()))
+                        .await
+                        .map_err(io::Error::from)
+                        .and_then(|r| r),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "connection is reconnecting",
+                    )),
+                }
+            }
+            None => {
+                let (network, server_id) = (self.network, self.server_id);
+                if !shortcut::is_local(network, server_id).await {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Cannot found callback for shortcut",
+                    ));
+                }
+                tokio::spawn(async move {
+                    if let Err(e) = shortcut::call(network, server_id, msg).await {
+                        debug!("Oneway shortcut call failed, {:?}", e);
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+    async fn send_once(&self, msg: TcpReq) -> io::Result<BytesMut> {
+        // Waits as long as the deadline of the call when there is one
+        let remaining = deadline::remaining();
+        if remaining == Some(Duration::from_secs(0)) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "deadline exceeded before sending",
+            ));
+        }
+        let timeout = remaining.unwrap_or(self.timeout);
+        if let Some(ref conn) = self.conn {
+            if conn.state() != ConnectionState::Connected {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("connection to {} is {:?}", conn.address, conn.state()),
+                ));
+            }
+            let msg_id = self.msg_counter.fetch_add(1, Relaxed) & frame::MSG_ID_MASK;
+            let mut req = BytesMut::with_capacity(8 + 4 + msg.len());
+            // Header is filled after the payload, when flags are known
+            req.put_u64_le(0);
+            let mut flags = 0;
+            if let Some(remaining) = remaining {
+                if conn.has_feature(handshake::FEATURE_DEADLINE) {
+                    req.put_u32_le(deadline::encode(remaining));
+                    flags |= frame::FLAG_DEADLINE;
+                }
+            }
+            flags |= frame::encode_payload(
+                conn.compression(),
+                conn.options.compression_threshold,
+                msg.as_ref(),
+                &mut req,
+            );
+            req[..8].copy_from_slice(&frame::join_msg_id(msg_id, flags).to_le_bytes());
+            if req.len() > conn.options.max_frame_length {
+                return Err(limit_exceeded(Limit::FrameLength));
+            }
             let rx = {
-                frame.put_u64_le(msg_id);
-                frame.extend_from_slice(msg.as_ref());
                 let (tx, rx) = oneshot::channel();
-                let mut senders = self.senders.lock();
+                let mut senders = conn.senders.lock();
+                if senders.len() >= conn.options.max_in_flight {
+                    return Err(limit_exceeded(Limit::InFlightRequests));
+                }
                 senders.insert(msg_id, tx);
                 rx
             };
-            trace!("Sending msg {}, size {}", msg_id, frame.len());
-            time::timeout(self.timeout, transport.lock().await.send(frame.freeze())).await??;
+            trace!("Sending msg {}, size {}", msg_id, req.len());
+            let sent = {
+                let mut writer = conn.writer.lock().await;
+                match &mut *writer {
+                    Some(writer) => time::timeout(timeout, writer.send(req.freeze()))
+                        .await
+                        .map_err(io::Error::from)
+                        .and_then(|r| r),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "connection is reconnecting",
+                    )),
+                }
+            };
+            if let Err(e) = sent {
+                conn.senders.lock().remove(&msg_id);
+                return Err(e);
+            }
             trace!("Sent msg {}", msg_id);
-            Ok(time::timeout(self.timeout, rx).await?.unwrap())
+            // Cancels the request on timeout, or when this future is dropped before the response
+            let mut pending = Pending {
+                conn,
+                msg_id,
+                answered: false,
+            };
+            match time::timeout(timeout, rx).await {
+                Ok(Ok(res)) => {
+                    pending.answered = true;
+                    res
+                }
+                Ok(Err(_)) => {
+                    pending.answered = true;
+                    Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection dropped the request",
+                    ))
+                }
+                Err(e) => Err(e.into()),
+            }
         } else {
-            Ok(shortcut::call(self.server_id, msg).await?)
+            Ok(shortcut::call(self.network, self.server_id, msg).await?)
+        }
+    }
+    pub fn state(&self) -> ConnectionState {
+        match self.conn {
+            Some(ref conn) => conn.state(),
+            None => ConnectionState::Connected,
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        self.state() == ConnectionState::Connected
+    }
+    // Compression negotiated with the server for the current connection
+    pub fn compression(&self) -> Compression {
+        match self.conn {
+            Some(ref conn) => conn.compression(),
+            None => Compression::None,
+        }
+    }
+}
+
+struct Pending<'a> {
+    conn: &'a Arc<Connection>,
+    msg_id: u64,
+    answered: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.conn.cancel(self.msg_id);
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(ref conn) = self.conn {
+            conn.close();
         }
     }
 }
 
 unsafe impl Send for Client {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::exceeded_limit;
+    use crate::tcp::server::{Server, ServerOptions};
+    use crate::utils::serde::SERIALIZER_JSON;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::LengthDelimitedCodec;
+
+    // Accept a connection and answer its handshake as a server using `serializer`
+    async fn accept(
+        listener: &mut TcpListener,
+        serializer: u8,
+    ) -> Framed<TcpStream, LengthDelimitedCodec> {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut transport = Framed::new(socket, codec(DEFAULT_MAX_FRAME_LENGTH));
+        let mut req = transport.next().await.unwrap().unwrap();
+        let (msg_id, _) = frame::split_msg_id(req.get_u64_le());
+        assert_eq!(
+            frame::read_control(&mut req),
+            Some(frame::CONTROL_HANDSHAKE)
+        );
+        let remote = Handshake {
+            serializer,
+            ..Handshake::decode(req.as_ref()).unwrap()
+        };
+        let res = frame::control_frame(msg_id, frame::CONTROL_HANDSHAKE, &remote.encode());
+        transport.send(res.freeze()).await.unwrap();
+        transport
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn fail_pending_and_reconnect() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1910");
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let (client, mut transport) = futures::join!(
+            Client::connect_with_timeout(&addr, Duration::from_secs(10)),
+            accept(&mut listener, default_codec().id())
+        );
+        let client = client.unwrap();
+        let started = Instant::now();
+        let (res, _) = futures::join!(client.send_msg(BytesMut::from(&b"hello"[..])), async {
+            // Read the request and drop the connection without responding
+            transport.next().await;
+            drop(transport);
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!client.is_connected());
+        let _transport = accept(&mut listener, default_codec().id()).await;
+        time::delay_for(Duration::from_millis(500)).await;
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn request_limits() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1911");
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let options = ClientOptions {
+            max_frame_length: 64,
+            max_in_flight: 1,
+            ..ClientOptions::default()
+        };
+        let (client, _transport) = futures::join!(
+            Client::connect_with_options(&addr, &options),
+            accept(&mut listener, default_codec().id())
+        );
+        let client = client.unwrap();
+        let res = client.send_msg(BytesMut::from(&[0u8; 128][..])).await;
+        assert_eq!(exceeded_limit(&res.unwrap_err()), Some(Limit::FrameLength));
+        // The server never responds, the first request occupies the only slot
+        let (_, res) = futures::join!(client.send_msg(BytesMut::from(&b"first"[..])), async {
+            time::delay_for(Duration::from_millis(100)).await;
+            client.send_msg(BytesMut::from(&b"second"[..])).await
+        });
+        assert_eq!(
+            exceeded_limit(&res.unwrap_err()),
+            Some(Limit::InFlightRequests)
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn handshake_mismatch() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1912");
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        // Pretend to be a node set to another codec
+        let (res, _) = futures::join!(
+            Client::connect(&addr),
+            accept(&mut listener, SERIALIZER_JSON)
+        );
+        let err = res.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("serializer mismatch"));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn dead_peer_detection() {
+        let _ = env_logger::try_init();
+        let options = ClientOptions {
+            ping_interval: Duration::from_millis(100),
+            idle_timeout: Duration::from_millis(300),
+            ..ClientOptions::default()
+        };
+        let server = Server::start(
+            &String::from("0.0.0.0:1913"),
+            Arc::new(|data: TcpReq| future::ready(data).boxed()),
+            &ServerOptions::default(),
+        )
+        .await
+        .unwrap();
+        let alive = Client::connect_with_options(&String::from("127.0.0.1:1913"), &options)
+            .await
+            .unwrap();
+
+        let unreachable = Arc::new(SyncMutex::new(None));
+        let addr = String::from("127.0.0.1:1914");
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let options = ClientOptions {
+            on_unreachable: {
+                let unreachable = unreachable.clone();
+                Some(Arc::new(move |address: &String| {
+                    *unreachable.lock() = Some(address.clone())
+                }))
+            },
+            ..options
+        };
+        // Answers the handshake, but never the pings
+        let (dead, _transport) = futures::join!(
+            Client::connect_with_options(&addr, &options),
+            accept(&mut listener, default_codec().id())
+        );
+        let dead = dead.unwrap();
+
+        time::delay_for(Duration::from_secs(1)).await;
+        assert!(alive.is_connected());
+        assert!(!dead.is_connected());
+        assert_eq!(*unreachable.lock(), Some(addr));
+        server.close(Duration::from_secs(1)).await;
+    }
+}
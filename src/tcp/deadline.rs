@@ -0,0 +1,51 @@
+// Deadlines of requests. Calls made in a deadline scope carry the time left to the server,
+// which skips requests that expired before they are dispatched and runs the rest in a scope
+// with the same deadline. Handlers can look it up, and calls they make inherit it.
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static DEADLINE: Option<Instant>;
+}
+
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok().flatten()
+}
+
+// Time left before the deadline of the current call, zero once expired
+pub fn remaining() -> Option<Duration> {
+    deadline().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+pub fn expired() -> bool {
+    remaining() == Some(Duration::from_secs(0))
+}
+
+// Run `f` with the deadline, or the one of the enclosing scope if that is earlier
+pub async fn with_deadline<F: Future>(deadline: Instant, f: F) -> F::Output {
+    let deadline = match self::deadline() {
+        Some(current) if current < deadline => current,
+        _ => deadline,
+    };
+    DEADLINE.scope(Some(deadline), f).await
+}
+
+pub async fn with_timeout<F: Future>(timeout: Duration, f: F) -> F::Output {
+    with_deadline(Instant::now() + timeout, f).await
+}
+
+// Scope of a request received with the time left from the client
+pub(crate) async fn with_remaining<F: Future>(remaining: Option<Duration>, f: F) -> F::Output {
+    DEADLINE
+        .scope(remaining.map(|remaining| Instant::now() + remaining), f)
+        .await
+}
+
+// Time left as carried in frames, in milliseconds
+pub(crate) fn encode(remaining: Duration) -> u32 {
+    remaining.as_millis().min(u32::MAX as u128) as u32
+}
+
+pub(crate) fn decode(remaining: u32) -> Duration {
+    Duration::from_millis(remaining as u64)
+}
@@ -0,0 +1,243 @@
+use crate::tcp::NetworkId;
+use bifrost_hasher::hash_str;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+// Fault injection for links between nodes, for testing clusters running in one process.
+// Links are identified by the server address of the sending node, taken from
+// `ClientOptions::sender_address`, and the address the client dialed. Raft members and
+// membership heartbeats set the sender address to the one of their server. Faults apply to requests sent by
+// `tcp::client::Client`, including those going through the shortcut.
+// Every network has its own faults, so clusters running side by side in their own
+// `rpc::Network` don't affect each other.
+
+// Matches every sender, including clients without a node id
+pub const ANY_NODE: &'static str = "*";
+
+#[derive(Debug, Clone, Default)]
+pub struct Fault {
+    // Requests are refused right away
+    pub partitioned: bool,
+    // Added to every request
+    pub latency: Duration,
+    // Random delay up to this on top of `latency`, reorders concurrent requests
+    pub jitter: Duration,
+    // Chance of a request to be lost, the caller will time out
+    pub drop_rate: f64,
+    // Chance of a request to be delivered twice
+    pub duplicate_rate: f64,
+}
+
+// What should happen to a single request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Refuse,
+    Drop,
+    Deliver { delay: Duration, duplicate: bool },
+}
+
+type Links = HashMap<(u64, u64), Fault>;
+
+lazy_static! {
+    static ref FAULTS: RwLock<HashMap<NetworkId, Links>> = RwLock::new(HashMap::new());
+}
+
+// Networks with faults, skips the lock for the common case of no faults at all
+static FAULTY_NETWORKS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn node_id(address: &str) -> u64 {
+    if address == ANY_NODE {
+        0
+    } else {
+        hash_str(&String::from(address))
+    }
+}
+
+fn update<F>(network: NetworkId, from: &str, to: &str, f: F)
+where
+    F: FnOnce(&mut Fault),
+{
+    let mut faults = FAULTS.write();
+    let links = faults.entry(network).or_insert_with(|| {
+        FAULTY_NETWORKS.fetch_add(1, Relaxed);
+        HashMap::new()
+    });
+    f(links.entry((node_id(from), node_id(to))).or_default());
+}
+
+// Cut all links between nodes of the two groups, in both directions
+pub fn partition(network: NetworkId, group_a: &[&str], group_b: &[&str]) {
+    for a in group_a {
+        for b in group_b {
+            update(network, a, b, |fault| fault.partitioned = true);
+            update(network, b, a, |fault| fault.partitioned = true);
+        }
+    }
+}
+
+pub fn add_latency(network: NetworkId, from: &str, to: &str, latency: Duration, jitter: Duration) {
+    update(network, from, to, |fault| {
+        fault.latency = latency;
+        fault.jitter = jitter;
+    });
+}
+
+pub fn drop_requests(network: NetworkId, from: &str, to: &str, rate: f64) {
+    update(network, from, to, |fault| fault.drop_rate = rate);
+}
+
+pub fn duplicate_requests(network: NetworkId, from: &str, to: &str, rate: f64) {
+    update(network, from, to, |fault| fault.duplicate_rate = rate);
+}
+
+pub fn set_fault(network: NetworkId, from: &str, to: &str, fault: Fault) {
+    update(network, from, to, |f| *f = fault);
+}
+
+// Remove faults of a single link
+pub fn heal_link(network: NetworkId, from: &str, to: &str) {
+    let mut faults = FAULTS.write();
+    let healed = match faults.get_mut(&network) {
+        Some(links) => {
+            links.remove(&(node_id(from), node_id(to)));
+            links.is_empty()
+        }
+        None => false,
+    };
+    // Let calls take the direct shortcut again once the last fault is gone
+    if healed {
+        faults.remove(&network);
+        FAULTY_NETWORKS.fetch_sub(1, Relaxed);
+    }
+}
+
+// Remove every fault of the network
+pub fn heal(network: NetworkId) {
+    if FAULTS.write().remove(&network).is_some() {
+        FAULTY_NETWORKS.fetch_sub(1, Relaxed);
+    }
+}
+
+// True if the network has any fault
+pub fn enabled(network: NetworkId) -> bool {
+    FAULTY_NETWORKS.load(Relaxed) > 0 && FAULTS.read().contains_key(&network)
+}
+
+// Decide the fate of a request from node `from` to server `to`
+pub fn action(network: NetworkId, from: u64, to: u64) -> Option<Action> {
+    if FAULTY_NETWORKS.load(Relaxed) == 0 {
+        return None;
+    }
+    let fault = {
+        let faults = FAULTS.read();
+        let links = faults.get(&network)?;
+        match links.get(&(from, to)).or_else(|| links.get(&(0, to))) {
+            Some(fault) => fault.clone(),
+            None => return None,
+        }
+    };
+    if fault.partitioned {
+        return Some(Action::Refuse);
+    }
+    if fault.drop_rate > 0.0 && rand::random::<f64>() < fault.drop_rate {
+        return Some(Action::Drop);
+    }
+    let jitter_ms = fault.jitter.as_millis() as u64;
+    let jitter = if jitter_ms > 0 {
+        Duration::from_millis(rand::random::<u64>() % jitter_ms)
+    } else {
+        Duration::from_millis(0)
+    };
+    Some(Action::Deliver {
+        delay: fault.latency + jitter,
+        duplicate: fault.duplicate_rate > 0.0 && rand::random::<f64>() < fault.duplicate_rate,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::{Client, ClientOptions};
+    use crate::tcp::server::{Server, ServerOptions, TcpReq};
+    use crate::tcp::{new_network_id, DEFAULT_NETWORK};
+    use bytes::BytesMut;
+    use futures::prelude::*;
+    use std::io;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn faulty_links() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:1930");
+        let received = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let received = received.clone();
+            let callback = Arc::new(move |data: TcpReq| {
+                received.fetch_add(1, Relaxed);
+                future::ready(data).boxed()
+            });
+            Server::start(&addr, callback, &ServerOptions::default())
+                .await
+                .unwrap()
+        };
+        let connect = |node: &str, timeout: Duration| {
+            let options = ClientOptions {
+                sender_address: Some(String::from(node)),
+                timeout,
+                ..ClientOptions::default()
+            };
+            let addr = addr.clone();
+            async move { Client::connect_with_options(&addr, &options).await.unwrap() }
+        };
+        let node_a = connect("node-a", Duration::from_millis(300)).await;
+        let node_b = connect("node-b", Duration::from_millis(300)).await;
+        let msg = || BytesMut::from(&b"hello"[..]);
+
+        partition(DEFAULT_NETWORK, &["node-a"], &[addr.as_str()]);
+        let err = node_a.send_msg(msg()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(node_b.send_msg(msg()).await.is_ok());
+        heal_link(DEFAULT_NETWORK, "node-a", &addr);
+        assert!(node_a.send_msg(msg()).await.is_ok());
+
+        add_latency(
+            DEFAULT_NETWORK,
+            ANY_NODE,
+            &addr,
+            Duration::from_millis(200),
+            Duration::from_millis(0),
+        );
+        let started = Instant::now();
+        assert!(node_b.send_msg(msg()).await.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        heal_link(DEFAULT_NETWORK, ANY_NODE, &addr);
+
+        drop_requests(DEFAULT_NETWORK, "node-a", &addr, 1.0);
+        let err = node_a.send_msg(msg()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        heal_link(DEFAULT_NETWORK, "node-a", &addr);
+
+        duplicate_requests(DEFAULT_NETWORK, "node-b", &addr, 1.0);
+        let before = received.load(Relaxed);
+        assert!(node_b.send_msg(msg()).await.is_ok());
+        assert_eq!(received.load(Relaxed) - before, 2);
+        heal_link(DEFAULT_NETWORK, "node-b", &addr);
+
+        // Faults of another network leave this one alone
+        let other = new_network_id();
+        partition(other, &["node-a"], &[addr.as_str()]);
+        assert!(enabled(other));
+        assert!(node_a.send_msg(msg()).await.is_ok());
+        heal_link(other, "node-a", &addr);
+        assert!(enabled(other));
+        heal_link(other, &addr, "node-a");
+        assert!(!enabled(other));
+
+        server.close(Duration::from_secs(1)).await;
+    }
+}
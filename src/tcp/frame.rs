@@ -0,0 +1,154 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+
+// Every frame starts with a u64 msg id. Its top byte is reserved for flags, so peers
+// without knowledge of any flag see them as part of the id and echo them back.
+
+pub const FLAGS_SHIFT: u32 = 56;
+pub const MSG_ID_MASK: u64 = (1 << FLAGS_SHIFT) - 1;
+
+// Payload is compressed with the algorithm negotiated for the connection
+pub const FLAG_COMPRESSED: u8 = 0b0000_0001;
+// Payload is preceded by the time the client waits for the response, u32 milliseconds.
+// Only sent to peers with `handshake::FEATURE_DEADLINE`.
+pub const FLAG_DEADLINE: u8 = 0b0000_0010;
+// Client does not wait for a response, the server dispatches the request and answers
+// nothing. Only sent to peers with `handshake::FEATURE_ONEWAY`.
+pub const FLAG_ONEWAY: u8 = 0b0000_0100;
+// Frame is for connection management and never reaches the callback
+pub const FLAG_CONTROL: u8 = 0b1000_0000;
+
+// Control payloads lead with an id no service can be registered under, so peers that
+// predate control frames answer them with `ServiceIdNotFound` instead of failing
+pub const CONTROL_SERVICE_ID: u64 = u64::MAX;
+// Kinds of control frames, follows the service id
+// Exchanged when connected, see `tcp::handshake`
+pub const CONTROL_HANDSHAKE: u8 = 1;
+// Keeps idle connections alive, answered with another ping
+pub const CONTROL_PING: u8 = 2;
+// Client no longer waits for the request with the msg id in the body, u64. Not answered.
+pub const CONTROL_CANCEL: u8 = 3;
+
+pub fn split_msg_id(raw: u64) -> (u64, u8) {
+    (raw & MSG_ID_MASK, (raw >> FLAGS_SHIFT) as u8)
+}
+
+pub fn join_msg_id(msg_id: u64, flags: u8) -> u64 {
+    (msg_id & MSG_ID_MASK) | ((flags as u64) << FLAGS_SHIFT)
+}
+
+pub fn control_frame(msg_id: u64, kind: u8, body: &[u8]) -> BytesMut {
+    let mut frame = BytesMut::with_capacity(8 + 8 + 1 + body.len());
+    frame.put_u64_le(join_msg_id(msg_id, FLAG_CONTROL));
+    frame.put_u64_le(CONTROL_SERVICE_ID);
+    frame.put_u8(kind);
+    frame.extend_from_slice(body);
+    frame
+}
+
+// Take the kind from a control payload, leaving its body in the buffer
+pub fn read_control(payload: &mut BytesMut) -> Option<u8> {
+    if payload.len() < 9 || (&payload[..8]).get_u64_le() != CONTROL_SERVICE_ID {
+        return None;
+    }
+    payload.advance(8);
+    Some(payload.get_u8())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            1 => Compression::Lz4,
+            _ => Compression::None,
+        }
+    }
+}
+
+// Compress the payload if the connection negotiated compression and the payload is
+// big enough to be worth it. Returns the flags to put on the frame.
+pub fn encode_payload(
+    compression: Compression,
+    threshold: usize,
+    payload: &[u8],
+    buffer: &mut BytesMut,
+) -> u8 {
+    match compression {
+        Compression::Lz4 if payload.len() >= threshold => {
+            buffer.extend_from_slice(&lz4_flex::compress_prepend_size(payload));
+            FLAG_COMPRESSED
+        }
+        _ => {
+            buffer.extend_from_slice(payload);
+            0
+        }
+    }
+}
+
+pub fn decode_payload(flags: u8, payload: BytesMut, max_length: usize) -> io::Result<BytesMut> {
+    if flags & FLAG_COMPRESSED == 0 {
+        return Ok(payload);
+    }
+    if payload.len() < 4 {
+        return Err(invalid_data("compressed payload is truncated"));
+    }
+    let mut size = [0u8; 4];
+    size.copy_from_slice(&payload[..4]);
+    // Check the prepended size before allocating for it
+    if u32::from_le_bytes(size) as usize > max_length {
+        return Err(invalid_data(
+            "decompressed payload exceeds frame length limit",
+        ));
+    }
+    lz4_flex::decompress_size_prepended(payload.as_ref())
+        .map(|data| BytesMut::from(data.as_slice()))
+        .map_err(|e| invalid_data(format!("cannot decompress payload, {:?}", e)))
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn msg_id_flags() {
+        let raw = join_msg_id(42, FLAG_COMPRESSED | FLAG_CONTROL);
+        assert_eq!(split_msg_id(raw), (42, FLAG_COMPRESSED | FLAG_CONTROL));
+        assert_eq!(split_msg_id(42), (42, 0));
+    }
+
+    #[test]
+    fn compress_above_threshold() {
+        let payload = vec![7u8; 4096];
+        let mut small = BytesMut::new();
+        assert_eq!(
+            encode_payload(Compression::Lz4, 8192, &payload, &mut small),
+            0
+        );
+        assert_eq!(small.as_ref(), payload.as_slice());
+        let mut compressed = BytesMut::new();
+        let flags = encode_payload(Compression::Lz4, 1024, &payload, &mut compressed);
+        assert_eq!(flags, FLAG_COMPRESSED);
+        assert!(compressed.len() < payload.len());
+        let decoded = decode_payload(flags, compressed.clone(), 8192).unwrap();
+        assert_eq!(decoded.as_ref(), payload.as_slice());
+        assert!(decode_payload(flags, compressed, 1024).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::tcp::frame::Compression;
+use crate::utils::serde::{serializer_name, Codec};
+use bytes::{Buf, BufMut};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// First control frame on every connection. The client sends its handshake and the server
+// answers with its own, each side checks the other can understand what it is going to send.
+
+pub const MAGIC: [u8; 4] = *b"BFST";
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// Handshakes from peers that predate `features` end before it
+const ENCODED_LEN: usize = 4 + 2 + 1 + 8 + 1;
+
+// Optional protocol features, the handshake of each side lists the ones it understands.
+// Frames relying on a feature are only sent to peers that listed it.
+// Requests may carry a deadline, see `tcp::deadline`
+pub const FEATURE_DEADLINE: u8 = 0b0000_0001;
+// Requests can be cancelled with `frame::CONTROL_CANCEL`
+pub const FEATURE_CANCEL: u8 = 0b0000_0010;
+// Requests flagged with `frame::FLAG_ONEWAY` are not answered
+pub const FEATURE_ONEWAY: u8 = 0b0000_0100;
+pub const FEATURES: u8 = FEATURE_DEADLINE | FEATURE_CANCEL | FEATURE_ONEWAY;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u16,
+    pub serializer: u8,
+    // Server id of the sender, 0 for clients not running a server
+    pub server_id: u64,
+    // Requested by clients, accepted by servers
+    pub compression: Compression,
+    pub features: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    Truncated,
+    BadMagic([u8; 4]),
+    VersionMismatch { local: u16, remote: u16 },
+    SerializerMismatch { local: u8, remote: u8 },
+}
+
+impl Handshake {
+    pub fn local(server_id: u64, compression: Compression, codec: &dyn Codec) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            serializer: codec.id(),
+            server_id,
+            compression,
+            features: FEATURES,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ENCODED_LEN + 1);
+        buf.extend_from_slice(&MAGIC);
+        buf.put_u16_le(self.version);
+        buf.put_u8(self.serializer);
+        buf.put_u64_le(self.server_id);
+        buf.put_u8(self.compression.id());
+        buf.put_u8(self.features);
+        buf
+    }
+
+    pub fn decode(mut body: &[u8]) -> Result<Self, HandshakeError> {
+        if body.len() < ENCODED_LEN {
+            return Err(HandshakeError::Truncated);
+        }
+        let mut magic = [0u8; 4];
+        body.copy_to_slice(&mut magic);
+        if magic != MAGIC {
+            return Err(HandshakeError::BadMagic(magic));
+        }
+        Ok(Self {
+            version: body.get_u16_le(),
+            serializer: body.get_u8(),
+            server_id: body.get_u64_le(),
+            compression: Compression::from_id(body.get_u8()),
+            features: if body.has_remaining() {
+                body.get_u8()
+            } else {
+                0
+            },
+        })
+    }
+
+    // Check if the remote peer speaks the same protocol as we do
+    pub fn check(&self, remote: &Handshake) -> Result<(), HandshakeError> {
+        if self.version != remote.version {
+            return Err(HandshakeError::VersionMismatch {
+                local: self.version,
+                remote: remote.version,
+            });
+        }
+        if self.serializer != remote.serializer {
+            return Err(HandshakeError::SerializerMismatch {
+                local: self.serializer,
+                remote: remote.serializer,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::Truncated => write!(f, "handshake is truncated"),
+            HandshakeError::BadMagic(magic) => {
+                write!(f, "peer is not speaking bifrost, magic {:?}", magic)
+            }
+            HandshakeError::VersionMismatch { local, remote } => write!(
+                f,
+                "protocol version mismatch, local {}, remote {}",
+                local, remote
+            ),
+            HandshakeError::SerializerMismatch { local, remote } => write!(
+                f,
+                "serializer mismatch, local {}, remote {}. Are the peers set to other codecs?",
+                serializer_name(*local),
+                serializer_name(*remote)
+            ),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::serde::{Cbor, SERIALIZER_JSON};
+
+    #[test]
+    fn encode_and_check() {
+        let local = Handshake::local(42, Compression::Lz4, &Cbor);
+        let decoded = Handshake::decode(&local.encode()).unwrap();
+        assert_eq!(decoded, local);
+        assert!(local.check(&decoded).is_ok());
+        // Peers predating features
+        let old = Handshake::decode(&local.encode()[..ENCODED_LEN]).unwrap();
+        assert_eq!(old.features, 0);
+        let remote = Handshake {
+            serializer: SERIALIZER_JSON,
+            ..local
+        };
+        match local.check(&remote) {
+            Err(HandshakeError::SerializerMismatch { .. }) => {}
+            r => panic!("Expected serializer mismatch, got {:?}", r),
+        }
+        assert_eq!(
+            Handshake::decode(b"HTTP/1.1 200 OK\r\n"),
+            Err(HandshakeError::BadMagic(*b"HTTP"))
+        );
+    }
+}
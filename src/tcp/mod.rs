@@ -1,12 +1,90 @@
 use bifrost_hasher::hash_str;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 pub mod client;
+pub mod deadline;
+pub mod faults;
+pub mod frame;
+pub mod handshake;
 pub mod server;
 pub mod shortcut;
+pub mod tls;
 
 pub static STANDALONE_ADDRESS: &'static str = "STANDALONE";
+pub static UNIX_ADDRESS_PREFIX: &'static str = "unix:";
+
+// Servers and clients in the default network use real sockets, other networks are virtual
+// and only reachable through the in-process shortcut, so they can reuse addresses freely
+pub type NetworkId = u64;
+pub const DEFAULT_NETWORK: NetworkId = 0;
+
+static NETWORK_COUNTER: AtomicU64 = AtomicU64::new(DEFAULT_NETWORK + 1);
+
+pub fn new_network_id() -> NetworkId {
+    NETWORK_COUNTER.fetch_add(1, Relaxed)
+}
+
+// Same as the default of `LengthDelimitedCodec`
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
 
 lazy_static! {
     pub static ref STANDALONE_ADDRESS_STRING: String = String::from(STANDALONE_ADDRESS);
     pub static ref STANDALONE_SERVER_ID: u64 = hash_str(&STANDALONE_ADDRESS_STRING);
 }
+
+// Plain sockets and TLS sessions are both carried as boxed streams so the framing
+// code on both sides does not need to know which one it is talking over
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+pub type Transport = Framed<BoxedStream, LengthDelimitedCodec>;
+
+// Addresses like `unix:/var/run/bifrost.sock` are served over Unix domain sockets
+pub fn unix_socket_path(address: &str) -> Option<&str> {
+    if address.starts_with(UNIX_ADDRESS_PREFIX) {
+        Some(&address[UNIX_ADDRESS_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+pub fn codec(max_frame_length: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_codec()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    FrameLength,
+    InFlightRequests,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::FrameLength => write!(f, "frame length limit exceeded"),
+            Limit::InFlightRequests => write!(f, "in-flight requests limit exceeded"),
+        }
+    }
+}
+
+impl Error for Limit {}
+
+pub fn limit_exceeded(limit: Limit) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, limit)
+}
+
+// Find out if an IO error is caused by hitting one of the limits above
+pub fn exceeded_limit(e: &io::Error) -> Option<Limit> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<Limit>())
+        .cloned()
+}
@@ -1,65 +1,701 @@
 use super::STANDALONE_ADDRESS;
-use crate::tcp::shortcut;
-use bytes::{Buf, BufMut, BytesMut};
+use crate::rpc::auth::{self, Secret};
+use crate::tcp::frame::{self, Compression};
+use crate::tcp::handshake::Handshake;
+use crate::tcp::tls::ServerTls;
+use crate::tcp::{
+    codec, deadline, shortcut, unix_socket_path, BoxedStream, NetworkId, DEFAULT_MAX_FRAME_LENGTH,
+    DEFAULT_NETWORK,
+};
+use crate::utils::serde::{default_codec, Codec};
+use crate::utils::time::get_time;
+use bifrost_hasher::hash_str;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::{AbortHandle, Abortable, Aborted, BoxFuture, FutureExt, Shared};
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures::SinkExt;
+use parking_lot::Mutex as SyncMutex;
+use std::collections::HashMap;
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::stream::StreamExt;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::time::delay_for;
+use tokio_util::codec::Framed;
 
 pub type RPCFuture = dyn Future<Output = TcpRes>;
 pub type BoxedRPCFuture = Box<RPCFuture>;
 pub type TcpReq = BytesMut;
 pub type TcpRes = Pin<Box<dyn Future<Output = BytesMut> + Send>>;
+pub type TcpCallback = Arc<dyn Fn(TcpReq) -> TcpRes + Send + Sync>;
+
+const DRAIN_CHECK_MS: u64 = 10;
+
+tokio::task_local! {
+    // Address of the peer and id of the connection being served, `None` inside shortcut calls
+    pub(crate) static PEER: Option<(String, u64)>;
+}
+
+// Peer of the request the callback is handling, `None` for calls through the shortcut
+pub fn peer_address() -> Option<String> {
+    PEER.try_with(|peer| peer.as_ref().map(|(address, _)| address.clone()))
+        .ok()
+        .flatten()
+}
+
+// Connection of the request the callback is handling, unique within the server. `None` for
+// calls through the shortcut.
+pub fn connection_id() -> Option<u64> {
+    PEER.try_with(|peer| peer.as_ref().map(|(_, conn_id)| *conn_id))
+        .ok()
+        .flatten()
+}
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    pub tls: Option<ServerTls>,
+    // Requests dispatched at the same time on a single connection. Further requests get
+    // `overloaded_response`, or wait for reading to resume without it.
+    pub max_concurrent_requests: usize,
+    // Peers sending larger frames are disconnected
+    pub max_frame_length: usize,
+    // Compression clients may ask for, `Compression::None` to turn down every request
+    pub compression: Compression,
+    // Responses smaller than this are always sent as is
+    pub compression_threshold: usize,
+    // Servers in networks other than the default one don't bind to the address
+    pub network: NetworkId,
+    // Address the listener binds to, like `0.0.0.0:port` behind NAT or in containers.
+    // The server address is then only what peers dial, and the key of the shortcut.
+    pub bind_address: Option<String>,
+    // Answer to requests arriving while the server drains. Without it the connection
+    // stops reading and those requests are left to time out.
+    pub closing_response: Option<Bytes>,
+    // Answer to requests arriving while `max_concurrent_requests` are dispatched on the
+    // connection. Without it the connection pauses reading until some complete.
+    pub overloaded_response: Option<Bytes>,
+    // RPC requests not signed with this secret are refused, defaults to the cluster secret
+    pub secret: Option<Secret>,
+    // Encoding of RPC arguments and results, clients must use the same one
+    pub codec: &'static dyn Codec,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            max_concurrent_requests: 128,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            compression: Compression::Lz4,
+            compression_threshold: 1024,
+            network: DEFAULT_NETWORK,
+            bind_address: None,
+            closing_response: None,
+            overloaded_response: None,
+            secret: auth::cluster_secret(),
+            codec: default_codec(),
+        }
+    }
+}
 
 pub struct Server;
 
+// Shared between the accept loop, connection tasks and the handle
+struct ServerState {
+    server_id: u64,
+    closing: AtomicBool,
+    in_flight: AtomicUsize,
+    conn_counter: AtomicU64,
+    connections: SyncMutex<HashMap<u64, AbortHandle>>,
+}
+
+pub struct ServerHandle {
+    pub address: String,
+    // Address the listener is bound to, the advertised one unless set in the options
+    pub bind_address: String,
+    pub network: NetworkId,
+    state: Arc<ServerState>,
+    accept_abort: Option<AbortHandle>,
+    // Awaited by both `stopped` and `stop_accepting`
+    accept_loop: Option<Shared<BoxFuture<'static, ()>>>,
+    closed: AtomicBool,
+}
+
 impl Server {
-    pub async fn new(
+    pub async fn new(addr: &String, callback: TcpCallback) -> Result<(), Box<dyn Error>> {
+        Self::new_with_options(addr, callback, &ServerOptions::default()).await
+    }
+
+    // Serve until the server is closed through its handle
+    pub async fn new_with_options(
         addr: &String,
-        callback: Arc<dyn Fn(TcpReq) -> TcpRes + Send + Sync>,
+        callback: TcpCallback,
+        options: &ServerOptions,
     ) -> Result<(), Box<dyn Error>> {
-        shortcut::register_server(addr, &callback).await;
-        if !addr.eq(&STANDALONE_ADDRESS) {
-            let mut listener = TcpListener::bind(&addr).await?;
+        let handle = Self::start(addr, callback, options).await?;
+        handle.stopped().await;
+        Ok(())
+    }
+
+    // Returns as soon as the listener is bound, leaving the accept loop in background
+    pub async fn start(
+        addr: &String,
+        callback: TcpCallback,
+        options: &ServerOptions,
+    ) -> Result<Arc<ServerHandle>, Box<dyn Error>> {
+        let state = Arc::new(ServerState {
+            server_id: hash_str(addr),
+            closing: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            conn_counter: AtomicU64::new(0),
+            connections: SyncMutex::new(HashMap::new()),
+        });
+        shortcut::register_server(options.network, addr, &callback).await;
+        if addr.eq(&STANDALONE_ADDRESS) || options.network != DEFAULT_NETWORK {
+            let handle = ServerHandle {
+                address: addr.clone(),
+                bind_address: addr.clone(),
+                network: options.network,
+                state,
+                accept_abort: None,
+                accept_loop: None,
+                closed: AtomicBool::new(false),
+            };
+            return Ok(Arc::new(handle));
+        }
+        let (accept_abort, abort_reg) = AbortHandle::new_pair();
+        let bind_addr = options.bind_address.clone().unwrap_or_else(|| addr.clone());
+        let options = options.clone();
+        let loop_state = state.clone();
+        let loop_callback = callback.clone();
+        let accept_loop = if let Some(path) = unix_socket_path(&bind_addr) {
+            // Socket file left behind by a previous run will fail the bind
+            let _ = std::fs::remove_file(path);
+            let mut listener = UnixListener::bind(path)?;
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                let accepting = async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((socket, _)) => Self::serve(
+                                Box::new(socket),
+                                addr.clone(),
+                                &loop_callback,
+                                &options,
+                                &loop_state,
+                            ),
+                            Err(e) => error!("error accepting unix socket; error = {:?}", e),
+                        }
+                    }
+                };
+                let _ = Abortable::new(accepting, abort_reg).await;
+            })
+        } else {
+            let mut listener = TcpListener::bind(&bind_addr).await?;
+            tokio::spawn(async move {
+                let accepting = async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((socket, peer)) => Self::serve(
+                                Box::new(socket),
+                                peer.to_string(),
+                                &loop_callback,
+                                &options,
+                                &loop_state,
+                            ),
+                            Err(e) => error!("error accepting socket; error = {:?}", e),
+                        }
+                    }
+                };
+                let _ = Abortable::new(accepting, abort_reg).await;
+            })
+        };
+        debug!("Server {} bound to {}", addr, bind_addr);
+        let handle = ServerHandle {
+            address: addr.clone(),
+            bind_address: bind_addr,
+            network: DEFAULT_NETWORK,
+            state,
+            accept_abort: Some(accept_abort),
+            accept_loop: Some(accept_loop.map(|_| ()).boxed().shared()),
+            closed: AtomicBool::new(false),
+        };
+        Ok(Arc::new(handle))
+    }
+
+    fn serve(
+        stream: BoxedStream,
+        peer: String,
+        callback: &TcpCallback,
+        options: &ServerOptions,
+        state: &Arc<ServerState>,
+    ) {
+        // Like with other small servers, we'll `spawn` this client to ensure it
+        // runs concurrently with all other clients. The `move` keyword is used
+        // here to move ownership of our db handle into the async closure.
+        let callback = callback.clone();
+        let tls = options.tls.clone();
+        let max_concurrent = options.max_concurrent_requests.max(1);
+        let max_frame_length = options.max_frame_length;
+        // Offered to clients, with the compression they asked for if it is accepted
+        let offered = Handshake::local(state.server_id, options.compression, options.codec);
+        let compression_threshold = options.compression_threshold;
+        let closing_response = options.closing_response.clone();
+        let overloaded_response = options.overloaded_response.clone();
+        let conn_state = state.clone();
+        let conn_id = state.conn_counter.fetch_add(1, Relaxed);
+        let (conn_abort, abort_reg) = AbortHandle::new_pair();
+        state.connections.lock().insert(conn_id, conn_abort);
+        let conn_peer = peer.clone();
+        let connection = async move {
+            let stream = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("TLS handshake with {} failed, error = {:?}", peer, e);
+                        return;
+                    }
+                },
+                None => stream,
+            };
+            let transport = Framed::new(stream, codec(max_frame_length));
+            let (mut writer, mut reader) = transport.split();
+            // Requests are dispatched as they arrive and responses are written in the order
+            // they complete. Client can match them by msg id.
+            let mut pending = FuturesUnordered::new();
+            // Abort handles of the dispatched requests, for clients to cancel them
+            let mut dispatched: HashMap<u64, AbortHandle> = HashMap::new();
+            let mut reading = true;
+            // Stays uncompressed until the client asks for it, old clients never do
+            let mut compression = Compression::None;
             loop {
-                match listener.accept().await {
-                    Ok((socket, _)) => {
-                        // Like with other small servers, we'll `spawn` this client to ensure it
-                        // runs concurrently with all other clients. The `move` keyword is used
-                        // here to move ownership of our db handle into the async closure.
-                        let callback = callback.clone();
-                        tokio::spawn(async move {
-                            let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
-                            while let Some(result) = transport.next().await {
-                                match result {
-                                    Ok(mut data) => {
-                                        let msg_id = data.get_u64_le();
-                                        let call_back_data = callback(data).await;
-                                        let mut res =
-                                            BytesMut::with_capacity(8 + call_back_data.len());
-                                        // debug!("Received TCP message {}", msg_id);
-                                        res.put_u64_le(msg_id);
-                                        res.extend_from_slice(call_back_data.as_ref());
-                                        if let Err(e) = transport.send(res.freeze()).await {
-                                            error!("Error on TCP callback {:?}", e);
+                tokio::select! {
+                    result = reader.next(), if reading && (pending.len() < max_concurrent || overloaded_response.is_some()) => {
+                        match result {
+                            Some(Ok(mut data)) if conn_state.closing.load(Relaxed) => {
+                                match closing_response {
+                                    Some(ref closing_res) if data.len() >= 8 => {
+                                        let (msg_id, flags) = frame::split_msg_id(data.get_u64_le());
+                                        if flags & frame::FLAG_CONTROL == 0 {
+                                            let mut res = BytesMut::with_capacity(8 + closing_res.len());
+                                            res.put_u64_le(msg_id);
+                                            res.extend_from_slice(closing_res);
+                                            if let Err(e) = writer.send(res.freeze()).await {
+                                                error!("Error on closing response {:?}", e);
+                                            }
                                         }
                                     }
-                                    Err(e) => {
-                                        error!("error on decoding from socket; error = {:?}", e);
+                                    _ => {
+                                        debug!("Server is closing, stop reading from {}", peer);
+                                        reading = false;
                                     }
                                 }
                             }
-                            // The connection will be closed at this point as `lines.next()` has returned `None`.
-                        });
+                            Some(Ok(mut data)) => {
+                                let (msg_id, flags) = frame::split_msg_id(data.get_u64_le());
+                                if flags & frame::FLAG_CONTROL != 0 {
+                                    let (res, close) = Self::control(
+                                        msg_id,
+                                        data,
+                                        &offered,
+                                        &peer,
+                                        &mut compression,
+                                        &mut dispatched,
+                                    );
+                                    if let Some(res) = res {
+                                        if let Err(e) = writer.send(res.freeze()).await {
+                                            error!("Error on control frame {:?}", e);
+                                        }
+                                    }
+                                    if close {
+                                        reading = false;
+                                    }
+                                } else if pending.len() >= max_concurrent {
+                                    // Only read at the cap with an overloaded response to send
+                                    debug!("Turning down request {} from {}, overloaded", msg_id, peer);
+                                    match overloaded_response {
+                                        Some(ref overloaded) if flags & frame::FLAG_ONEWAY == 0 => {
+                                            let mut res = BytesMut::with_capacity(8 + overloaded.len());
+                                            res.put_u64_le(msg_id);
+                                            res.extend_from_slice(overloaded);
+                                            if let Err(e) = writer.send(res.freeze()).await {
+                                                error!("Error on overloaded response {:?}", e);
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                } else if flags & frame::FLAG_DEADLINE != 0 && data.len() < 4 {
+                                    error!("Truncated deadline from {}", peer);
+                                    reading = false;
+                                } else {
+                                    let remaining = if flags & frame::FLAG_DEADLINE != 0 {
+                                        Some(deadline::decode(data.get_u32_le()))
+                                    } else {
+                                        None
+                                    };
+                                    match frame::decode_payload(flags, data, max_frame_length) {
+                                        // Client stopped waiting for it already
+                                        Ok(_) if remaining == Some(Duration::from_secs(0)) => {
+                                            debug!("Skipping expired request {} from {}", msg_id, peer);
+                                        }
+                                        Ok(data) => {
+                                            conn_state.in_flight.fetch_add(1, Relaxed);
+                                            let (abort, abort_reg) = AbortHandle::new_pair();
+                                            dispatched.insert(msg_id, abort);
+                                            let call_back = Abortable::new(
+                                                deadline::with_remaining(remaining, callback(data)),
+                                                abort_reg,
+                                            );
+                                            let oneway = flags & frame::FLAG_ONEWAY != 0;
+                                            pending.push(async move {
+                                                (msg_id, oneway, call_back.await)
+                                            });
+                                        }
+                                        Err(e) => {
+                                            error!("Bad payload from {}; error = {:?}", peer, e);
+                                            reading = false;
+                                        }
+                                    }
+                                }
+                            }
+                            Some(Err(e)) => {
+                                // Frames after a bad one cannot be trusted, answer what has been
+                                // dispatched and close the connection
+                                error!("error on decoding from {}; error = {:?}", peer, e);
+                                reading = false;
+                            }
+                            None => reading = false,
+                        }
+                    }
+                    Some((msg_id, oneway, call_back_res)) = pending.next(), if !pending.is_empty() => {
+                        dispatched.remove(&msg_id);
+                        match call_back_res {
+                            Ok(_) if oneway => trace!("Handled oneway request {} from {}", msg_id, peer),
+                            Ok(call_back_data) => {
+                                let mut res = BytesMut::with_capacity(8 + call_back_data.len());
+                                // debug!("Received TCP message {}", msg_id);
+                                res.put_u64_le(0);
+                                let flags = frame::encode_payload(
+                                    compression,
+                                    compression_threshold,
+                                    call_back_data.as_ref(),
+                                    &mut res,
+                                );
+                                res[..8].copy_from_slice(&frame::join_msg_id(msg_id, flags).to_le_bytes());
+                                if let Err(e) = writer.send(res.freeze()).await {
+                                    error!("Error on TCP callback {:?}", e);
+                                }
+                            }
+                            Err(Aborted) => debug!("Request {} cancelled by {}", msg_id, peer),
+                        }
+                        conn_state.in_flight.fetch_sub(1, Relaxed);
+                    }
+                    else => break,
+                }
+            }
+            // The connection will be closed at this point as the stream has ended and all
+            // dispatched requests are answered.
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let _ = Abortable::new(
+                PEER.scope(Some((conn_peer, conn_id)), connection),
+                abort_reg,
+            )
+            .await;
+            state.connections.lock().remove(&conn_id);
+        });
+    }
+
+    // Answers control frames. Returns the response, and whether the connection should be
+    // closed after sending it.
+    fn control(
+        msg_id: u64,
+        mut payload: BytesMut,
+        offered: &Handshake,
+        peer: &String,
+        compression: &mut Compression,
+        dispatched: &mut HashMap<u64, AbortHandle>,
+    ) -> (Option<BytesMut>, bool) {
+        match frame::read_control(&mut payload) {
+            Some(frame::CONTROL_HANDSHAKE) => {
+                let remote = match Handshake::decode(payload.as_ref()) {
+                    Ok(remote) => remote,
+                    Err(e) => {
+                        error!("Bad handshake from {}, {}", peer, e);
+                        return (None, true);
+                    }
+                };
+                *compression = if remote.compression == offered.compression {
+                    offered.compression
+                } else {
+                    Compression::None
+                };
+                let local = Handshake {
+                    compression: *compression,
+                    ..*offered
+                };
+                let res = frame::control_frame(msg_id, frame::CONTROL_HANDSHAKE, &local.encode());
+                // Answer even when rejecting, so the client can tell what went wrong
+                match local.check(&remote) {
+                    Ok(()) => {
+                        debug!("Handshake from {}, node {}", peer, remote.server_id);
+                        (Some(res), false)
                     }
-                    Err(e) => error!("error accepting socket; error = {:?}", e),
+                    Err(e) => {
+                        error!("Rejecting {}, node {}: {}", peer, remote.server_id, e);
+                        (Some(res), true)
+                    }
+                }
+            }
+            Some(frame::CONTROL_PING) => (
+                Some(frame::control_frame(msg_id, frame::CONTROL_PING, &[])),
+                false,
+            ),
+            Some(frame::CONTROL_CANCEL) if payload.len() >= 8 => {
+                let cancelled = payload.get_u64_le();
+                // Its response is skipped when the request is aborted
+                if let Some(abort) = dispatched.remove(&cancelled) {
+                    trace!("Cancelling request {} from {}", cancelled, peer);
+                    abort.abort();
                 }
+                (None, false)
+            }
+            kind => {
+                warn!("Unknown control frame {:?}", kind);
+                (None, false)
             }
         }
-        Ok(())
+    }
+}
+
+impl ServerHandle {
+    // Stop accepting new connections and release the listening address.
+    // Established connections are left untouched.
+    pub async fn stop_accepting(&self) {
+        if let Some(ref accept_abort) = self.accept_abort {
+            accept_abort.abort();
+            // The listener is only dropped once the accept task has finished
+            self.stopped().await;
+            if let Some(path) = unix_socket_path(&self.bind_address) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        shortcut::deregister_server(self.network, &self.address).await;
+    }
+
+    // Wait for requests in progress to complete. Return false if some are still running
+    // after the deadline. Connections will stop reading new requests once draining begins.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        self.state.closing.store(true, Relaxed);
+        let ends = get_time() + deadline.as_millis() as i64;
+        while self.in_flight() > 0 {
+            if get_time() >= ends {
+                warn!(
+                    "Server {} still have {} requests in flight after drain deadline",
+                    self.address,
+                    self.in_flight()
+                );
+                return false;
+            }
+            delay_for(Duration::from_millis(DRAIN_CHECK_MS)).await;
+        }
+        true
+    }
+
+    // Stop accepting, drain in-flight requests for at most `deadline`, then drop all
+    // connections. Returns true if every request completed before the connections closed.
+    pub async fn close(&self, deadline: Duration) -> bool {
+        if self.closed.swap(true, Relaxed) {
+            return true;
+        }
+        debug!("Closing server {}", self.address);
+        self.stop_accepting().await;
+        let drained = self.drain(deadline).await;
+        let connections: Vec<_> = self.state.connections.lock().drain().collect();
+        for (_, conn_abort) in connections {
+            conn_abort.abort();
+        }
+        drained
+    }
+
+    // Resolves once the server stopped accepting connections
+    pub async fn stopped(&self) {
+        if let Some(ref accept_loop) = self.accept_loop {
+            accept_loop.clone().await;
+        }
+    }
+
+    // Whether the connection of `connection_id` is still open
+    pub fn is_connected(&self, conn_id: u64) -> bool {
+        self.state.connections.lock().contains_key(&conn_id)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Relaxed)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::{Client, ClientOptions};
+    use futures::{future, FutureExt};
+    use std::time::Instant;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn concurrent_dispatch() {
+        let _ = env_logger::try_init();
+        let addr = String::from("0.0.0.0:1920");
+        let callback: TcpCallback = Arc::new(|data: TcpReq| {
+            async move {
+                if data.as_ref() == b"slow" {
+                    delay_for(Duration::from_secs(2)).await;
+                }
+                data
+            }
+            .boxed()
+        });
+        let server = Server::start(&addr, callback, &ServerOptions::default())
+            .await
+            .unwrap();
+        // Dial another address of the same listener to skip the shortcut
+        let client =
+            Client::connect_with_timeout(&String::from("127.0.0.1:1920"), Duration::from_secs(5))
+                .await
+                .unwrap();
+        let started = Instant::now();
+        let slow = client.send_msg(BytesMut::from(&b"slow"[..]));
+        let fast = async {
+            // Make sure the slow request goes first on the connection
+            delay_for(Duration::from_millis(100)).await;
+            let res = client.send_msg(BytesMut::from(&b"fast"[..])).await;
+            (res, started.elapsed())
+        };
+        let (slow_res, (fast_res, fast_elapsed)) = futures::join!(slow, fast);
+        assert_eq!(fast_res.unwrap().as_ref(), b"fast");
+        assert!(fast_elapsed < Duration::from_secs(1));
+        assert_eq!(slow_res.unwrap().as_ref(), b"slow");
+        server.close(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn negotiated_compression() {
+        let _ = env_logger::try_init();
+        let echo: TcpCallback = Arc::new(|data: TcpReq| future::ready(data).boxed());
+        let lz4_server = Server::start(
+            &String::from("0.0.0.0:1921"),
+            echo.clone(),
+            &ServerOptions::default(),
+        )
+        .await
+        .unwrap();
+        let plain_options = ServerOptions {
+            compression: Compression::None,
+            ..ServerOptions::default()
+        };
+        let plain_server = Server::start(&String::from("0.0.0.0:1922"), echo, &plain_options)
+            .await
+            .unwrap();
+        let client_options = ClientOptions {
+            compression: Compression::Lz4,
+            compression_threshold: 16,
+            ..ClientOptions::default()
+        };
+        let payload = BytesMut::from("compress me ".repeat(512).as_bytes());
+        for (addr, expected) in &[
+            ("127.0.0.1:1921", Compression::Lz4),
+            ("127.0.0.1:1922", Compression::None),
+        ] {
+            let client = Client::connect_with_options(&String::from(*addr), &client_options)
+                .await
+                .unwrap();
+            assert_eq!(client.compression(), *expected);
+            let res = client.send_msg(payload.clone()).await.unwrap();
+            assert_eq!(res, payload);
+            let res = client.send_msg(BytesMut::from(&b"tiny"[..])).await.unwrap();
+            assert_eq!(res.as_ref(), b"tiny");
+        }
+        lz4_server.close(Duration::from_secs(1)).await;
+        plain_server.close(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn cancel_dropped_requests() {
+        let _ = env_logger::try_init();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let callback: TcpCallback = {
+            let finished = finished.clone();
+            Arc::new(move |data: TcpReq| {
+                let finished = finished.clone();
+                async move {
+                    delay_for(Duration::from_secs(1)).await;
+                    finished.fetch_add(1, Relaxed);
+                    data
+                }
+                .boxed()
+            })
+        };
+        let server = Server::start(
+            &String::from("0.0.0.0:1923"),
+            callback,
+            &ServerOptions::default(),
+        )
+        .await
+        .unwrap();
+        let client = Client::connect(&String::from("127.0.0.1:1923"))
+            .await
+            .unwrap();
+        // Caller gives up and drops the call
+        let call = client.send_msg(BytesMut::from(&b"abandoned"[..]));
+        assert!(tokio::time::timeout(Duration::from_millis(200), call)
+            .await
+            .is_err());
+        delay_for(Duration::from_millis(200)).await;
+        assert_eq!(server.in_flight(), 0);
+        delay_for(Duration::from_secs(1)).await;
+        assert_eq!(finished.load(Relaxed), 0);
+        server.close(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn rebind_after_close() {
+        let _ = env_logger::try_init();
+        let addr = String::from("0.0.0.0:1924");
+        let callback: TcpCallback = Arc::new(|data: TcpReq| future::ready(data).boxed());
+        for _ in 0..3 {
+            // The listener must be released by the time `close` returns
+            let server = Server::start(&addr, callback.clone(), &ServerOptions::default())
+                .await
+                .unwrap();
+            assert!(server.close(Duration::from_secs(1)).await);
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn remove_bound_socket_on_close() {
+        let _ = env_logger::try_init();
+        let path = "/tmp/bifrost-bound-socket.sock";
+        let options = ServerOptions {
+            bind_address: Some(format!("unix:{}", path)),
+            ..ServerOptions::default()
+        };
+        let callback: TcpCallback = Arc::new(|data: TcpReq| future::ready(data).boxed());
+        // Advertised as another path, which must be left alone
+        let advertised = String::from("unix:/tmp/bifrost-advertised-socket.sock");
+        let server = Server::start(&advertised, callback, &options)
+            .await
+            .unwrap();
+        assert!(std::path::Path::new(path).exists());
+        server.close(Duration::from_secs(1)).await;
+        assert!(!std::path::Path::new(path).exists());
     }
 }
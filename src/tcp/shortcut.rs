@@ -1,4 +1,5 @@
-use crate::tcp::server::{TcpReq, TcpRes};
+use crate::tcp::server::{TcpReq, TcpRes, PEER};
+use crate::tcp::NetworkId;
 use async_std::sync::*;
 use bifrost_hasher::hash_str;
 use bytes::BytesMut;
@@ -9,24 +10,50 @@ use std::sync::Arc;
 trait TcpCallbackFunc = Fn(TcpReq) -> TcpRes;
 trait TcpCallbackFuncShareable = TcpCallbackFunc + Send + Sync;
 
+type ServerCallbacks = BTreeMap<(NetworkId, u64), Arc<dyn TcpCallbackFuncShareable>>;
+
 lazy_static! {
-    pub static ref TCP_CALLBACKS: RwLock<BTreeMap<u64, Arc<dyn TcpCallbackFuncShareable>>> =
-        RwLock::new(BTreeMap::new());
+    pub static ref TCP_CALLBACKS: RwLock<ServerCallbacks> = RwLock::new(BTreeMap::new());
 }
 
 pub async fn register_server(
+    network: NetworkId,
     server_address: &String,
     callback: &Arc<dyn TcpCallbackFuncShareable>,
 ) {
     let server_id = hash_str(server_address);
     let mut servers_cbs = TCP_CALLBACKS.write().await;
-    servers_cbs.insert(server_id, callback.clone());
+    servers_cbs.insert((network, server_id), callback.clone());
+}
+
+pub async fn deregister_server(network: NetworkId, server_address: &String) {
+    let server_id = hash_str(server_address);
+    let mut servers_cbs = TCP_CALLBACKS.write().await;
+    servers_cbs.remove(&(network, server_id));
+}
+
+// Remove every server of the network
+pub async fn deregister_network(network: NetworkId) {
+    let mut servers_cbs = TCP_CALLBACKS.write().await;
+    let servers: Vec<_> = servers_cbs
+        .range((network, 0)..=(network, u64::MAX))
+        .map(|(key, _)| *key)
+        .collect();
+    for key in servers {
+        servers_cbs.remove(&key);
+    }
 }
 
-pub async fn call(server_id: u64, data: TcpReq) -> Result<BytesMut> {
-    let server_cbs = TCP_CALLBACKS.read().await;
-    match server_cbs.get(&server_id) {
-        Some(c) => Ok(c(data).await),
+pub async fn call(network: NetworkId, server_id: u64, data: TcpReq) -> Result<BytesMut> {
+    // Release the lock before running the handler, servers take it to shut down
+    let callback = TCP_CALLBACKS
+        .read()
+        .await
+        .get(&(network, server_id))
+        .cloned();
+    match callback {
+        // Callers may be serving a connection themselves, which is not the peer of this call
+        Some(c) => Ok(PEER.scope(None, c(data)).await),
         _ => Err(Error::new(
             ErrorKind::Other,
             "Cannot found callback for shortcut",
@@ -34,7 +61,7 @@ pub async fn call(server_id: u64, data: TcpReq) -> Result<BytesMut> {
     }
 }
 
-pub async fn is_local(server_id: u64) -> bool {
+pub async fn is_local(network: NetworkId, server_id: u64) -> bool {
     let cbs = TCP_CALLBACKS.read().await;
-    cbs.contains_key(&server_id)
+    cbs.contains_key(&(network, server_id))
 }
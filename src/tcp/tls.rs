@@ -0,0 +1,105 @@
+use crate::tcp::BoxedStream;
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// Certificates and keys are DER encoded. Use `rustls::internal::pemfile` or similar
+// to convert PEM files before handing them over.
+
+#[derive(Clone)]
+pub struct ServerTls {
+    pub config: Arc<ServerConfig>,
+}
+
+#[derive(Clone)]
+pub struct ClientTls {
+    pub config: Arc<ClientConfig>,
+    pub domain: String,
+}
+
+fn invalid_input<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e))
+}
+
+fn cert_chain(chain: Vec<Vec<u8>>) -> Vec<Certificate> {
+    chain.into_iter().map(Certificate).collect()
+}
+
+fn root_store(roots: &Vec<Vec<u8>>) -> io::Result<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store
+            .add(&Certificate(root.clone()))
+            .map_err(invalid_input)?;
+    }
+    Ok(store)
+}
+
+impl ServerTls {
+    pub fn new(cert_chain_der: Vec<Vec<u8>>, key_der: Vec<u8>) -> io::Result<Self> {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(cert_chain(cert_chain_der), PrivateKey(key_der))
+            .map_err(invalid_input)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+    // Only accept clients presenting a certificate signed by one of the `client_roots`
+    pub fn with_client_auth(
+        cert_chain_der: Vec<Vec<u8>>,
+        key_der: Vec<u8>,
+        client_roots_der: Vec<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let verifier = AllowAnyAuthenticatedClient::new(root_store(&client_roots_der)?);
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(cert_chain(cert_chain_der), PrivateKey(key_der))
+            .map_err(invalid_input)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+    pub async fn accept(&self, socket: BoxedStream) -> io::Result<BoxedStream> {
+        let acceptor = TlsAcceptor::from(self.config.clone());
+        Ok(Box::new(acceptor.accept(socket).await?))
+    }
+}
+
+impl ClientTls {
+    // `domain` is the name the server certificate is verified against
+    pub fn new(roots_der: Vec<Vec<u8>>, domain: &str) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        config.root_store = root_store(&roots_der)?;
+        Ok(Self {
+            config: Arc::new(config),
+            domain: domain.to_string(),
+        })
+    }
+    pub fn with_client_cert(
+        roots_der: Vec<Vec<u8>>,
+        domain: &str,
+        cert_chain_der: Vec<Vec<u8>>,
+        key_der: Vec<u8>,
+    ) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        config.root_store = root_store(&roots_der)?;
+        config
+            .set_single_client_cert(cert_chain(cert_chain_der), PrivateKey(key_der))
+            .map_err(invalid_input)?;
+        Ok(Self {
+            config: Arc::new(config),
+            domain: domain.to_string(),
+        })
+    }
+    pub async fn connect(&self, socket: BoxedStream) -> io::Result<BoxedStream> {
+        let connector = TlsConnector::from(self.config.clone());
+        let domain = DNSNameRef::try_from_ascii_str(&self.domain).map_err(invalid_input)?;
+        Ok(Box::new(connector.connect(domain, socket).await?))
+    }
+}
@@ -1,61 +1,307 @@
+use bincode::Options;
+use parking_lot::RwLock;
 use serde;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::Relaxed;
 
-#[cfg(not(debug_assertions))]
-pub fn serialize<T>(obj: &T) -> Vec<u8>
-where
-    T: serde::Serialize,
-{
-    match serde_cbor::to_vec(obj) {
-        Ok(data) => data,
-        Err(e) => panic!("Cannot serialize: {:?}", e),
+// Identifies the encoding on the wire and on disk, peers must agree on it
+pub const SERIALIZER_JSON: u8 = 1;
+pub const SERIALIZER_CBOR: u8 = 2;
+pub const SERIALIZER_BINCODE: u8 = 3;
+
+// Takes the deserializer given by `Codec::deserialize_erased`
+pub type Visit<'v, 'a> =
+    &'v mut dyn FnMut(&mut dyn erased_serde::Deserializer<'a>) -> Result<(), erased_serde::Error>;
+
+// Encoding of RPC payloads, raft logs and snapshots. Other encodings implement it and
+// `register_codec` with an id of their own, which is what handshakes and headers record.
+// Use `serialize` and `deserialize` of `dyn Codec` rather than the erased methods.
+pub trait Codec: Send + Sync {
+    fn id(&self) -> u8;
+    fn name(&self) -> &'static str;
+    fn serialize_erased(&self, obj: &dyn erased_serde::Serialize) -> Result<Vec<u8>, String>;
+    // Call `visit` with a deserializer of the data, which must be used up by it
+    fn deserialize_erased<'a>(&self, data: &'a [u8], visit: Visit<'_, 'a>) -> Result<(), String>;
+}
+
+impl dyn Codec {
+    pub fn serialize<T>(&self, obj: &T) -> Vec<u8>
+    where
+        T: serde::Serialize,
+    {
+        match self.serialize_erased(obj) {
+            Ok(data) => data,
+            Err(e) => panic!("Cannot serialize with {}: {}", self.name(), e),
+        }
+    }
+
+    pub fn deserialize<'a, T>(&self, data: &'a [u8]) -> Option<T>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        let mut obj = None;
+        let res = self.deserialize_erased(data, &mut |de| {
+            obj = Some(erased_serde::deserialize(de)?);
+            Ok(())
+        });
+        match res {
+            Ok(()) => obj,
+            Err(e) => {
+                warn!(
+                    "Error on decoding {} data for type '{}', {}",
+                    self.name(),
+                    std::intrinsics::type_name::<T>(),
+                    e
+                );
+                if self.id() == SERIALIZER_JSON {
+                    debug!("json: {}", String::from_utf8_lossy(data));
+                }
+                None
+            }
+        }
     }
 }
 
-#[cfg(not(debug_assertions))]
-pub fn deserialize<'a, T>(data: &'a [u8]) -> Option<T>
-where
-    T: serde::Deserialize<'a>,
-{
-    match serde_cbor::from_slice(data) {
-        Ok(obj) => Some(obj),
-        Err(e) => {
-            warn!(
-                "Error on decoding data for type '{}', {}",
-                std::intrinsics::type_name::<T>(),
-                e
-            );
-            None
+// Codecs are the same when their ids are
+impl PartialEq for dyn Codec {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl fmt::Debug for dyn Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub struct Json;
+pub struct Cbor;
+// Bincode is the most compact but does not describe the data, it cannot decode types
+// relying on `deserialize_any` like untagged enums or `serde_json::Value`.
+pub struct Bincode;
+
+impl Codec for Json {
+    fn id(&self) -> u8 {
+        SERIALIZER_JSON
+    }
+    fn name(&self) -> &'static str {
+        "JSON"
+    }
+    fn serialize_erased(&self, obj: &dyn erased_serde::Serialize) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(&obj).map_err(|e| e.to_string())
+    }
+    fn deserialize_erased<'a>(&self, data: &'a [u8], visit: Visit<'_, 'a>) -> Result<(), String> {
+        let mut de = serde_json::Deserializer::from_slice(data);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(|e| e.to_string())?;
+        de.end().map_err(|e| e.to_string())
+    }
+}
+
+impl Codec for Cbor {
+    fn id(&self) -> u8 {
+        SERIALIZER_CBOR
+    }
+    fn name(&self) -> &'static str {
+        "CBOR"
+    }
+    fn serialize_erased(&self, obj: &dyn erased_serde::Serialize) -> Result<Vec<u8>, String> {
+        serde_cbor::to_vec(&obj).map_err(|e| e.to_string())
+    }
+    fn deserialize_erased<'a>(&self, data: &'a [u8], visit: Visit<'_, 'a>) -> Result<(), String> {
+        let mut de = serde_cbor::Deserializer::from_slice(data);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(|e| e.to_string())?;
+        de.end().map_err(|e| e.to_string())
+    }
+}
+
+impl Codec for Bincode {
+    fn id(&self) -> u8 {
+        SERIALIZER_BINCODE
+    }
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+    fn serialize_erased(&self, obj: &dyn erased_serde::Serialize) -> Result<Vec<u8>, String> {
+        bincode::serialize(&obj).map_err(|e| e.to_string())
+    }
+    fn deserialize_erased<'a>(&self, data: &'a [u8], visit: Visit<'_, 'a>) -> Result<(), String> {
+        // The options of `bincode::serialize`
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let mut de = bincode::Deserializer::from_slice(data, options);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(|e| e.to_string())
+    }
+}
+
+lazy_static! {
+    static ref CODECS: RwLock<HashMap<u8, &'static dyn Codec>> = {
+        let mut codecs: HashMap<u8, &'static dyn Codec> = HashMap::new();
+        for codec in &[&Json as &'static dyn Codec, &Cbor, &Bincode] {
+            codecs.insert(codec.id(), *codec);
+        }
+        RwLock::new(codecs)
+    };
+}
+
+// Make a codec known by its id, for logs, snapshots and handshakes naming it. Returns
+// false when another codec has the id.
+pub fn register_codec(codec: &'static dyn Codec) -> bool {
+    let mut codecs = CODECS.write();
+    match codecs.get(&codec.id()) {
+        Some(registered) => registered.name() == codec.name(),
+        None => {
+            codecs.insert(codec.id(), codec);
+            true
         }
     }
 }
 
+pub fn codec_by_id(id: u8) -> Option<&'static dyn Codec> {
+    CODECS.read().get(&id).cloned()
+}
+
+pub fn serializer_name(id: u8) -> &'static str {
+    codec_by_id(id).map_or("unknown", |codec| codec.name())
+}
+
+// Codec of builds before it was configurable, for data they left behind
 #[cfg(debug_assertions)]
+pub const LEGACY_CODEC: &dyn Codec = &Json;
+#[cfg(not(debug_assertions))]
+pub const LEGACY_CODEC: &dyn Codec = &Cbor;
+
+static DEFAULT_CODEC: AtomicU8 = AtomicU8::new(SERIALIZER_CBOR);
+
+tokio::task_local! {
+    static CODEC: &'static dyn Codec;
+}
+
+// Codec of server and client options created from now on by default, including the
+// ones of raft, membership and the default client pool. Set it before starting them.
+pub fn set_default_codec(codec: &'static dyn Codec) {
+    register_codec(codec);
+    DEFAULT_CODEC.store(codec.id(), Relaxed);
+}
+
+pub fn default_codec() -> &'static dyn Codec {
+    codec_by_id(DEFAULT_CODEC.load(Relaxed)).unwrap()
+}
+
+// Codec of the request being handled, the default one outside of requests
+pub fn codec() -> &'static dyn Codec {
+    CODEC
+        .try_with(|codec| *codec)
+        .unwrap_or_else(|_| default_codec())
+}
+
+pub async fn with_codec<F: Future>(codec: &'static dyn Codec, f: F) -> F::Output {
+    CODEC.scope(codec, f).await
+}
+
 pub fn serialize<T>(obj: &T) -> Vec<u8>
 where
     T: serde::Serialize,
 {
-    match serde_json::to_vec(obj) {
-        Ok(data) => data,
-        Err(e) => panic!("Cannot serialize: {:?}", e),
-    }
+    codec().serialize(obj)
 }
 
-#[cfg(debug_assertions)]
 pub fn deserialize<'a, T>(data: &'a [u8]) -> Option<T>
 where
     T: serde::Deserialize<'a>,
 {
-    let type_name = std::intrinsics::type_name::<T>();
-    match serde_json::from_slice(data) {
-        Ok(obj) => Some(obj),
-        Err(e) => {
-            warn!(
-                "Error on decoding data for type '{}', {}, json: {}",
-                type_name,
-                e,
-                String::from_utf8_lossy(data)
-            );
-            None
+    codec().deserialize(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Value {
+        Unit,
+        Named { name: String, tags: Vec<String> },
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut map = BTreeMap::new();
+        map.insert(1u64, (String::from("one"), Some(vec![1u8, 2, 3])));
+        map.insert(2u64, (String::from("two"), None));
+        let values = vec![
+            Value::Unit,
+            Value::Named {
+                name: String::from("three"),
+                tags: vec![String::from("odd")],
+            },
+        ];
+        let codecs: [&'static dyn Codec; 3] = [&Json, &Cbor, &Bincode];
+        for codec in &codecs {
+            assert_eq!(codec_by_id(codec.id()), Some(*codec));
+            let data = codec.serialize(&map);
+            let decoded: BTreeMap<u64, (String, Option<Vec<u8>>)> =
+                codec.deserialize(&data).unwrap();
+            assert_eq!(decoded, map);
+            let data = codec.serialize(&values);
+            assert_eq!(codec.deserialize::<Vec<Value>>(&data).unwrap(), values);
         }
+        // Same encoding as the format crates on their own
+        assert_eq!(
+            Bincode.serialize_erased(&map),
+            Ok(bincode::serialize(&map).unwrap())
+        );
+        assert_eq!(
+            Cbor.serialize_erased(&map),
+            Ok(serde_cbor::to_vec(&map).unwrap())
+        );
+        let bincode: &dyn Codec = &Bincode;
+        let json: &dyn Codec = &Json;
+        let numbers = vec![1_000_000u32; 10];
+        assert!(bincode.serialize(&numbers).len() < json.serialize(&numbers).len());
+        assert!(json.deserialize::<u64>(b"1 2").is_none());
+        assert_eq!(serializer_name(0), "unknown");
+    }
+
+    // Encodes with JSON, under an id of its own
+    struct Custom(u8);
+
+    impl Codec for Custom {
+        fn id(&self) -> u8 {
+            self.0
+        }
+        fn name(&self) -> &'static str {
+            "custom"
+        }
+        fn serialize_erased(&self, obj: &dyn erased_serde::Serialize) -> Result<Vec<u8>, String> {
+            Json.serialize_erased(obj)
+        }
+        fn deserialize_erased<'a>(
+            &self,
+            data: &'a [u8],
+            visit: Visit<'_, 'a>,
+        ) -> Result<(), String> {
+            Json.deserialize_erased(data, visit)
+        }
+    }
+
+    #[test]
+    fn custom_codec() {
+        assert_eq!(serializer_name(200), "unknown");
+        assert!(register_codec(&Custom(200)));
+        assert!(register_codec(&Custom(200)));
+        assert!(!register_codec(&Custom(SERIALIZER_JSON)));
+        assert_eq!(serializer_name(200), "custom");
+        let codec = codec_by_id(200).unwrap();
+        let data = codec.serialize(&(1u32, String::from("one")));
+        assert_eq!(
+            codec.deserialize::<(u32, String)>(&data),
+            Some((1, String::from("one")))
+        );
     }
 }